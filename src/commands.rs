@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use crate::LightClient;
+use crate::lightclient::TxFilter;
 
 pub trait Command {
     fn help(&self) -> String;
@@ -128,9 +129,20 @@ impl Command for BalanceCommand {
         let mut h = vec![];
         h.push("Show the current TAZ balance in the wallet");
         h.push("Usage:");
-        h.push("balance");
+        h.push("balance [--minconf <n>]");
         h.push("");
         h.push("Transparent and Shielded balances, along with the addresses they belong to are displayed");
+        h.push("With --minconf, zbalance and tbalance only count funds with at least that many confirmations.");
+        h.push("");
+        h.push("The balances are also split by whether they can be spent right now, in total and for every address:");
+        h.push("  spendable_zbalance          - Notes that can be spent");
+        h.push("  unconfirmed_zbalance        - Received notes that need more confirmations before they can be spent");
+        h.push("  unconfirmed_change_zbalance - Change that needs more confirmations before it can be spent");
+        h.push("  blocks_until_spendable      - Blocks until all of the unconfirmed notes can be spent");
+        h.push("  pending_spent_zbalance      - Notes spent by sent transactions that haven't been mined yet");
+        h.push("  confirmed_tbalance          - Transparent funds with as many confirmations as notes need");
+        h.push("  unconfirmed_tbalance        - Transparent funds with fewer confirmations");
+        h.push("  pending_spent_tbalance      - Transparent funds spent by sent transactions that haven't been mined yet");
 
         h.join("\n")
     }
@@ -139,10 +151,19 @@ impl Command for BalanceCommand {
         "Show the current TAZ balance in the wallet".to_string()
     }
 
-    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        let minconf = match args {
+            [] => None,
+            ["--minconf", n] => match n.parse::<u32>() {
+                Ok(n)  => Some(n),
+                Err(e) => return format!("Couldn't parse minconf: {}", e),
+            },
+            _ => return self.help(),
+        };
+
         lightclient.do_sync(true);
         
-        format!("{}", lightclient.do_balance().pretty(2))
+        format!("{}", lightclient.do_balance(minconf).pretty(2))
     }
 }
 
@@ -207,7 +228,22 @@ impl Command for SendCommand {
         let mut h = vec![];
         h.push("Send TAZ to a given address");
         h.push("Usage:");
-        h.push("send <address> <amount in tazoshis> \"optional_memo\"");
+        h.push("send [--dry-run] [--expiry <blocks>] <address> <amount in tazoshis> \"optional_memo\"");
+        h.push("send [--dry-run] [--expiry <blocks>] [--from <address>] <address> all \"optional_memo\"");
+        h.push("");
+        h.push("With --dry-run, the notes and utxos to spend, the fee and the change are shown without creating the transaction.");
+        h.push("Use the 'confirm' command afterwards to actually send it.");
+        h.push("");
+        h.push("With 'all' as the amount, all the funds that can be spent right now are sent, minus the fee, without any");
+        h.push("change. --from limits this to the funds of one of your addresses.");
+        h.push("");
+        h.push("--expiry sets how many blocks the transaction can take to be mined before it expires. The default is the");
+        h.push("wallet's expiry_delta option (see 'setoption'), and it has to be at least 4.");
+        h.push("");
+        h.push("The address can also be a label from the address book (see 'label').");
+        h.push("");
+        h.push("Memos can only be sent to z-addresses, and can be up to 512 bytes. A memo starting with 0x is sent as");
+        h.push("hex encoded binary data. Start it with 0xff for arbitrary (non-text) data, as described in ZIP-302.");
         h.push("");
         h.push("Example:");
         h.push("send ztestsapling1x65nq4dgp0qfywgxcwk9n0fvm4fysmapgr2q00p85ju252h6l7mmxu2jg9cqqhtvzd69jwhgv8d 200000 \"Hello from the command line\"");
@@ -222,11 +258,51 @@ impl Command for SendCommand {
 
     fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
         // Parse the args. 
+        // 0 - Optional --dry-run and --expiry <blocks> flags
+        let mut args = args;
+        let mut dry_run = false;
+        let mut expiry_delta = None;
+        let mut from = None;
+        loop {
+            match args.first() {
+                Some(&"--dry-run") => {
+                    dry_run = true;
+                    args = &args[1..];
+                },
+                Some(&"--expiry") if args.len() > 1 => {
+                    expiry_delta = match args[1].parse::<u32>() {
+                        Ok(d)  => Some(d),
+                        Err(e) => return format!("Couldn't parse expiry: {}", e)
+                    };
+                    args = &args[2..];
+                },
+                Some(&"--from") if args.len() > 1 => {
+                    from = Some(args[1].to_string());
+                    args = &args[2..];
+                },
+                _ => break
+            }
+        }
+
         // 1 - Destination address. T or Z address
         if args.len() < 2 || args.len() > 3 {
             return self.help();
         }
 
+        let memo = if args.len() == 3 { Some(args[2].to_string()) } else {None};
+
+        if args[1] == "all" {
+            lightclient.do_sync(true);
+
+            return if dry_run {
+                format!("{}", lightclient.do_send_all_preview(args[0], memo, from, expiry_delta).pretty(2))
+            } else {
+                lightclient.do_send_all(args[0], memo, from, expiry_delta)
+            };
+        } else if from.is_some() {
+            return format!("--from can only be used when sending all funds");
+        }
+
         // Make sure we can parse the amount
         let value = match args[1].parse::<u64>() {
             Ok(amt) => amt,
@@ -235,11 +311,381 @@ impl Command for SendCommand {
             }
         };
 
-        let memo = if args.len() == 3 { Some(args[2].to_string()) } else {None};
-        
         lightclient.do_sync(true);
 
-        lightclient.do_send(args[0], value, memo)
+        if dry_run {
+            format!("{}", lightclient.do_send_preview(args[0], value, memo, expiry_delta).pretty(2))
+        } else {
+            lightclient.do_send(args[0], value, memo, expiry_delta)
+        }
+    }
+}
+
+struct PayCommand {}
+impl Command for PayCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Pay a ZIP-321 payment request URI");
+        h.push("Usage:");
+        h.push("pay [--expiry <blocks>] <zcash: URI>");
+        h.push("");
+        h.push("All the payments in the request are sent in one transaction. Every payment needs an amount.");
+        h.push("Put the URI in quotes, since it can contain '&'.");
+        h.push("--expiry works like it does for 'send'.");
+        h.push("");
+        h.push("Example:");
+        h.push("pay \"zcash:ztestsapling1x65nq4dgp0qfywgxcwk9n0fvm4fysmapgr2q00p85ju252h6l7mmxu2jg9cqqhtvzd69jwhgv8d?amount=0.002&memo=SGVsbG8\"");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Pay a zcash: payment request URI".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        let mut args = args;
+        let mut expiry_delta = None;
+        if args.len() > 1 && args[0] == "--expiry" {
+            expiry_delta = match args[1].parse::<u32>() {
+                Ok(d)  => Some(d),
+                Err(e) => return format!("Couldn't parse expiry: {}", e)
+            };
+            args = &args[2..];
+        }
+
+        if args.len() != 1 {
+            return self.help();
+        }
+
+        lightclient.do_sync(true);
+
+        lightclient.do_pay(args[0], expiry_delta)
+    }
+}
+
+struct SendBatchCommand {}
+impl Command for SendBatchCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Send payments to all the rows of a CSV file");
+        h.push("Usage:");
+        h.push("sendbatch <file.csv> [report.csv]");
+        h.push("");
+        h.push("Every row of the file is 'address,amount in tazoshis,optional memo'. Put memos with commas in double quotes.");
+        h.push("All the rows are checked first, and nothing is sent if any of them are invalid. The rows are then sent");
+        h.push("in as few transactions as the spendable funds and the transaction size limit allow.");
+        h.push("");
+        h.push("The transaction id for each row is written to the report file, which is <file.csv>.report.csv by default.");
+        h.push("If the batch is run again with the same report file, rows that were already sent are skipped. If there");
+        h.push("weren't enough spendable funds to send every row, run it again after the change has confirmed.");
+        h.push("");
+        h.push("Example:");
+        h.push("sendbatch payroll.csv");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Send payments to all the rows of a CSV file".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() < 1 || args.len() > 2 {
+            return self.help();
+        }
+
+        lightclient.do_sync(true);
+
+        format!("{}", lightclient.do_send_batch(args[0], args.get(1).map(|r| *r)).pretty(2))
+    }
+}
+
+struct ConsolidateCommand {}
+impl Command for ConsolidateCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Merge your smallest notes into one note");
+        h.push("Usage:");
+        h.push("consolidate [--max-inputs <n>] [--address <your zaddr>]");
+        h.push("");
+        h.push("Wallets with a lot of small notes are slow to send from, and their transactions can get too big.");
+        h.push("This merges up to --max-inputs (default 50) of the smallest spendable notes per transaction into one note");
+        h.push("at --address, which defaults to your first z address. Transactions are sent until there is at most");
+        h.push("one spendable note left. Each transaction pays the normal fee.");
+        h.push("");
+        h.push("Notes need enough confirmations before they can be spent, so notes that were received recently are left alone.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Merge your smallest notes into one note".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        let mut max_inputs = None;
+        let mut address = None;
+
+        let mut i = 0;
+        while i < args.len() {
+            match (args[i], args.get(i + 1)) {
+                ("--max-inputs", Some(n)) => match n.parse::<usize>() {
+                    Ok(n)  => max_inputs = Some(n),
+                    Err(e) => return format!("Couldn't parse --max-inputs: {}", e),
+                },
+                ("--address", Some(a)) => address = Some(a.to_string()),
+                _ => return self.help(),
+            }
+            i += 2;
+        }
+
+        lightclient.do_sync(true);
+
+        format!("{}", lightclient.do_consolidate(max_inputs, address).pretty(2))
+    }
+}
+
+struct RequestCommand {}
+impl Command for RequestCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Create a ZIP-321 payment request URI to receive funds at your first z address");
+        h.push("Usage:");
+        h.push("request <amount in tazoshis> \"optional_memo\"");
+        h.push("");
+        h.push("Example:");
+        h.push("request 200000 \"Invoice 42\"");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Create a zcash: payment request URI".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() < 1 || args.len() > 2 {
+            return self.help();
+        }
+
+        let value = match args[0].parse::<u64>() {
+            Ok(amt) => amt,
+            Err(e)  => return format!("Couldn't parse amount: {}", e)
+        };
+
+        let memo = if args.len() == 2 { Some(args[1].to_string()) } else { None };
+
+        format!("{}", lightclient.do_payment_request(value, memo).pretty(2))
+    }
+}
+
+struct ConfirmCommand {}
+impl Command for ConfirmCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Send the transaction from the last 'send --dry-run'");
+        h.push("Usage:");
+        h.push("confirm");
+        h.push("");
+        h.push("The transaction is built exactly as it was shown by the dry run. If any of its notes or utxos");
+        h.push("have been spent since, or the dry run is too old, you'll need to do a new dry run.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Send the transaction from the last dry run".to_string()
+    }
+
+    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
+        lightclient.do_sync(true);
+
+        lightclient.do_confirm_send()
+    }
+}
+
+struct ExportProposalCommand {}
+impl Command for ExportProposalCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Export the transaction from the last 'send --dry-run' to a file, so it can be signed offline");
+        h.push("Usage:");
+        h.push("exportproposal <file>");
+        h.push("");
+        h.push("The proposal contains the selected notes with their witnesses, the utxos, the outputs and the");
+        h.push("target height. Sign it with 'signproposal' on a wallet that has the spending keys, and then");
+        h.push("broadcast the result here with 'sendsigned'.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Export the last dry run for offline signing".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 1 {
+            return self.help();
+        }
+
+        lightclient.do_export_proposal(args[0])
+    }
+}
+
+struct SignProposalCommand {}
+impl Command for SignProposalCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Sign a transaction proposal exported with 'exportproposal'");
+        h.push("Usage:");
+        h.push("signproposal <proposal file> <signed tx file>");
+        h.push("");
+        h.push("The transaction is built, proven and signed, and the raw transaction is written to the second file.");
+        h.push("This doesn't need a connection to the server, so it can be run on an offline wallet (see --offline).");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Sign an exported transaction proposal".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 2 {
+            return self.help();
+        }
+
+        lightclient.do_sign_proposal(args[0], args[1])
+    }
+}
+
+struct SendSignedCommand {}
+impl Command for SendSignedCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Broadcast a transaction signed with 'signproposal'");
+        h.push("Usage:");
+        h.push("sendsigned <signed tx file>");
+        h.push("");
+        h.push("The notes and utxos spent by the transaction are marked as pending until it is mined.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Broadcast an offline signed transaction".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 1 {
+            return self.help();
+        }
+
+        lightclient.do_send_signed(args[0])
+    }
+}
+
+struct RebroadcastCommand {}
+impl Command for RebroadcastCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Broadcast sent transactions that haven't been mined yet again");
+        h.push("Usage:");
+        h.push("rebroadcast [txid]");
+        h.push("");
+        h.push("Sent transactions are kept until they are mined or expire. Without a txid, all of them are broadcast again.");
+        h.push("This also happens automatically after every sync.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Rebroadcast pending sent transactions".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() > 1 {
+            return self.help();
+        }
+
+        format!("{}", lightclient.do_rebroadcast(args.first().map(|t| *t)).pretty(2))
+    }
+}
+
+struct PendingCommand {}
+impl Command for PendingCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("List sent transactions that haven't been mined yet");
+        h.push("Usage:");
+        h.push("pending");
+        h.push("");
+        h.push("Shows the expiry height of each transaction and how many blocks are left before it expires.");
+        h.push("Once a transaction expires, the funds it spent can be spent again.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "List unmined sent transactions".to_string()
+    }
+
+    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
+        format!("{}", lightclient.do_pending().pretty(2))
+    }
+}
+
+struct GetOptionCommand {}
+impl Command for GetOptionCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Show the wallet's options");
+        h.push("Usage:");
+        h.push("getoption");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Show the wallet's options".to_string()
+    }
+
+    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
+        format!("{}", lightclient.do_get_options().pretty(2))
+    }
+}
+
+struct SetOptionCommand {}
+impl Command for SetOptionCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Set a wallet option");
+        h.push("Usage:");
+        h.push("setoption <name> <value>");
+        h.push("");
+        h.push("Options:");
+        h.push("expiry_delta - Number of blocks a sent transaction can take to be mined before it expires (default 20, at least 4)");
+        h.push("change_policy - Where the change of a transaction goes. One of:");
+        h.push("    same_as_input     - The address of the first spent note, or the shielded address if only t funds are spent (default)");
+        h.push("    fresh_diversified - A new diversified address of the spending account for every transaction");
+        h.push("    <address>         - A fixed address in this wallet. Use a t address to get transparent change");
+        h.push("");
+        h.push("Options are saved with the wallet.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Set a wallet option".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 2 {
+            return self.help();
+        }
+
+        format!("{}", lightclient.do_set_option(args[0], args[1]).pretty(2))
     }
 }
 
@@ -294,8 +740,19 @@ impl Command for TransactionsCommand {
         let mut h = vec![];
         h.push("List all incoming and outgoing transactions from this wallet");
         h.push("Usage:");
-        h.push("list");
+        h.push("list [--address <address>] [--direction incoming|outgoing|change] [--min-height <height>] [--max-height <height>]");
+        h.push("     [--from <date>] [--to <date>] [--min-amount <amount in tazoshis>] [--memo <text>] [--annotation <text>]");
+        h.push("     [--tag <tag>] [--minconf <n>] [--offset <n>] [--limit <n>]");
+        h.push("");
+        h.push("The options only list the transactions that match all of them. Dates are YYYY-MM-DD in UTC, or unix timestamps,");
+        h.push("and --to includes the whole day. --memo matches part of a memo. --annotation matches part of an annotation, or");
+        h.push("a whole tag (see 'annotate' and 'tag'). --minconf only lists transactions with at least that many");
+        h.push("confirmations. Change is only listed with --direction change.");
+        h.push("--offset and --limit page through the matching transactions, oldest first. --address can also be a label");
+        h.push("from the address book.");
         h.push("");
+        h.push("Example:");
+        h.push("list --direction incoming --from 2019-10-01 --limit 20");
 
         h.join("\n")
     }
@@ -304,10 +761,389 @@ impl Command for TransactionsCommand {
         "List all transactions in the wallet".to_string()
     }
 
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        let mut filter = TxFilter::default();
+
+        let mut i = 0;
+        while i < args.len() {
+            let value = match args.get(i + 1) {
+                Some(v) => *v,
+                None    => return self.help(),
+            };
+
+            let res = match args[i] {
+                "--address"    => { filter.address = Some(value.to_string()); Ok(()) },
+                "--direction"  => match value {
+                    "incoming" | "outgoing" | "change" => { filter.direction = Some(value.to_string()); Ok(()) },
+                    _ => Err(format!("Unknown direction {}", value)),
+                },
+                "--min-height" => value.parse::<i32>().map(|h| filter.min_height = Some(h)).map_err(|e| e.to_string()),
+                "--max-height" => value.parse::<i32>().map(|h| filter.max_height = Some(h)).map_err(|e| e.to_string()),
+                "--from"       => parse_date(value, false).map(|d| filter.from_date = Some(d)),
+                "--to"         => parse_date(value, true).map(|d| filter.to_date = Some(d)),
+                "--min-amount" => value.parse::<u64>().map(|a| filter.min_amount = Some(a)).map_err(|e| e.to_string()),
+                "--memo"       => { filter.memo = Some(value.to_string()); Ok(()) },
+                "--annotation" => { filter.annotation = Some(value.to_string()); Ok(()) },
+                "--tag"        => { filter.tag = Some(value.to_string()); Ok(()) },
+                "--minconf"    => value.parse::<u32>().map(|m| filter.minconf = Some(m)).map_err(|e| e.to_string()),
+                "--offset"     => value.parse::<usize>().map(|o| filter.offset = o).map_err(|e| e.to_string()),
+                "--limit"      => value.parse::<usize>().map(|l| filter.limit = Some(l)).map_err(|e| e.to_string()),
+                _              => return self.help(),
+            };
+
+            if let Err(e) = res {
+                return format!("Couldn't parse {} {}: {}", args[i], value, e);
+            }
+            i += 2;
+        }
+
+        lightclient.do_sync(true);
+
+        format!("{}", lightclient.do_list_transactions_filtered(&filter).pretty(2))
+    }
+}
+
+struct ExportHistoryCommand {}
+impl Command for ExportHistoryCommand {
+    fn help(&self)  -> String {
+        let mut h = vec![];
+        h.push("Export the transaction history to a file, for accounting");
+        h.push("Usage:");
+        h.push("exporthistory <file> [--format csv|json]");
+        h.push("");
+        h.push("Every incoming and outgoing transaction is a row, with its txid, height, time, direction, address, amount,");
+        h.push("fee, memo, annotation and tags, oldest first. The amounts are the same as in 'list', and the balance column");
+        h.push("is the wallet's balance after each row. Transactions that haven't been mined yet aren't included.");
+        h.push("The default format is csv.");
+        h.push("");
+        h.push("Example:");
+        h.push("exporthistory history.csv");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Export the transaction history to a CSV or JSON file".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        let format = match args {
+            [_] => "csv",
+            [_, "--format", format] => *format,
+            _ => return self.help(),
+        };
+
+        lightclient.do_sync(true);
+
+        lightclient.do_export_history(args[0], format)
+    }
+}
+
+struct TxCommand {}
+impl Command for TxCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Show the details of a transaction");
+        h.push("Usage:");
+        h.push("tx <txid>");
+        h.push("");
+        h.push("Shows everything the wallet knows about the transaction: its height, time, confirmations, the notes and");
+        h.push("utxos it paid us, what it spent, where it sent funds, and the fee. The full transaction is fetched from");
+        h.push("the server, once, to show its transparent inputs and outputs.");
+        h.push("");
+        h.push("Example:");
+        h.push("tx 6f31d2bb1d9e2c8e7e5ec3b1dfaf7a0fa32f97e42f09ad2eac5bc8ad3b0f9ad5");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Show the details of a transaction".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 1 {
+            return self.help();
+        }
+
+        format!("{}", lightclient.do_tx(args[0]).pretty(2))
+    }
+}
+
+struct ReceivedCommand {}
+impl Command for ReceivedCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("List the funds received by an address");
+        h.push("Usage:");
+        h.push("received <address> [minconf]");
+        h.push("");
+        h.push("Every note or utxo received by the address is listed with its amount, txid, height and memo, oldest first.");
+        h.push("Change is included, with is_change set. The address can also be a label from the address book.");
+        h.push("With minconf, only the funds with at least that many confirmations are listed.");
+        h.push("");
+        h.push("Example:");
+        h.push("received ztestsapling1x65nq4dgp0qfywgxcwk9n0fvm4fysmapgr2q00p85ju252h6l7mmxu2jg9cqqhtvzd69jwhgv8d 10");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "List the funds received by an address".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.is_empty() || args.len() > 2 {
+            return self.help();
+        }
+
+        let minconf = match args.get(1).map(|m| m.parse::<u32>()) {
+            None         => 0,
+            Some(Ok(m))  => m,
+            Some(Err(e)) => return format!("Couldn't parse minconf: {}", e),
+        };
+
+        lightclient.do_sync(true);
+
+        format!("{}", lightclient.do_received(args[0], minconf).pretty(2))
+    }
+}
+
+struct ReceivedByAddressCommand {}
+impl Command for ReceivedByAddressCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Show the total received by every address in the wallet");
+        h.push("Usage:");
+        h.push("receivedbyaddress [minconf]");
+        h.push("");
+        h.push("All the wallet's addresses are listed, along with the diversified addresses that received funds.");
+        h.push("'received' and 'count' are the value and number of notes and utxos received, not counting change.");
+        h.push("'unspent' is what is left at the address now. With minconf, only the funds with at least that many");
+        h.push("confirmations are counted.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Show the total received by every address".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        let minconf = match args {
+            []  => 0,
+            [m] => match m.parse::<u32>() {
+                Ok(m)  => m,
+                Err(e) => return format!("Couldn't parse minconf: {}", e),
+            },
+            _   => return self.help(),
+        };
+
+        lightclient.do_sync(true);
+
+        format!("{}", lightclient.do_received_by_address(minconf).pretty(2))
+    }
+}
+
+struct InboxCommand {}
+impl Command for InboxCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("List the memos you received, newest first");
+        h.push("Usage:");
+        h.push("inbox");
+        h.push("");
+        h.push("Messages are numbered oldest first, so a message keeps its number when new ones arrive. Answer them with");
+        h.push("'reply'. Senders can add their address to a memo, on the last line after a \"Reply-To:\" line, like ZecWallet");
+        h.push("does. The messages of each sender that did, and the memos you sent them, are also grouped into conversations.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "List the received memos, and the conversations".to_string()
+    }
+
     fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
         lightclient.do_sync(true);
 
-        format!("{}", lightclient.do_list_transactions().pretty(2))
+        format!("{}", lightclient.do_inbox().pretty(2))
+    }
+}
+
+struct ReplyCommand {}
+impl Command for ReplyCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Reply to a message from the inbox");
+        h.push("Usage:");
+        h.push("reply <n | txid> \"text\"");
+        h.push("");
+        h.push("n is the number of the message in 'inbox', or the txid of the message, which needs a reply-to address.");
+        h.push("The reply is a memo with a small amount, and your address that received the message as its reply-to address.");
+        h.push("");
+        h.push("Example:");
+        h.push("reply 1 \"Thanks, got it\"");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Reply to a message from the inbox".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 2 {
+            return self.help();
+        }
+
+        // Don't sync first. The message numbers are the ones the user saw in the last 'inbox'.
+        lightclient.do_reply(args[0], args[1])
+    }
+}
+
+struct AnnotateCommand {}
+impl Command for AnnotateCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Add a note to a transaction, like an invoice number");
+        h.push("Usage:");
+        h.push("annotate <txid> \"text\"");
+        h.push("");
+        h.push("Annotations are only stored in this wallet, and replace the transaction's previous annotation.");
+        h.push("An empty text removes it. They are shown in 'list', which can search them, and in 'exporthistory'.");
+        h.push("");
+        h.push("Example:");
+        h.push("annotate 6f31d2bb1d9e2c8e7e5ec3b1dfaf7a0fa32f97e42f09ad2eac5bc8ad3b0f9ad5 \"Invoice 2019-117\"");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Add a note to a transaction".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 2 {
+            return self.help();
+        }
+
+        format!("{}", lightclient.do_annotate(args[0], args[1]).pretty(2))
+    }
+}
+
+struct TagCommand {}
+impl Command for TagCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Tag a transaction with a category");
+        h.push("Usage:");
+        h.push("tag [--remove] <txid> <tag>");
+        h.push("");
+        h.push("A transaction can have any number of tags. They are only stored in this wallet. Use 'list --tag <tag>'");
+        h.push("to list the transactions with a tag. --remove removes the tag from the transaction.");
+        h.push("");
+        h.push("Example:");
+        h.push("tag 6f31d2bb1d9e2c8e7e5ec3b1dfaf7a0fa32f97e42f09ad2eac5bc8ad3b0f9ad5 salary");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Tag a transaction".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        let (remove, args) = match args.first() {
+            Some(&"--remove") => (true, &args[1..]),
+            _                 => (false, args),
+        };
+
+        if args.len() != 2 {
+            return self.help();
+        }
+
+        format!("{}", lightclient.do_tag(args[0], args[1], remove).pretty(2))
+    }
+}
+
+struct LabelCommand {}
+impl Command for LabelCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Add an address to the address book, or change its label");
+        h.push("Usage:");
+        h.push("label <address> \"label\"");
+        h.push("");
+        h.push("The address can be one of your own or someone else's. Labels are shown next to addresses in");
+        h.push("'balance', 'notes' and 'list', and can be used in place of the address in 'send'.");
+        h.push("Every label can only be used for one address.");
+        h.push("");
+        h.push("Example:");
+        h.push("label ztestsapling1x65nq4dgp0qfywgxcwk9n0fvm4fysmapgr2q00p85ju252h6l7mmxu2jg9cqqhtvzd69jwhgv8d \"Alice\"");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Label an address in the address book".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 2 {
+            return self.help();
+        }
+
+        format!("{}", lightclient.do_set_label(args[0], args[1]).pretty(2))
+    }
+}
+
+struct UnlabelCommand {}
+impl Command for UnlabelCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Remove an address from the address book");
+        h.push("Usage:");
+        h.push("unlabel <address or label>");
+        h.push("");
+        h.push("Example:");
+        h.push("unlabel \"Alice\"");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Remove an address from the address book".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 1 {
+            return self.help();
+        }
+
+        format!("{}", lightclient.do_remove_label(args[0]).pretty(2))
+    }
+}
+
+struct AddressBookCommand {}
+impl Command for AddressBookCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("List the labelled addresses in the address book");
+        h.push("Usage:");
+        h.push("addressbook");
+        h.push("");
+        h.push("'is_mine' is true for the addresses that belong to this wallet.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "List the address book".to_string()
+    }
+
+    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
+        format!("{}", lightclient.do_address_book().pretty(2))
     }
 }
 
@@ -344,9 +1180,10 @@ impl Command for NotesCommand {
         let mut h = vec![];
         h.push("Show all sapling notes and utxos in this wallet");
         h.push("Usage:");
-        h.push("notes [all]");
+        h.push("notes [all] [--minconf <n>]");
         h.push("");
         h.push("If you supply the \"all\" parameter, all previously spent sapling notes and spent utxos are also included");
+        h.push("With --minconf, only the notes and utxos with at least that many confirmations are shown.");
 
         h.join("\n")
     }
@@ -357,6 +1194,16 @@ impl Command for NotesCommand {
 
     fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
         // Parse the args. 
+        let mut args = args;
+        let mut minconf = 0;
+        if args.len() >= 2 && args[args.len() - 2] == "--minconf" {
+            minconf = match args[args.len() - 1].parse::<u32>() {
+                Ok(m)  => m,
+                Err(e) => return format!("Couldn't parse minconf: {}", e),
+            };
+            args = &args[..args.len() - 2];
+        }
+
         if args.len() > 1 {
             return self.short_help();
         }
@@ -373,7 +1220,7 @@ impl Command for NotesCommand {
 
         lightclient.do_sync(true);
         
-        format!("{}", lightclient.do_list_notes(all_notes).pretty(2))
+        format!("{}", lightclient.do_list_notes(all_notes, minconf).pretty(2))
     }
 }
 
@@ -399,6 +1246,19 @@ impl Command for QuitCommand {
     }
 }
 
+// Parse a YYYY-MM-DD date in UTC, or a unix timestamp. With `end_of_day`, a date is
+// the last second of that day.
+fn parse_date(s: &str, end_of_day: bool) -> Result<u64, String> {
+    if let Ok(timestamp) = s.parse::<u64>() {
+        return Ok(timestamp);
+    }
+
+    let date = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|e| e.to_string())?;
+    let time = if end_of_day { date.and_hms(23, 59, 59) } else { date.and_hms(0, 0, 0) };
+
+    Ok(time.timestamp() as u64)
+}
+
 pub fn get_commands() -> Box<HashMap<String, Box<dyn Command>>> {
     let mut map: HashMap<String, Box<dyn Command>> = HashMap::new();
 
@@ -410,9 +1270,32 @@ pub fn get_commands() -> Box<HashMap<String, Box<dyn Command>>> {
     map.insert("export".to_string(),    Box::new(ExportCommand{}));
     map.insert("info".to_string(),      Box::new(InfoCommand{}));
     map.insert("send".to_string(),      Box::new(SendCommand{}));
+    map.insert("confirm".to_string(),   Box::new(ConfirmCommand{}));
+    map.insert("pay".to_string(),       Box::new(PayCommand{}));
+    map.insert("sendbatch".to_string(), Box::new(SendBatchCommand{}));
+    map.insert("consolidate".to_string(), Box::new(ConsolidateCommand{}));
+    map.insert("request".to_string(),   Box::new(RequestCommand{}));
+    map.insert("exportproposal".to_string(), Box::new(ExportProposalCommand{}));
+    map.insert("signproposal".to_string(),   Box::new(SignProposalCommand{}));
+    map.insert("sendsigned".to_string(),     Box::new(SendSignedCommand{}));
+    map.insert("rebroadcast".to_string(),    Box::new(RebroadcastCommand{}));
+    map.insert("pending".to_string(),   Box::new(PendingCommand{}));
+    map.insert("getoption".to_string(), Box::new(GetOptionCommand{}));
+    map.insert("setoption".to_string(), Box::new(SetOptionCommand{}));
     map.insert("save".to_string(),      Box::new(SaveCommand{}));
     map.insert("quit".to_string(),      Box::new(QuitCommand{}));
     map.insert("list".to_string(),      Box::new(TransactionsCommand{}));
+    map.insert("exporthistory".to_string(), Box::new(ExportHistoryCommand{}));
+    map.insert("tx".to_string(),        Box::new(TxCommand{}));
+    map.insert("received".to_string(),  Box::new(ReceivedCommand{}));
+    map.insert("receivedbyaddress".to_string(), Box::new(ReceivedByAddressCommand{}));
+    map.insert("inbox".to_string(),     Box::new(InboxCommand{}));
+    map.insert("reply".to_string(),     Box::new(ReplyCommand{}));
+    map.insert("annotate".to_string(),  Box::new(AnnotateCommand{}));
+    map.insert("tag".to_string(),       Box::new(TagCommand{}));
+    map.insert("label".to_string(),     Box::new(LabelCommand{}));
+    map.insert("unlabel".to_string(),   Box::new(UnlabelCommand{}));
+    map.insert("addressbook".to_string(), Box::new(AddressBookCommand{}));
     map.insert("notes".to_string(),     Box::new(NotesCommand{}));
     map.insert("new".to_string(),       Box::new(NewAddressCommand{}));
     map.insert("seed".to_string(),      Box::new(SeedCommand{}));
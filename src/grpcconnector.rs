@@ -4,8 +4,10 @@ use log::{error};
 use std::sync::{Arc};
 use std::net::ToSocketAddrs;
 use std::net::SocketAddr;
+use std::io::{self, BufReader, Read, Write};
+use std::fs::File;
 
-use futures::{Future};
+use futures::{Future, Poll};
 use futures::stream::Stream;
 
 use tower_h2;
@@ -14,8 +16,10 @@ use tower_grpc::Request;
 
 use tokio_rustls::client::TlsStream;
 use tokio_rustls::{rustls::ClientConfig, TlsConnector};
+use tokio_rustls::rustls::{Certificate, RootCertStore, internal::pemfile};
 
 use tokio::executor::DefaultExecutor;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::tcp::TcpStream;
 
 use zcash_primitives::transaction::{TxId};
@@ -41,32 +45,226 @@ mod danger {
     }
 }
 
-/// A Secure (https) grpc destination.
+mod pinned {
+    use rustls;
+    use webpki;
+    use sha2::{Sha256, Digest};
+
+    /// Trusts the server only if the SHA-256 of its leaf certificate, or of the certificate's
+    /// SubjectPublicKeyInfo, matches the pin. The certificate doesn't need to chain to a CA, so
+    /// this works for self-signed certificates.
+    pub struct PinnedCertificateVerification {
+        pub pin: [u8; 32],
+    }
+
+    impl rustls::ServerCertVerifier for PinnedCertificateVerification {
+        fn verify_server_cert(&self,
+                              _roots: &rustls::RootCertStore,
+                              presented_certs: &[rustls::Certificate],
+                              _dns_name: webpki::DNSNameRef<'_>,
+                              _ocsp: &[u8]) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+            let leaf = presented_certs.first().ok_or(rustls::TLSError::NoCertificatesPresented)?;
+
+            let cert_matches = Sha256::digest(&leaf.0).as_slice() == self.pin;
+            let key_matches = subject_public_key_info(&leaf.0)
+                .map(|spki| Sha256::digest(spki).as_slice() == self.pin)
+                .unwrap_or(false);
+
+            if cert_matches || key_matches {
+                Ok(rustls::ServerCertVerified::assertion())
+            } else {
+                Err(rustls::TLSError::General(format!("Server certificate doesn't match the pinned sha256 {}", hex::encode(self.pin))))
+            }
+        }
+    }
+
+    const SEQUENCE: u8 = 0x30;
+    const INTEGER: u8  = 0x02;
+    const VERSION: u8  = 0xa0;      // The context specific [0] tag of the certificate's version
+
+    /// Splits off the DER element at the start of `der`, if it has the tag `tag`. Returns the whole
+    /// element, its contents, and what comes after it.
+    fn der_element(der: &[u8], tag: u8) -> Option<(&[u8], &[u8], &[u8])> {
+        if *der.first()? != tag {
+            return None;
+        }
+
+        let first = *der.get(1)? as usize;
+        let (header, len) = if first < 0x80 {
+            (2, first)
+        } else {
+            let n = first & 0x7f;
+            if n == 0 || n > 4 {
+                return None;
+            }
+
+            let mut len = 0usize;
+            for i in 0..n {
+                len = (len << 8) | *der.get(2 + i)? as usize;
+            }
+            (2 + n, len)
+        };
+
+        let end = header.checked_add(len)?;
+        Some((der.get(..end)?, der.get(header..end)?, der.get(end..)?))
+    }
+
+    /// The DER encoded SubjectPublicKeyInfo of an X.509 certificate, which is what
+    /// `openssl x509 -pubkey` prints. None if the certificate doesn't have the expected structure.
+    pub(super) fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+        // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+        let (_, cert, _) = der_element(cert, SEQUENCE)?;
+        let (_, tbs, _) = der_element(cert, SEQUENCE)?;
+
+        // The version is optional, and then come the serial number, and the signature algorithm,
+        // issuer, validity and subject sequences before the public key.
+        let fields = der_element(tbs, VERSION).map_or(tbs, |(_, _, rest)| rest);
+        let (_, _, mut fields) = der_element(fields, INTEGER)?;
+        for _ in 0..4 {
+            fields = der_element(fields, SEQUENCE)?.2;
+        }
+
+        der_element(fields, SEQUENCE).map(|(spki, _, _)| spki)
+    }
+}
+
+/// How the server's TLS certificate is verified. By default, it has to chain to one of the
+/// webpki roots.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// Accept any certificate. Set by --dangerous
+    pub no_cert:    bool,
+    /// Extra trust anchors from --tls-ca, for servers with a private CA
+    pub ca_certs:   Vec<Certificate>,
+    /// The SHA-256 from --tls-pin of the server's certificate or public key. A server that
+    /// matches it is trusted even if its certificate is self-signed.
+    pub pin:        Option<[u8; 32]>,
+}
+
+impl TlsOptions {
+    /// Trust the CA certificates in the PEM file at `path`
+    pub fn add_ca_file(&mut self, path: &str) -> Result<(), String> {
+        let file = File::open(path).map_err(|e| format!("Couldn't open {}: {}", path, e))?;
+        let certs = pemfile::certs(&mut BufReader::new(file))
+            .map_err(|_| format!("Couldn't read the PEM certificates in {}", path))?;
+
+        if certs.is_empty() {
+            return Err(format!("No PEM certificates found in {}", path));
+        }
+
+        // Check that they can be used as trust anchors now, instead of failing every connection later
+        let mut store = RootCertStore::empty();
+        for cert in &certs {
+            store.add(cert).map_err(|e| format!("Invalid CA certificate in {}: {:?}", path, e))?;
+        }
+
+        self.ca_certs.extend(certs);
+        Ok(())
+    }
+
+    /// Set the pin from its hex SHA-256. Colons between the bytes, like openssl prints them, are allowed.
+    pub fn set_pin(&mut self, pin: &str) -> Result<(), String> {
+        let bytes = hex::decode(pin.trim().replace(":", ""))
+            .map_err(|e| format!("Couldn't parse the pin {}: {}", pin, e))?;
+
+        if bytes.len() != 32 {
+            return Err(format!("The pin should be a 32 byte SHA-256 hash, but {} is {} bytes", pin, bytes.len()));
+        }
+
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&bytes);
+        self.pin = Some(hash);
+        Ok(())
+    }
+}
+
+/// The connection to the server, which is only encrypted for https servers
+enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Read for MaybeTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MaybeTlsStream::Plain(s) => s.read(buf),
+            MaybeTlsStream::Tls(s)   => s.read(buf),
+        }
+    }
+}
+
+impl Write for MaybeTlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MaybeTlsStream::Plain(s) => s.write(buf),
+            MaybeTlsStream::Tls(s)   => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MaybeTlsStream::Plain(s) => s.flush(),
+            MaybeTlsStream::Tls(s)   => s.flush(),
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            MaybeTlsStream::Plain(s) => AsyncWrite::shutdown(s),
+            MaybeTlsStream::Tls(s)   => AsyncWrite::shutdown(s),
+        }
+    }
+}
+
+/// A grpc destination. The connection uses TLS for https servers, and is plain TCP for http
+/// servers, like a lightwalletd running on the local machine.
 struct Dst {
     addr:        SocketAddr, 
     host:        String,
-    no_cert:     bool,
+    tls:         bool,
+    tls_options: TlsOptions,
 }
 
 impl tower_service::Service<()> for Dst {
-    type Response = TlsStream<TcpStream>;
+    type Response = MaybeTlsStream;
     type Error = ::std::io::Error;
-    type Future = Box<dyn Future<Item = TlsStream<TcpStream>, Error = ::std::io::Error> + Send>;
+    type Future = Box<dyn Future<Item = MaybeTlsStream, Error = ::std::io::Error> + Send>;
 
     fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
         Ok(().into())
     }
 
     fn call(&mut self, _: ()) -> Self::Future {
+        if !self.tls {
+            let stream = TcpStream::connect(&self.addr).and_then(move |sock| {
+                sock.set_nodelay(true).unwrap();
+                Ok(MaybeTlsStream::Plain(sock))
+            });
+
+            return Box::new(stream);
+        }
+
         let mut config = ClientConfig::new();
 
 
         config.alpn_protocols.push(b"h2".to_vec());
         config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+        // These were checked when they were loaded
+        for cert in &self.tls_options.ca_certs {
+            config.root_store.add(cert).unwrap();
+        }
         
-        if self.no_cert {
+        if self.tls_options.no_cert {
             config.dangerous()
                 .set_certificate_verifier(Arc::new(danger::NoCertificateVerification {}));
+        } else if let Some(pin) = self.tls_options.pin {
+            config.dangerous()
+                .set_certificate_verifier(Arc::new(pinned::PinnedCertificateVerification { pin }));
         }
 
         let config = Arc::new(config);
@@ -84,41 +282,14 @@ impl tower_service::Service<()> for Dst {
             sock.set_nodelay(true).unwrap();
             tls_connector.connect(domain_local.as_ref(), sock)
         })
-            .map(move |tcp| tcp);
+            .map(MaybeTlsStream::Tls);
 
         Box::new(stream)
     }
 }
 
-// Same implementation but without TLS. Should make it straightforward to run without TLS
-// when testing on local machine
-//
-// impl tower_service::Service<()> for Dst {
-//     type Response = TcpStream;
-//     type Error = ::std::io::Error;
-//     type Future = Box<dyn Future<Item = TcpStream, Error = ::std::io::Error> + Send>;
-//
-//     fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
-//         Ok(().into())
-//     }
-//
-//     fn call(&mut self, _: ()) -> Self::Future {
-//         let mut config = ClientConfig::new();
-//         config.alpn_protocols.push(b"h2".to_vec());
-//         config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-//
-//         let stream = TcpStream::connect(&self.addr)
-//             .and_then(move |sock| {
-//                 sock.set_nodelay(true).unwrap();
-//                 Ok(sock)
-//             });
-//         Box::new(stream)
-//     }
-// }
-
-
 macro_rules! make_grpc_client {
-    ($protocol:expr, $host:expr, $port:expr, $nocert:expr) => {{
+    ($protocol:expr, $host:expr, $port:expr, $tls_options:expr) => {{
         let uri: http::Uri = format!("{}://{}", $protocol, $host).parse().unwrap();
 
         let addr = format!("{}:{}", $host, $port)
@@ -128,11 +299,17 @@ macro_rules! make_grpc_client {
             .unwrap();
 
         let h2_settings = Default::default();
-        let mut make_client = tower_h2::client::Connect::new(Dst {addr, host: $host.to_string(), no_cert: $nocert}, h2_settings, DefaultExecutor::current());
+        // Only http:// servers are connected to without TLS, so an unexpected scheme fails closed
+        let tls = $protocol != "http";
+        let mut make_client = tower_h2::client::Connect::new(Dst {addr, host: $host.to_string(), tls, tls_options: $tls_options.clone()}, h2_settings, DefaultExecutor::current());
 
         make_client
             .make_service(())
-            .map_err(|e| { format!("HTTP/2 connection failed; err={:?}.\nIf you're connecting to a local server, please pass --dangerous to trust the server without checking its TLS certificate", e) })
+            .map_err(move |e| if tls {
+                format!("HTTP/2 connection failed; err={:?}.\nIf you're connecting to a local server without TLS, use an http:// server URL", e)
+            } else {
+                format!("HTTP/2 connection failed; err={:?}.\nIf the server uses TLS, use an https:// server URL", e)
+            })
             .and_then(move |conn| {
                 let conn = tower_request_modifier::Builder::new()
                     .set_origin(uri)
@@ -152,8 +329,8 @@ macro_rules! make_grpc_client {
 // GRPC code
 // ==============
 
-pub fn get_info(uri: http::Uri, no_cert: bool) -> Result<LightdInfo, String> {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
+pub fn get_info(uri: http::Uri, tls_options: &TlsOptions) -> Result<LightdInfo, String> {
+    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), tls_options)
         .and_then(move |mut client| {
             client.get_lightd_info(Request::new(Empty{}))
                 .map_err(|e| {
@@ -171,9 +348,9 @@ pub fn get_info(uri: http::Uri, no_cert: bool) -> Result<LightdInfo, String> {
 }
 
 
-pub fn fetch_blocks<F : 'static + std::marker::Send>(uri: &http::Uri, start_height: u64, end_height: u64, no_cert: bool, mut c: F)
+pub fn fetch_blocks<F : 'static + std::marker::Send>(uri: &http::Uri, start_height: u64, end_height: u64, tls_options: &TlsOptions, mut c: F)
     where F : FnMut(&[u8], u64) {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
+    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), tls_options)
         .and_then(move |mut client| {
             let bs = BlockId{ height: start_height, hash: vec!()};
             let be = BlockId{ height: end_height,   hash: vec!()};
@@ -209,9 +386,9 @@ pub fn fetch_blocks<F : 'static + std::marker::Send>(uri: &http::Uri, start_heig
 }
 
 pub fn fetch_transparent_txids<F : 'static + std::marker::Send>(uri: &http::Uri, address: String, 
-    start_height: u64, end_height: u64, no_cert: bool, c: F)
+    start_height: u64, end_height: u64, tls_options: &TlsOptions, c: F)
         where F : Fn(&[u8], u64) {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
+    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), tls_options)
         .and_then(move |mut client| {
             let start = Some(BlockId{ height: start_height, hash: vec!()});
             let end   = Some(BlockId{ height: end_height,   hash: vec!()});
@@ -244,9 +421,9 @@ pub fn fetch_transparent_txids<F : 'static + std::marker::Send>(uri: &http::Uri,
     };
 }
 
-pub fn fetch_full_tx<F : 'static + std::marker::Send>(uri: &http::Uri, txid: TxId, no_cert: bool, c: F)
+pub fn fetch_full_tx<F : 'static + std::marker::Send>(uri: &http::Uri, txid: TxId, tls_options: &TlsOptions, c: F)
         where F : Fn(&[u8]) {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
+    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), tls_options)
         .and_then(move |mut client| {
             let txfilter = TxFilter { block: None, index: 0, hash: txid.0.to_vec() };
             client.get_transaction(Request::new(txfilter))
@@ -270,8 +447,8 @@ pub fn fetch_full_tx<F : 'static + std::marker::Send>(uri: &http::Uri, txid: TxI
     };
 }
 
-pub fn broadcast_raw_tx(uri: &http::Uri, no_cert: bool, tx_bytes: Box<[u8]>) -> Result<String, String> {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
+pub fn broadcast_raw_tx(uri: &http::Uri, tls_options: &TlsOptions, tx_bytes: Box<[u8]>) -> Result<String, String> {
+    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), tls_options)
         .and_then(move |mut client| {
             client.send_transaction(Request::new(RawTransaction {data: tx_bytes.to_vec(), height: 0}))
                 .map_err(|e| {
@@ -291,9 +468,9 @@ pub fn broadcast_raw_tx(uri: &http::Uri, no_cert: bool, tx_bytes: Box<[u8]>) ->
     tokio::runtime::current_thread::Runtime::new().unwrap().block_on(runner)
 }
 
-pub fn fetch_latest_block<F : 'static + std::marker::Send>(uri: &http::Uri, no_cert: bool, mut c : F) 
+pub fn fetch_latest_block<F : 'static + std::marker::Send>(uri: &http::Uri, tls_options: &TlsOptions, mut c : F) 
     where F : FnMut(BlockId) {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
+    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), tls_options)
         .and_then(|mut client| {
             client.get_latest_block(Request::new(ChainSpec {}))
             .map_err(|e| { format!("ERR = {:?}", e) })
@@ -312,3 +489,116 @@ pub fn fetch_latest_block<F : 'static + std::marker::Send>(uri: &http::Uri, no_c
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use rustls::{Certificate, RootCertStore, ServerCertVerifier, internal::pemfile};
+    use sha2::{Sha256, Digest};
+
+    use super::{TlsOptions, pinned};
+
+    // A self-signed P-256 certificate for "localhost"
+    const CERT_PEM: &str = "-----BEGIN CERTIFICATE-----\n\
+MIIBgDCCASWgAwIBAgIUEsu6MB+P/LsqGp6NTTz5V9NgTnAwCgYIKoZIzj0EAwIw\n\
+FDESMBAGA1UEAwwJbG9jYWxob3N0MCAXDTI2MTAxOTAyNTQzNFoYDzIxMjYwOTI1\n\
+MDI1NDM0WjAUMRIwEAYDVQQDDAlsb2NhbGhvc3QwWTATBgcqhkjOPQIBBggqhkjO\n\
+PQMBBwNCAARCKZKdeOnngQziXjWpzd5OlxMWAC72lHVkDeEg07GpTWU5LMfRgV2r\n\
+vTXuYmjG/TJ8mT0QKHCd01m9N6q42Deao1MwUTAdBgNVHQ4EFgQUHqICuqSF76Lp\n\
+uysq5MSARyxUnQ0wHwYDVR0jBBgwFoAUHqICuqSF76Lpuysq5MSARyxUnQ0wDwYD\n\
+VR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNJADBGAiEAwyodS1AyOwW3OB1f6ifG\n\
+3jG2q+ukYzXXYLLizDbYgOoCIQDR03gdCygQu1zM2oKFcOBXn8xEKJhNDWX0W9Yb\n\
+dxlGaw==\n\
+-----END CERTIFICATE-----\n";
+
+    // openssl x509 -in cert.pem -noout -fingerprint -sha256
+    const CERT_SHA256: &str = "8A:B0:D1:CF:5D:4B:92:07:9B:E1:F8:E7:1A:79:02:C5:CF:67:85:B2:DF:0D:18:85:C3:75:76:2E:08:13:00:2F";
+
+    // openssl x509 -in cert.pem -noout -pubkey | openssl pkey -pubin -outform der | sha256sum
+    const SPKI_SHA256: &str = "230a42d818db302e563bab6a803386c21015e76b6786d83c11f7c1f00df9bca7";
+
+    fn get_cert() -> Certificate {
+        pemfile::certs(&mut CERT_PEM.as_bytes()).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_subject_public_key_info() {
+        let cert = get_cert();
+
+        assert_eq!(hex::encode(Sha256::digest(&cert.0)), CERT_SHA256.replace(":", "").to_lowercase());
+        let spki = pinned::subject_public_key_info(&cert.0).unwrap();
+        assert_eq!(hex::encode(Sha256::digest(spki)), SPKI_SHA256);
+
+        // Anything that isn't shaped like a certificate has no public key
+        assert!(pinned::subject_public_key_info(&[]).is_none());
+        assert!(pinned::subject_public_key_info(&cert.0[..cert.0.len() / 2]).is_none());
+        let mut bad_tag = cert.0.clone();
+        bad_tag[0] = 0x31;
+        assert!(pinned::subject_public_key_info(&bad_tag).is_none());
+    }
+
+    #[test]
+    fn test_pinned_verification() {
+        let cert = get_cert();
+        let roots = RootCertStore::empty();
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+        // Either the certificate or its public key can be pinned
+        for pin in &[CERT_SHA256, SPKI_SHA256] {
+            let mut options = TlsOptions::default();
+            options.set_pin(pin).unwrap();
+
+            let verifier = pinned::PinnedCertificateVerification { pin: options.pin.unwrap() };
+            assert!(verifier.verify_server_cert(&roots, &[cert.clone()], dns_name, &[]).is_ok());
+        }
+
+        // Any other pin is rejected, and so is a server without a certificate
+        let mut wrong_pin = [0u8; 32];
+        wrong_pin.copy_from_slice(&hex::decode(SPKI_SHA256).unwrap());
+        wrong_pin[31] ^= 1;
+        let verifier = pinned::PinnedCertificateVerification { pin: wrong_pin };
+        assert!(verifier.verify_server_cert(&roots, &[cert.clone()], dns_name, &[]).is_err());
+        assert!(verifier.verify_server_cert(&roots, &[], dns_name, &[]).is_err());
+    }
+
+    #[test]
+    fn test_set_pin() {
+        let mut options = TlsOptions::default();
+
+        options.set_pin(CERT_SHA256).unwrap();
+        assert_eq!(hex::encode(options.pin.unwrap()), CERT_SHA256.replace(":", "").to_lowercase());
+
+        options.set_pin(SPKI_SHA256).unwrap();
+        assert_eq!(hex::encode(options.pin.unwrap()), SPKI_SHA256);
+
+        // Wrong lengths and bad hex
+        assert!(options.set_pin(&SPKI_SHA256[2..]).err().unwrap().contains("32 byte"));
+        assert!(options.set_pin(&format!("{}00", SPKI_SHA256)).err().unwrap().contains("32 byte"));
+        assert!(options.set_pin("").is_err());
+        assert!(options.set_pin(&SPKI_SHA256.replace("a", "g")).is_err());
+
+        // A failed pin doesn't replace the last one
+        assert_eq!(hex::encode(options.pin.unwrap()), SPKI_SHA256);
+    }
+
+    #[test]
+    fn test_add_ca_file() {
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join("zecwallet-test-ca.pem");
+        let empty_path = dir.join("zecwallet-test-empty.pem");
+        fs::write(&cert_path, CERT_PEM).unwrap();
+        fs::write(&empty_path, "not a certificate").unwrap();
+
+        let mut options = TlsOptions::default();
+        options.add_ca_file(cert_path.to_str().unwrap()).unwrap();
+        assert_eq!(options.ca_certs.len(), 1);
+        assert_eq!(options.ca_certs[0].0, get_cert().0);
+
+        assert!(options.add_ca_file(empty_path.to_str().unwrap()).err().unwrap().contains("No PEM certificates"));
+        assert!(options.add_ca_file(dir.join("zecwallet-test-missing.pem").to_str().unwrap()).is_err());
+        assert_eq!(options.ca_certs.len(), 1);
+
+        fs::remove_file(cert_path).unwrap();
+        fs::remove_file(empty_path).unwrap();
+    }
+}
@@ -1,10 +1,12 @@
-use crate::lightwallet::LightWallet;
+use crate::lightwallet::{LightWallet, TxProposal, ChangePolicy, zip321, prover::InMemTxProver,
+                          MAX_TX_SIZE, DEFAULT_CONSOLIDATION_INPUTS, MESSAGE_VALUE};
 
 use log::{info, warn, error};
 
 use rand::{rngs::OsRng, seq::SliceRandom};
 
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicU64, AtomicI32, AtomicUsize, Ordering};
 use std::path::Path;
 use std::fs::File;
@@ -13,13 +15,16 @@ use std::io::prelude::*;
 use std::io::{BufReader, BufWriter, Error, ErrorKind};
 
 use json::{object, array, JsonValue};
+use sha2::{Sha256, Digest};
 use zcash_primitives::transaction::{TxId, Transaction};
+use zcash_primitives::JUBJUB;
 use zcash_client_backend::{
     constants::testnet, constants::mainnet, constants::regtest, encoding::encode_payment_address,
 };
 
 use crate::grpc_client::{BlockId};
 use crate::grpcconnector::*;
+#[cfg(feature = "embed_params")]
 use crate::SaplingParams;
 
 
@@ -27,6 +32,11 @@ pub const DEFAULT_SERVER: &str = "https://lightd-main.zecwallet.co:443";
 pub const WALLET_NAME: &str    = "zecwallet-light-wallet.dat";
 pub const LOGFILE_NAME: &str   = "zecwallet-light-wallet.debug.log";
 
+pub const SAPLING_SPEND_NAME: &str  = "sapling-spend.params";
+pub const SAPLING_OUTPUT_NAME: &str = "sapling-output.params";
+const SAPLING_SPEND_HASH: &str  = "8e48ffd23abb3a5fd9c5589204f32d9c31285a04b78096ba40a79b75677efc13";
+const SAPLING_OUTPUT_HASH: &str = "2f0ebbcbb9bb0bcffe95a397e7eba89c29eb4dde6191c339db88570e3f3fb0e4";
+
 
 #[derive(Clone, Debug)]
 pub struct LightClientConfig {
@@ -35,7 +45,8 @@ pub struct LightClientConfig {
     pub sapling_activation_height   : u64,
     pub consensus_branch_id         : String,
     pub anchor_offset               : u32,
-    pub no_cert_verification        : bool,
+    pub tls_options                 : TlsOptions,
+    pub params_dir                  : Option<String>,
 }
 
 impl LightClientConfig {
@@ -67,6 +78,24 @@ impl LightClientConfig {
         wallet_location.into_boxed_path()
     }
 
+    // The directory with the sapling params. Defaults to where zcashd's fetch-params.sh puts them
+    pub fn get_params_path(&self) -> Box<Path> {
+        if let Some(dir) = &self.params_dir {
+            return Path::new(dir).to_path_buf().into_boxed_path();
+        }
+
+        let mut params_location;
+        if cfg!(target_os="macos") || cfg!(target_os="windows") {
+            params_location = dirs::data_dir().expect("Couldn't determine app data directory!");
+            params_location.push("ZcashParams");
+        } else {
+            params_location = dirs::home_dir().expect("Couldn't determine home directory!");
+            params_location.push(".zcash-params");
+        };
+
+        params_location.into_boxed_path()
+    }
+
     pub fn get_log_path(&self) -> Box<Path> {
         let mut log_path = self.get_zcash_data_path().into_path_buf();
         log_path.push(LOGFILE_NAME);
@@ -91,10 +120,11 @@ impl LightClientConfig {
     pub fn get_server_or_default(server: Option<String>) -> http::Uri {
         match server {
             Some(s) => {
-                let mut s = if s.starts_with("http") {s} else { "http://".to_string() + &s};
+                // Servers without a scheme use TLS. Only http:// servers are connected to without it.
+                let mut s = if s.contains("://") {s} else { "https://".to_string() + &s};
                 let uri: http::Uri = s.parse().unwrap();
                 if uri.port_part().is_none() {
-                    s = s + ":443";
+                    s = s + if uri.scheme_str() == Some("http") { ":80" } else { ":443" };
                 }
                 s
             }
@@ -158,14 +188,146 @@ impl LightClientConfig {
     }
 }
 
+/// Read the sapling spend and output params from the params directory, and make sure they
+/// are the real ones. If they aren't there, fall back to the params embedded in the binary.
+pub fn read_sapling_params(config: &LightClientConfig) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let spend = read_params_file(config, SAPLING_SPEND_NAME, SAPLING_SPEND_HASH)?;
+    let output = read_params_file(config, SAPLING_OUTPUT_NAME, SAPLING_OUTPUT_HASH)?;
+
+    Ok((spend, output))
+}
+
+fn read_params_file(config: &LightClientConfig, name: &str, hash: &str) -> io::Result<Vec<u8>> {
+    let path = config.get_params_path().join(name);
+
+    let params = if path.exists() {
+        info!("Reading {}", path.display());
+        std::fs::read(&path).map_err(|e|
+            Error::new(e.kind(), format!("Couldn't read {}: {}", path.display(), e)))?
+    } else {
+        match embedded_params(name) {
+            Some(params) => params,
+            None => return Err(Error::new(ErrorKind::NotFound,
+                        format!("Couldn't find {}. Download the Sapling params with zcash's fetch-params.sh, or pass the directory they are in with --params-dir",
+                                path.display())))
+        }
+    };
+
+    let params_hash = hex::encode(Sha256::digest(&params));
+    if params_hash != hash {
+        let e = format!("{} has the wrong SHA-256 hash {}, expected {}. It might be corrupt or incomplete, please download it again",
+                        name, params_hash, hash);
+        error!("{}", e);
+        return Err(Error::new(ErrorKind::InvalidData, e));
+    }
+
+    Ok(params)
+}
+
+#[cfg(feature = "embed_params")]
+fn embedded_params(name: &str) -> Option<Vec<u8>> {
+    SaplingParams::get(name).map(|p| p.to_vec())
+}
+
+#[cfg(not(feature = "embed_params"))]
+fn embedded_params(_name: &str) -> Option<Vec<u8>> {
+    None
+}
+
+/// Which transactions `do_list_transactions_filtered` returns. The default matches all of them,
+/// except for change.
+#[derive(Clone, Debug, Default)]
+pub struct TxFilter {
+    pub address     : Option<String>,   // Received at, or sent to this address
+    pub direction   : Option<String>,   // "incoming", "outgoing" or "change"
+    pub min_height  : Option<i32>,
+    pub max_height  : Option<i32>,
+    pub from_date   : Option<u64>,      // Unix timestamps, inclusive
+    pub to_date     : Option<u64>,
+    pub min_amount  : Option<u64>,      // Absolute value in zatoshis
+    pub memo        : Option<String>,   // Substring of a memo
+    pub annotation  : Option<String>,   // Substring of the user's annotation, or one of its tags
+    pub tag         : Option<String>,
+    pub minconf     : Option<u32>,      // Minimum confirmations. Sends that aren't mined yet have 0
+    pub offset      : usize,
+    pub limit       : Option<usize>,
+}
+
+impl TxFilter {
+    pub fn matches(&self, tx: &JsonValue) -> bool {
+        // Outgoing transactions have the addresses and memos in their outgoing metadata
+        let addresses = if tx["outgoing_metadata"].is_array() {
+            tx["outgoing_metadata"].members().filter_map(|om| om["address"].as_str()).collect::<Vec<_>>()
+        } else {
+            tx["address"].as_str().map_or(vec![], |a| a.split(',').collect())
+        };
+        let memos = if tx["outgoing_metadata"].is_array() {
+            tx["outgoing_metadata"].members().filter_map(|om| om["memo"].as_str()).collect::<Vec<_>>()
+        } else {
+            tx["memo"].as_str().into_iter().collect()
+        };
+
+        let height = tx["block_height"].as_i32();
+        let datetime = tx["datetime"].as_u64().filter(|d| *d > 0);
+        let amount = tx["amount"].as_i64().map(|a| a.abs() as u64);
+
+        self.address.as_ref().map_or(true, |a| addresses.contains(&a.as_str()))
+            && self.direction.as_ref().map_or(true, |d| tx["direction"].as_str() == Some(d))
+            && self.min_height.map_or(true, |h| height.map_or(false, |height| height >= h))
+            && self.max_height.map_or(true, |h| height.map_or(false, |height| height <= h))
+            && self.from_date.map_or(true, |d| datetime.map_or(false, |datetime| datetime >= d))
+            && self.to_date.map_or(true, |d| datetime.map_or(false, |datetime| datetime <= d))
+            && self.min_amount.map_or(true, |m| amount.map_or(false, |amount| amount >= m))
+            && self.memo.as_ref().map_or(true, |m| memos.iter().any(|memo| memo.contains(m.as_str())))
+            && self.annotation.as_ref().map_or(true, |a|
+                    tx["annotation"].as_str().map_or(false, |text| text.contains(a.as_str()))
+                    || tx["tags"].members().any(|t| t.as_str() == Some(a.as_str())))
+            && self.tag.as_ref().map_or(true, |t| tx["tags"].members().any(|tag| tag.as_str() == Some(t.as_str())))
+            && self.minconf.map_or(true, |m| tx["confirmations"].as_u32().map_or(false, |c| c >= m))
+    }
+}
+
+// The sapling prover, which is built from the spend and output params the first time it's needed.
+// Reading the params and building the prover takes a few seconds, so it's done once and shared by all
+// sends. Opening, syncing or looking at a wallet doesn't need the params at all.
+enum SaplingProver {
+    NotLoaded,
+    Ready(Arc<InMemTxProver>),
+}
+
+fn build_prover(prover: &Mutex<SaplingProver>, config: &LightClientConfig) -> Result<Arc<InMemTxProver>, String> {
+    let mut prover = prover.lock().unwrap();
+
+    if let SaplingProver::Ready(p) = &*prover {
+        return Ok(p.clone());
+    }
+
+    let (sapling_spend, sapling_output) = read_sapling_params(config).map_err(|e| {
+        let e = format!("Couldn't load the sapling params: {}", e);
+        error!("{}", e);
+        e
+    })?;
+
+    info!("Building the sapling prover");
+    let ready = Arc::new(InMemTxProver::new(&sapling_spend, &sapling_output));
+
+    *prover = SaplingProver::Ready(ready.clone());
+    Ok(ready)
+}
+
 pub struct LightClient {
     pub wallet          : Arc<LightWallet>,
 
     pub config          : LightClientConfig,
 
     // zcash-params
-    pub sapling_output  : Vec<u8>,
-    pub sapling_spend   : Vec<u8>,
+    prover              : Arc<Mutex<SaplingProver>>,
+
+    // The last transaction proposal from a dry run send, waiting to be confirmed
+    pub proposal        : Arc<RwLock<Option<TxProposal>>>,
+
+    // Raw transactions that were fetched to show their details, so they're only downloaded once
+    full_txs            : Arc<RwLock<HashMap<TxId, Vec<u8>>>>,
 }
 
 impl LightClient {
@@ -182,7 +344,7 @@ impl LightClient {
     }
 
     pub fn new(seed_phrase: Option<String>, config: &LightClientConfig, latest_block: u64) -> io::Result<Self> {
-        let mut lc = if config.get_wallet_path().exists() {
+        let lc = if config.get_wallet_path().exists() {
             // Make sure that if a wallet exists, there is no seed phrase being attempted
             if !seed_phrase.is_none() {
                 return Err(Error::new(ErrorKind::AlreadyExists,
@@ -195,15 +357,17 @@ impl LightClient {
              LightClient {
                 wallet          : Arc::new(wallet),
                 config          : config.clone(),
-                sapling_output  : vec![], 
-                sapling_spend   : vec![]
+                prover          : Arc::new(Mutex::new(SaplingProver::NotLoaded)),
+                proposal        : Arc::new(RwLock::new(None)),
+                full_txs        : Arc::new(RwLock::new(HashMap::new())),
             }
         } else {
             let l = LightClient {
                 wallet          : Arc::new(LightWallet::new(seed_phrase, config, latest_block)?),
                 config          : config.clone(),
-                sapling_output  : vec![], 
-                sapling_spend   : vec![]
+                prover          : Arc::new(Mutex::new(SaplingProver::NotLoaded)),
+                proposal        : Arc::new(RwLock::new(None)),
+                full_txs        : Arc::new(RwLock::new(HashMap::new())),
             };
 
             l.set_wallet_initial_state();
@@ -212,16 +376,28 @@ impl LightClient {
         };
 
         info!("Read wallet with birthday {}", lc.wallet.get_first_tx_block());
-        
-        // Read Sapling Params
-        lc.sapling_output.extend_from_slice(SaplingParams::get("sapling-output.params").unwrap().as_ref());
-        lc.sapling_spend.extend_from_slice(SaplingParams::get("sapling-spend.params").unwrap().as_ref());
 
         info!("Created LightClient to {}", &config.server);
 
         Ok(lc)
     }
 
+    // The shared prover, which is built the first time it's needed
+    fn get_prover(&self) -> Result<Arc<InMemTxProver>, String> {
+        build_prover(&self.prover, &self.config)
+    }
+
+    /// Build the prover in the background, so the first send doesn't have to wait for it.
+    /// A send that starts before it's done waits for it instead of building another one.
+    /// If the params can't be found, the error is logged, and sends report it again.
+    pub fn warm_up_prover(&self) {
+        let prover = self.prover.clone();
+        let config = self.config.clone();
+        std::thread::spawn(move || {
+            let _ = build_prover(&prover, &config);
+        });
+    }
+
     pub fn last_scanned_height(&self) -> u64 {
         self.wallet.last_scanned_height() as u64
     }
@@ -278,14 +454,25 @@ impl LightClient {
         }
     }
 
-    pub fn do_balance(&self) -> JsonValue {
+    /// The balances of the wallet and of each address. With `minconf`, `zbalance` and `tbalance` only count
+    /// funds that have at least that many confirmations.
+    pub fn do_balance(&self, minconf: Option<u32>) -> JsonValue {
+        let minconf_or_0 = minconf.unwrap_or(0);
+
         // Collect z addresses
         let z_addresses = self.wallet.address.read().unwrap().iter().map( |ad| {
             let address = encode_payment_address(self.config.hrp_sapling_address(), &ad);
+            let breakdown = self.wallet.balance_breakdown(Some(address.clone()));
             object!{
-                "address" => address.clone(),
-                "zbalance" => self.wallet.zbalance(Some(address.clone())),
-                "verified_zbalance" => self.wallet.verified_zbalance(Some(address)),
+                "address"                     => address.clone(),
+                "label"                       => self.wallet.get_label(&address),
+                "zbalance"                    => self.wallet.zbalance_minconf(Some(address.clone()), minconf_or_0),
+                "verified_zbalance"           => self.wallet.verified_zbalance(Some(address)),
+                "spendable_zbalance"          => breakdown.spendable,
+                "unconfirmed_zbalance"        => breakdown.unconfirmed,
+                "unconfirmed_change_zbalance" => breakdown.unconfirmed_change,
+                "blocks_until_spendable"      => breakdown.blocks_until_spendable,
+                "pending_spent_zbalance"      => breakdown.pending_spent,
             }
         }).collect::<Vec<JsonValue>>();
 
@@ -294,20 +481,36 @@ impl LightClient {
             let address = self.wallet.address_from_sk(&sk);
 
             // Get the balance for this address
-            let balance = self.wallet.tbalance(Some(address.clone()));
+            let balance = self.wallet.tbalance_minconf(Some(address.clone()), minconf_or_0);
+            let breakdown = self.wallet.balance_breakdown(Some(address.clone()));
             
             object!{
-                "address" => address,
-                "balance" => balance,
+                "label"                  => self.wallet.get_label(&address),
+                "address"                => address,
+                "balance"                => balance,
+                "confirmed_tbalance"     => breakdown.confirmed_transparent,
+                "unconfirmed_tbalance"   => breakdown.unconfirmed_transparent,
+                "pending_spent_tbalance" => breakdown.pending_spent_transparent,
             }
         }).collect::<Vec<JsonValue>>();
 
+        let breakdown = self.wallet.balance_breakdown(None);
+
         object!{
-            "zbalance"           => self.wallet.zbalance(None),
-            "verified_zbalance"  => self.wallet.verified_zbalance(None),
-            "tbalance"           => self.wallet.tbalance(None),
-            "z_addresses"        => z_addresses,
-            "t_addresses"        => t_addresses,
+            "minconf"                     => minconf,
+            "zbalance"                    => self.wallet.zbalance_minconf(None, minconf_or_0),
+            "verified_zbalance"           => self.wallet.verified_zbalance(None),
+            "spendable_zbalance"          => breakdown.spendable,
+            "unconfirmed_zbalance"        => breakdown.unconfirmed,
+            "unconfirmed_change_zbalance" => breakdown.unconfirmed_change,
+            "blocks_until_spendable"      => breakdown.blocks_until_spendable,
+            "pending_spent_zbalance"      => breakdown.pending_spent,
+            "tbalance"                    => self.wallet.tbalance_minconf(None, minconf_or_0),
+            "confirmed_tbalance"          => breakdown.confirmed_transparent,
+            "unconfirmed_tbalance"        => breakdown.unconfirmed_transparent,
+            "pending_spent_tbalance"      => breakdown.pending_spent_transparent,
+            "z_addresses"                 => z_addresses,
+            "t_addresses"                 => t_addresses,
         }
     }
 
@@ -327,7 +530,7 @@ impl LightClient {
     }
 
     pub fn do_info(&self) -> String {
-        match get_info(self.get_server_uri(), self.config.no_cert_verification) {
+        match get_info(self.get_server_uri(), &self.config.tls_options) {
             Ok(i) => format!("{:?}", i)[11..].to_string(),
             Err(e) => e
         }
@@ -340,8 +543,9 @@ impl LightClient {
         }
     }
 
-    // Return a list of all notes, spent and unspent
-    pub fn do_list_notes(&self, all_notes: bool) -> JsonValue {
+    // Return a list of all notes, spent and unspent. Only the notes and utxos with at least
+    // `minconf` confirmations are included.
+    pub fn do_list_notes(&self, all_notes: bool, minconf: u32) -> JsonValue {
         let mut unspent_notes: Vec<JsonValue> = vec![];
         let mut spent_notes  : Vec<JsonValue> = vec![];
         let mut pending_notes: Vec<JsonValue> = vec![];
@@ -356,9 +560,13 @@ impl LightClient {
                         Some(object!{
                             "created_in_block"   => wtx.block,
                             "created_in_txid"    => format!("{}", txid),
+                            "datetime"           => wtx.datetime,
+                            "date"               => LightWallet::datetime_str(wtx.datetime),
                             "value"              => nd.note.value,
                             "is_change"          => nd.is_change,
                             "address"            => self.wallet.note_address(nd),
+                            "memo"               => LightWallet::memo_str(&nd.memo),
+                            "memo_hex"           => LightWallet::memo_hex(&nd.memo),
                             "spent"              => nd.spent.map(|spent_txid| format!("{}", spent_txid)),
                             "unconfirmed_spent"  => nd.unconfirmed_spent.map(|spent_txid| format!("{}", spent_txid)),
                         })
@@ -375,6 +583,9 @@ impl LightClient {
                 }
             });
         
+        // The time of the Tx that created a utxo
+        let tx_time = |txid: &TxId| self.wallet.txs.read().unwrap().get(txid).map_or(0, |wtx| wtx.datetime);
+
         // Collect UTXOs
         let utxos = self.wallet.get_utxos().iter()
             .filter(|utxo| utxo.unconfirmed_spent.is_none())    // Filter out unconfirmed from the list of utxos
@@ -382,9 +593,11 @@ impl LightClient {
                 object!{
                     "created_in_block"   => utxo.height,
                     "created_in_txid"    => format!("{}", utxo.txid),
+                    "datetime"           => tx_time(&utxo.txid),
+                    "date"               => LightWallet::datetime_str(tx_time(&utxo.txid)),
                     "value"              => utxo.value,
                     "scriptkey"          => hex::encode(utxo.script.clone()),
-                    "is_change"          => false,  // TODO: Identify notes as change if we send change to taddrs
+                    "is_change"          => utxo.is_change,
                     "address"            => utxo.address.clone(),
                     "spent"              => utxo.spent.map(|spent_txid| format!("{}", spent_txid)),
                     "unconfirmed_spent"  => utxo.unconfirmed_spent.map(|spent_txid| format!("{}", spent_txid)),
@@ -399,9 +612,11 @@ impl LightClient {
                 object!{
                     "created_in_block"   => utxo.height,
                     "created_in_txid"    => format!("{}", utxo.txid),
+                    "datetime"           => tx_time(&utxo.txid),
+                    "date"               => LightWallet::datetime_str(tx_time(&utxo.txid)),
                     "value"              => utxo.value,
                     "scriptkey"          => hex::encode(utxo.script.clone()),
-                    "is_change"          => false,  // TODO: Identify notes as change if we send change to taddrs
+                    "is_change"          => utxo.is_change,
                     "address"            => utxo.address.clone(),
                     "spent"              => utxo.spent.map(|spent_txid| format!("{}", spent_txid)),
                     "unconfirmed_spent"  => utxo.unconfirmed_spent.map(|spent_txid| format!("{}", spent_txid)),
@@ -430,9 +645,11 @@ impl LightClient {
                             object!{
                                 "created_in_block"   => wtx.block,
                                 "created_in_txid"    => format!("{}", utxo.txid),
+                                "datetime"           => wtx.datetime,
+                                "date"               => LightWallet::datetime_str(wtx.datetime),
                                 "value"              => utxo.value,
                                 "scriptkey"          => hex::encode(utxo.script.clone()),
-                                "is_change"          => false,  // TODO: Identify notes as change if we send change to taddrs
+                                "is_change"          => utxo.is_change,
                                 "address"            => utxo.address.clone(),
                                 "spent"              => utxo.spent.map(|spent_txid| format!("{}", spent_txid)),
                                 "unconfirmed_spent"  => utxo.unconfirmed_spent.map(|spent_txid| format!("{}", spent_txid)),
@@ -442,24 +659,55 @@ impl LightClient {
             );
         }
 
+        // Show the address book labels next to the addresses
+        for (_, entries) in res.entries_mut() {
+            for entry in entries.members_mut() {
+                entry["label"] = self.labels_for(&entry["address"]);
+                entry["confirmations"] = self.wallet.confirmations(entry["created_in_block"].as_i32().unwrap_or(i32::max_value())).into();
+            }
+
+            let kept = entries.members()
+                .filter(|entry| entry["confirmations"].as_u32().unwrap_or(0) >= minconf)
+                .cloned()
+                .collect::<Vec<JsonValue>>();
+            *entries = JsonValue::Array(kept);
+        }
+
         res
     }
 
+    // The address book labels for an address, or for a comma separated list of addresses
+    fn labels_for(&self, addresses: &JsonValue) -> JsonValue {
+        let labels = addresses.as_str().map_or(vec![], |a| a.split(',').filter_map(|a| self.wallet.get_label(a)).collect());
+
+        if labels.is_empty() {
+            JsonValue::Null
+        } else {
+            labels.join(",").into()
+        }
+    }
+
     pub fn do_list_transactions(&self) -> JsonValue {
+        self.do_list_transactions_filtered(&TxFilter::default())
+    }
+
+    /// List the transactions that match the filter, oldest first
+    pub fn do_list_transactions_filtered(&self, filter: &TxFilter) -> JsonValue {
+        // The address can also be a label from the address book
+        let mut filter = filter.clone();
+        filter.address = filter.address.map(|a| self.wallet.resolve_address(&a));
+
         // Create a list of TransactionItems
         let mut tx_list = self.wallet.txs.read().unwrap().iter()
             .flat_map(| (_k, v) | {
                 let mut txns: Vec<JsonValue> = vec![];
 
-                if v.total_shielded_value_spent > 0 {
-                    // If money was spent, create a transaction. For this, we'll subtract
-                    // all the change notes. TODO: Add transparent change here to subtract it also
-                    let total_change: u64 = v.notes.iter()
-                        .filter( |nd| nd.is_change )
-                        .map( |nd| nd.note.value )
-                        .sum();
+                let total_spent = v.total_value_spent();
+                let total_change = v.total_change();
 
-                    // TODO: What happens if change is > than sent ?
+                if total_spent > 0 && total_change <= total_spent {
+                    // If money was spent, create a transaction. For this, we'll subtract
+                    // all the change notes and utxos, shielded or transparent.
 
                     // Collect outgoing metadata
                     let outgoing_json = v.outgoing_metadata.iter()
@@ -468,18 +716,73 @@ impl LightClient {
                                 "address" => om.address.clone(),
                                 "value"   => om.value,
                                 "memo"    => LightWallet::memo_str(&Some(om.memo.clone())),
+                                "memo_hex"=> LightWallet::memo_hex(&Some(om.memo.clone())),
                         })
                         .collect::<Vec<JsonValue>>();                    
 
                     txns.push(object! {
                         "block_height" => v.block,
+                        "datetime"     => v.datetime,
+                        "date"         => LightWallet::datetime_str(v.datetime),
                         "txid"         => format!("{}", v.txid),
-                        "amount"       => total_change as i64 
-                                            - v.total_shielded_value_spent as i64 
-                                            - v.total_transparent_value_spent as i64,
+                        "amount"       => total_change as i64 - total_spent as i64,
+                        "fee"          => v.fee(),
+                        "direction"    => "outgoing",
                         "outgoing_metadata" => outgoing_json,
                     });
-                } 
+                } else if total_spent > 0 {
+                    // Someone else also put money into this Tx, and more came back to our change
+                    // addresses than we spent, so the difference is incoming.
+                    let change_addresses = v.notes.iter().filter(|nd| nd.is_change).map(|nd| self.wallet.note_address(nd))
+                        .chain(v.utxos.iter().filter(|u| u.is_change).map(|u| Some(u.address.clone())))
+                        .filter_map(|a| a)
+                        .collect::<Vec<String>>();
+
+                    txns.push(object! {
+                        "block_height" => v.block,
+                        "datetime"     => v.datetime,
+                        "date"         => LightWallet::datetime_str(v.datetime),
+                        "txid"         => format!("{}", v.txid),
+                        "amount"       => (total_change - total_spent) as i64,
+                        "direction"    => "incoming",
+                        "address"      => change_addresses.join(","),
+                        "memo"         => None::<String>
+                    });
+                }
+
+                // Change is only listed if it's asked for
+                if filter.direction.as_ref().map_or(false, |d| d == "change") {
+                    txns.extend(v.notes.iter()
+                        .filter( |nd| nd.is_change )
+                        .map ( |nd|
+                            object! {
+                                "block_height" => v.block,
+                                "datetime"     => v.datetime,
+                                "date"         => LightWallet::datetime_str(v.datetime),
+                                "txid"         => format!("{}", v.txid),
+                                "amount"       => nd.note.value as i64,
+                                "direction"    => "change",
+                                "address"      => self.wallet.note_address(nd),
+                                "memo"         => LightWallet::memo_str(&nd.memo),
+                                "memo_hex"     => LightWallet::memo_hex(&nd.memo),
+                        })
+                    );
+
+                    txns.extend(v.utxos.iter()
+                        .filter( |u| u.is_change )
+                        .map ( |u|
+                            object! {
+                                "block_height" => v.block,
+                                "datetime"     => v.datetime,
+                                "date"         => LightWallet::datetime_str(v.datetime),
+                                "txid"         => format!("{}", v.txid),
+                                "amount"       => u.value as i64,
+                                "direction"    => "change",
+                                "address"      => u.address.clone(),
+                                "memo"         => None::<String>
+                        })
+                    );
+                }
 
                 // For each sapling note that is not a change, add a Tx.
                 txns.extend(v.notes.iter()
@@ -487,22 +790,30 @@ impl LightClient {
                     .map ( |nd| 
                         object! {
                             "block_height" => v.block,
+                            "datetime"     => v.datetime,
+                            "date"         => LightWallet::datetime_str(v.datetime),
                             "txid"         => format!("{}", v.txid),
                             "amount"       => nd.note.value as i64,
+                            "direction"    => "incoming",
                             "address"      => self.wallet.note_address(nd),
                             "memo"         => LightWallet::memo_str(&nd.memo),
+                            "memo_hex"     => LightWallet::memo_hex(&nd.memo),
                     })
                 );
 
-                // Get the total transparent received
-                let total_transparent_received = v.utxos.iter().map(|u| u.value).sum::<u64>();
-                if total_transparent_received > v.total_transparent_value_spent {
+                // Get the total transparent received, not counting change, which was subtracted above
+                let received_utxos = v.utxos.iter().filter(|u| !u.is_change).collect::<Vec<_>>();
+                let total_transparent_received = received_utxos.iter().map(|u| u.value).sum::<u64>();
+                if total_transparent_received > 0 {
                     // Create an input transaction for the transparent value as well.
                     txns.push(object!{
                         "block_height" => v.block,
+                        "datetime"     => v.datetime,
+                        "date"         => LightWallet::datetime_str(v.datetime),
                         "txid"         => format!("{}", v.txid),
-                        "amount"       => total_transparent_received as i64 - v.total_transparent_value_spent as i64,
-                        "address"      => v.utxos.iter().map(|u| u.address.clone()).collect::<Vec<String>>().join(","),
+                        "amount"       => total_transparent_received as i64,
+                        "direction"    => "incoming",
+                        "address"      => received_utxos.iter().map(|u| u.address.clone()).collect::<Vec<String>>().join(","),
                         "memo"         => None::<String>
                     })
                 }
@@ -511,14 +822,535 @@ impl LightClient {
             })
             .collect::<Vec<JsonValue>>();
 
+        // Add the sends that haven't been mined yet
+        tx_list.extend(self.pending_sent_txs());
+
+        // Unmined sends don't have a block height, so they go last
         tx_list.sort_by( |a, b| if a["block_height"] == b["block_height"] {
                                     a["txid"].as_str().cmp(&b["txid"].as_str())
                                 } else {
-                                    a["block_height"].as_i32().cmp(&b["block_height"].as_i32())
+                                    a["block_height"].as_i32().unwrap_or(i32::max_value())
+                                        .cmp(&b["block_height"].as_i32().unwrap_or(i32::max_value()))
                                 }
         );
 
-        JsonValue::Array(tx_list)
+        for tx in tx_list.iter_mut() {
+            tx["confirmations"] = self.wallet.confirmations(tx["block_height"].as_i32().unwrap_or(i32::max_value())).into();
+
+            let annotation = LightWallet::parse_txid(tx["txid"].as_str().unwrap_or(""))
+                                .ok()
+                                .and_then(|txid| self.wallet.get_annotation(&txid))
+                                .unwrap_or_default();
+            tx["annotation"] = annotation.text.into();
+            tx["tags"] = annotation.tags.into();
+
+            if tx["outgoing_metadata"].is_array() {
+                for om in tx["outgoing_metadata"].members_mut() {
+                    om["label"] = self.labels_for(&om["address"]);
+                }
+            } else {
+                tx["label"] = self.labels_for(&tx["address"]);
+            }
+        }
+
+        JsonValue::Array(tx_list.into_iter()
+            .filter(|tx| filter.matches(tx))
+            .skip(filter.offset)
+            .take(filter.limit.unwrap_or(usize::max_value()))
+            .collect())
+    }
+
+    // The raw bytes of a transaction, from the cache, our pending sends or the server
+    fn get_full_tx(&self, txid: &TxId) -> Option<Vec<u8>> {
+        if let Some(raw) = self.full_txs.read().unwrap().get(txid) {
+            return Some(raw.clone());
+        }
+
+        let raw = self.wallet.get_sent_tx_raw(txid).or_else(|| {
+            let fetched = Arc::new(RwLock::new(None));
+            let fetched_clone = fetched.clone();
+
+            fetch_full_tx(&self.get_server_uri(), txid.clone(), &self.config.tls_options, move |tx_bytes: &[u8]| {
+                *fetched_clone.write().unwrap() = Some(tx_bytes.to_vec());
+            });
+
+            let raw = fetched.read().unwrap().clone();
+            raw.filter(|r: &Vec<u8>| !r.is_empty())
+        })?;
+
+        self.full_txs.write().unwrap().insert(txid.clone(), raw.clone());
+        Some(raw)
+    }
+
+    /// Everything the wallet knows about a transaction, and its transparent inputs and outputs from the full transaction
+    pub fn do_tx(&self, txid: &str) -> JsonValue {
+        let txid = match LightWallet::parse_txid(txid) {
+            Ok(t)  => t,
+            Err(e) => return object!{ "error" => e }
+        };
+
+        let full_tx = self.get_full_tx(&txid).and_then(|raw| Transaction::read(&raw[..]).ok());
+
+        let mut res = match self.wallet.txs.read().unwrap().get(&txid) {
+            Some(wtx) => {
+                let annotation = self.wallet.get_annotation(&txid).unwrap_or_default();
+
+                object!{
+                    "txid"                          => format!("{}", txid),
+                    "in_wallet"                     => true,
+                    "block_height"                  => wtx.block,
+                    "datetime"                      => wtx.datetime,
+                    "date"                          => LightWallet::datetime_str(wtx.datetime),
+                    "confirmations"                 => self.wallet.confirmations(wtx.block),
+                    "amount"                        => wtx.net_value(),
+                    "fee"                           => wtx.fee(),
+                    "total_shielded_value_spent"    => wtx.total_shielded_value_spent,
+                    "total_transparent_value_spent" => wtx.total_transparent_value_spent,
+                    "notes"                         => wtx.notes.iter().map(|nd| object!{
+                        "address"           => self.wallet.note_address(nd),
+                        "value"             => nd.note.value,
+                        "is_change"         => nd.is_change,
+                        "memo"              => LightWallet::memo_str(&nd.memo),
+                        "memo_hex"          => LightWallet::memo_hex(&nd.memo),
+                        "spent"             => nd.spent.map(|spent_txid| format!("{}", spent_txid)),
+                        "unconfirmed_spent" => nd.unconfirmed_spent.map(|spent_txid| format!("{}", spent_txid)),
+                    }).collect::<Vec<JsonValue>>(),
+                    "utxos"                         => wtx.utxos.iter().map(|u| object!{
+                        "address"           => u.address.clone(),
+                        "output_index"      => u.output_index,
+                        "value"             => u.value,
+                        "is_change"         => u.is_change,
+                        "spent"             => u.spent.map(|spent_txid| format!("{}", spent_txid)),
+                        "unconfirmed_spent" => u.unconfirmed_spent.map(|spent_txid| format!("{}", spent_txid)),
+                    }).collect::<Vec<JsonValue>>(),
+                    "outgoing_metadata"             => wtx.outgoing_metadata.iter().map(|om| object!{
+                        "address"  => om.address.clone(),
+                        "label"    => self.wallet.get_label(&om.address),
+                        "value"    => om.value,
+                        "memo"     => LightWallet::memo_str(&Some(om.memo.clone())),
+                        "memo_hex" => LightWallet::memo_hex(&Some(om.memo.clone())),
+                    }).collect::<Vec<JsonValue>>(),
+                    "full_tx_scanned"               => wtx.full_tx_scanned,
+                    "annotation"                    => annotation.text,
+                    "tags"                          => annotation.tags,
+                }
+            },
+            None if full_tx.is_some() => object!{
+                "txid"      => format!("{}", txid),
+                "in_wallet" => false,
+            },
+            None => return object!{ "error" => format!("Transaction {} is not in this wallet, and couldn't be fetched", txid) }
+        };
+
+        match full_tx {
+            Some(tx) => {
+                // We only know the address and value of inputs that spent our own utxos
+                let txs = self.wallet.txs.read().unwrap();
+                res["transparent_inputs"] = tx.vin.iter().map(|vin| {
+                    let utxo = txs.get(&TxId{0: vin.prevout.hash})
+                                .and_then(|wtx| wtx.utxos.iter().find(|u| u.output_index == vin.prevout.n as u64));
+                    object!{
+                        "prevout_txid"  => format!("{}", TxId{0: vin.prevout.hash}),
+                        "prevout_index" => vin.prevout.n,
+                        "address"       => utxo.map(|u| u.address.clone()),
+                        "value"         => utxo.map(|u| u.value),
+                    }
+                }).collect::<Vec<JsonValue>>().into();
+
+                res["transparent_outputs"] = tx.vout.iter().enumerate().map(|(n, vout)| {
+                    let address = self.wallet.address_from_pubkeyhash(vout.script_pubkey.address());
+                    object!{
+                        "index"   => n,
+                        "is_mine" => address.as_ref().map_or(false, |a| self.wallet.is_wallet_address(a)),
+                        "address" => address,
+                        "value"   => i64::from(vout.value),
+                    }
+                }).collect::<Vec<JsonValue>>().into();
+
+                res["shielded_spends"] = tx.shielded_spends.len().into();
+                res["shielded_outputs"] = tx.shielded_outputs.len().into();
+                res["value_balance"] = i64::from(tx.value_balance).into();
+                res["expiry_height"] = tx.expiry_height.into();
+            },
+            None => {
+                warn!("Couldn't fetch the full transaction {}", txid);
+                res["full_tx_error"] = "Couldn't fetch the full transaction".into();
+            }
+        }
+
+        res
+    }
+
+    /// Every note and utxo received by `address` (or the address with that label) with at least
+    /// `minconf` confirmations, oldest first
+    pub fn do_received(&self, address: &str, minconf: u32) -> JsonValue {
+        let address = self.wallet.resolve_address(address);
+
+        let mut received = self.wallet.txs.read().unwrap().values()
+            .filter(|wtx| self.wallet.confirmations(wtx.block) >= minconf)
+            .flat_map(|wtx| {
+                let notes = wtx.notes.iter()
+                    .filter(|nd| self.wallet.note_address(nd).as_ref() == Some(&address))
+                    .map(move |nd| object!{
+                        "block_height"  => wtx.block,
+                        "datetime"      => wtx.datetime,
+                        "date"          => LightWallet::datetime_str(wtx.datetime),
+                        "confirmations" => self.wallet.confirmations(wtx.block),
+                        "txid"          => format!("{}", wtx.txid),
+                        "amount"        => nd.note.value,
+                        "is_change"     => nd.is_change,
+                        "memo"          => LightWallet::memo_str(&nd.memo),
+                        "memo_hex"      => LightWallet::memo_hex(&nd.memo),
+                    });
+
+                let utxos = wtx.utxos.iter()
+                    .filter(|u| u.address == address)
+                    .map(move |u| object!{
+                        "block_height"  => wtx.block,
+                        "datetime"      => wtx.datetime,
+                        "date"          => LightWallet::datetime_str(wtx.datetime),
+                        "confirmations" => self.wallet.confirmations(wtx.block),
+                        "txid"          => format!("{}", wtx.txid),
+                        "amount"        => u.value,
+                        "is_change"     => u.is_change,
+                        "memo"          => None::<String>,
+                    });
+
+                notes.chain(utxos).collect::<Vec<JsonValue>>()
+            })
+            .collect::<Vec<JsonValue>>();
+
+        received.sort_by(|a, b| a["block_height"].as_i32().cmp(&b["block_height"].as_i32())
+                                    .then(a["txid"].as_str().cmp(&b["txid"].as_str())));
+
+        object!{
+            "address"  => address.clone(),
+            "label"    => self.wallet.get_label(&address),
+            "minconf"  => minconf,
+            "received" => received,
+        }
+    }
+
+    /// The totals received by every wallet address, including diversified ones, and what is still unspent
+    pub fn do_received_by_address(&self, minconf: u32) -> JsonValue {
+        self.wallet.received_by_address(minconf).into_iter().map(|r| object!{
+            "label"    => self.wallet.get_label(&r.address),
+            "address"  => r.address,
+            "received" => r.received,
+            "count"    => r.count,
+            "unspent"  => r.unspent,
+        }).collect::<Vec<JsonValue>>().into()
+    }
+
+    // The text memos we received, newest first and numbered from 1, with the sender's address if
+    // they added one with the reply-to convention
+    fn inbox_messages(&self) -> Vec<JsonValue> {
+        let mut messages = self.wallet.txs.read().unwrap().values()
+            .flat_map(|wtx| wtx.notes.iter()
+                .filter(|nd| !nd.is_change)
+                .filter_map(move |nd| {
+                    let memo = LightWallet::memo_str(&nd.memo).filter(|m| !m.trim().is_empty())?;
+                    let (message, reply_to) = LightWallet::parse_reply_to(&memo);
+
+                    Some(object!{
+                        "block_height" => wtx.block,
+                        "datetime"     => wtx.datetime,
+                        "date"         => LightWallet::datetime_str(wtx.datetime),
+                        "txid"         => format!("{}", wtx.txid),
+                        "amount"       => nd.note.value,
+                        "address"      => self.wallet.note_address(nd),
+                        "memo"         => message,
+                        "reply_to"     => reply_to,
+                    })
+                })
+            )
+            .collect::<Vec<JsonValue>>();
+
+        // Number them oldest first, so a message keeps its number when new ones arrive, and list them newest first
+        messages.sort_by(|a, b| a["block_height"].as_i32().cmp(&b["block_height"].as_i32())
+                                    .then(a["txid"].as_str().cmp(&b["txid"].as_str())));
+
+        for (i, m) in messages.iter_mut().enumerate() {
+            m["n"] = (i + 1).into();
+            m["reply_to_label"] = self.labels_for(&m["reply_to"]);
+        }
+
+        messages.reverse();
+        messages
+    }
+
+    /// The received memos, newest first, and the conversations with every sender that added a reply-to
+    /// address, made of their messages and the memos we sent them, oldest first
+    pub fn do_inbox(&self) -> JsonValue {
+        let messages = self.inbox_messages();
+
+        let mut conversations: HashMap<String, Vec<JsonValue>> = HashMap::new();
+        for m in messages.iter() {
+            if let Some(reply_to) = m["reply_to"].as_str() {
+                conversations.entry(reply_to.to_string()).or_default().push(object!{
+                    "direction"    => "incoming",
+                    "n"            => m["n"].clone(),
+                    "block_height" => m["block_height"].clone(),
+                    "date"         => m["date"].clone(),
+                    "txid"         => m["txid"].clone(),
+                    "memo"         => m["memo"].clone(),
+                });
+            }
+        }
+
+        // The memos we sent, without our own reply-to address
+        for wtx in self.wallet.txs.read().unwrap().values() {
+            for om in wtx.outgoing_metadata.iter() {
+                if let Some(memo) = LightWallet::memo_str(&Some(om.memo.clone())).filter(|m| !m.trim().is_empty()) {
+                    conversations.entry(om.address.clone()).or_default().push(object!{
+                        "direction"    => "outgoing",
+                        "block_height" => wtx.block,
+                        "date"         => LightWallet::datetime_str(wtx.datetime),
+                        "txid"         => format!("{}", wtx.txid),
+                        "memo"         => LightWallet::parse_reply_to(&memo).0,
+                    });
+                }
+            }
+        }
+
+        // Only the counterparties that wrote to us are conversations. The most recent one goes first.
+        let mut conversations = conversations.into_iter()
+            .filter(|(_, msgs)| msgs.iter().any(|m| m["direction"] == "incoming"))
+            .map(|(counterparty, mut msgs)| {
+                msgs.sort_by(|a, b| a["block_height"].as_i32().cmp(&b["block_height"].as_i32()));
+                object!{
+                    "label"        => self.wallet.get_label(&counterparty),
+                    "counterparty" => counterparty,
+                    "messages"     => msgs,
+                }
+            })
+            .collect::<Vec<JsonValue>>();
+        conversations.sort_by(|a, b| {
+            let latest = |c: &JsonValue| c["messages"].members().last().and_then(|m| m["block_height"].as_i32());
+            latest(b).cmp(&latest(a))
+        });
+
+        object!{
+            "messages"      => messages,
+            "conversations" => conversations,
+        }
+    }
+
+    /// Reply to a message of the inbox, given by its number or its txid, sending `text` with a small amount
+    /// to its reply-to address. Our address that received the message is added as the reply-to address of the reply.
+    pub fn do_reply(&self, id: &str, text: &str) -> String {
+        let messages = self.inbox_messages();
+        let message = match id.parse::<usize>() {
+            Ok(n)  => messages.iter().find(|m| m["n"].as_usize() == Some(n)),
+            // A tx can have more than one memo for us, so prefer the one with a reply-to address
+            Err(_) => messages.iter().filter(|m| m["txid"].as_str() == Some(id)).find(|m| m["reply_to"].is_string())
+                        .or_else(|| messages.iter().find(|m| m["txid"].as_str() == Some(id))),
+        };
+        let message = match message {
+            Some(m) => m,
+            None    => return format!("There is no message {} in the inbox", id),
+        };
+
+        let reply_to = match message["reply_to"].as_str() {
+            Some(a) => a.to_string(),
+            None    => return format!("Message {} doesn't have a reply-to address", id),
+        };
+
+        let our_address = match message["address"].as_str() {
+            Some(a) => a.to_string(),
+            None    => encode_payment_address(self.config.hrp_sapling_address(), &self.wallet.address.read().unwrap()[0]),
+        };
+
+        self.do_send(&reply_to, MESSAGE_VALUE, Some(LightWallet::memo_with_reply_to(text, &our_address)), None)
+    }
+
+    /// Write the mined transaction history to `path` as "csv" or "json", with one row for every
+    /// entry in `do_list_transactions`, and the wallet's balance after each of them
+    pub fn do_export_history(&self, path: &str, format: &str) -> String {
+        let txs = self.do_list_transactions();
+
+        let mut balance: i64 = 0;
+        let rows = txs.members()
+            .filter(|tx| !tx["block_height"].is_null())     // Not mined yet
+            .map(|tx| {
+                let amount = tx["amount"].as_i64().unwrap_or(0);
+                balance += amount;
+
+                let (address, memo) = if tx["direction"] == "outgoing" {
+                    (
+                        tx["outgoing_metadata"].members().filter_map(|om| om["address"].as_str()).collect::<Vec<_>>().join(","),
+                        tx["outgoing_metadata"].members().filter_map(|om| om["memo"].as_str()).collect::<Vec<_>>().join(","),
+                    )
+                } else {
+                    (tx["address"].as_str().unwrap_or("").to_string(), tx["memo"].as_str().unwrap_or("").to_string())
+                };
+
+                object!{
+                    "txid"         => tx["txid"].clone(),
+                    "block_height" => tx["block_height"].clone(),
+                    "datetime"     => tx["datetime"].clone(),
+                    "date"         => tx["date"].clone(),
+                    "direction"    => tx["direction"].clone(),
+                    "address"      => address,
+                    "amount"       => amount,
+                    "fee"          => tx["fee"].clone(),
+                    "memo"         => memo,
+                    "annotation"   => tx["annotation"].clone(),
+                    "tags"         => tx["tags"].members().filter_map(|t| t.as_str()).collect::<Vec<_>>().join(","),
+                    "balance"      => balance,
+                }
+            })
+            .collect::<Vec<JsonValue>>();
+
+        let res = match format {
+            "json" => File::create(path).and_then(|mut f| f.write_all(JsonValue::Array(rows.clone()).pretty(2).as_bytes())),
+            "csv"  => write_history_csv(path, &rows),
+            _      => return format!("Unknown format {}, use csv or json", format),
+        };
+
+        match res {
+            Ok(_)  => {
+                info!("Exported {} transactions to {}", rows.len(), path);
+                format!("Exported {} transactions to {}", rows.len(), path)
+            },
+            Err(e) => {
+                let e = format!("Couldn't write the history to {}: {}", path, e);
+                error!("{}", e);
+                e
+            }
+        }
+    }
+
+    // Sent transactions that haven't been mined yet, who they pay, and how many blocks are left before
+    // they expire
+    fn pending_sent_txs(&self) -> Vec<JsonValue> {
+        let sent_txs = self.wallet.sent_txs();
+        let last_height = self.wallet.last_scanned_height();
+        let txs = self.wallet.txs.read().unwrap();
+
+        sent_txs.iter().map(|sent| {
+            // Value of our notes and utxos that this tx spends. The change isn't known until it is mined.
+            let spent = txs.values().map(|wtx| {
+                wtx.notes.iter().filter(|nd| nd.unconfirmed_spent == Some(sent.txid)).map(|nd| nd.note.value).sum::<u64>()
+                + wtx.utxos.iter().filter(|u| u.unconfirmed_spent == Some(sent.txid)).map(|u| u.value).sum::<u64>()
+            }).sum::<u64>();
+
+            // The recipients and the change are in the raw tx, so the amount is the same as once it's mined
+            let (outgoing, to_self) = self.wallet.sent_tx_outputs(&sent.raw);
+            let outgoing_json = outgoing.iter()
+                .map(|om|
+                    object!{
+                        "address" => om.address.clone(),
+                        "value"   => om.value,
+                        "memo"    => LightWallet::memo_str(&Some(om.memo.clone())),
+                        "memo_hex"=> LightWallet::memo_hex(&Some(om.memo.clone())),
+                })
+                .collect::<Vec<JsonValue>>();
+
+            object!{
+                "block_height"        => None::<i32>,
+                "unconfirmed"         => true,
+                "direction"           => "outgoing",
+                "txid"                => format!("{}", sent.txid),
+                "amount"              => to_self as i64 - spent as i64,
+                "outgoing_metadata"   => outgoing_json,
+                "spent_value"         => spent,
+                "expiry_height"       => sent.expiry_height,
+                "blocks_until_expiry" => sent.expiry_height as i64 - last_height as i64,
+            }
+        }).collect()
+    }
+
+    pub fn do_pending(&self) -> JsonValue {
+        JsonValue::Array(self.pending_sent_txs())
+    }
+
+    pub fn do_get_options(&self) -> JsonValue {
+        object!{
+            "expiry_delta"  => self.wallet.get_expiry_delta(),
+            "change_policy" => match self.wallet.get_change_policy() {
+                ChangePolicy::SameAsInput      => "same_as_input".to_string(),
+                ChangePolicy::FreshDiversified => "fresh_diversified".to_string(),
+                ChangePolicy::Fixed(address)   => address,
+            },
+        }
+    }
+
+    pub fn do_set_option(&self, name: &str, value: &str) -> JsonValue {
+        let res = match name {
+            "expiry_delta" => value.parse::<u32>()
+                                .map_err(|e| format!("Couldn't parse expiry delta: {}", e))
+                                .and_then(|delta| self.wallet.set_expiry_delta(delta)),
+            "change_policy" => self.wallet.set_change_policy(match value {
+                                    "same_as_input"     => ChangePolicy::SameAsInput,
+                                    "fresh_diversified" => ChangePolicy::FreshDiversified,
+                                    address             => ChangePolicy::Fixed(address.to_string()),
+                                }),
+            _ => Err(format!("Unknown option {}", name)),
+        };
+
+        match res {
+            Ok(_)  => self.do_get_options(),
+            Err(e) => object!{ "error" => e }
+        }
+    }
+
+    fn annotation_json(&self, txid: &TxId) -> JsonValue {
+        let annotation = self.wallet.get_annotation(txid).unwrap_or_default();
+        object!{
+            "txid"       => format!("{}", txid),
+            "annotation" => annotation.text,
+            "tags"       => annotation.tags,
+        }
+    }
+
+    /// Set the note for a transaction. An empty text removes it.
+    pub fn do_annotate(&self, txid: &str, text: &str) -> JsonValue {
+        match LightWallet::parse_txid(txid).and_then(|txid| self.wallet.set_annotation(&txid, text).map(|_| txid)) {
+            Ok(txid) => self.annotation_json(&txid),
+            Err(e)   => object!{ "error" => e }
+        }
+    }
+
+    /// Add a tag to a transaction, or remove it if `remove` is set
+    pub fn do_tag(&self, txid: &str, tag: &str, remove: bool) -> JsonValue {
+        let res = LightWallet::parse_txid(txid).and_then(|txid| {
+            let res = if remove {
+                self.wallet.remove_tag(&txid, tag)
+            } else {
+                self.wallet.add_tag(&txid, tag)
+            };
+            res.map(|_| txid)
+        });
+
+        match res {
+            Ok(txid) => self.annotation_json(&txid),
+            Err(e)   => object!{ "error" => e }
+        }
+    }
+
+    /// All the labelled addresses, and whether they are ours
+    pub fn do_address_book(&self) -> JsonValue {
+        self.wallet.get_address_book().into_iter().map(|(address, label)| object!{
+            "label"   => label,
+            "is_mine" => self.wallet.is_wallet_address(&address),
+            "address" => address,
+        }).collect::<Vec<JsonValue>>().into()
+    }
+
+    pub fn do_set_label(&self, address: &str, label: &str) -> JsonValue {
+        match self.wallet.set_label(address, label) {
+            Ok(_)  => self.do_address_book(),
+            Err(e) => object!{ "error" => e }
+        }
+    }
+
+    /// Remove an address from the address book, by its address or its label
+    pub fn do_remove_label(&self, address_or_label: &str) -> JsonValue {
+        match self.wallet.remove_label(address_or_label) {
+            Ok(_)  => self.do_address_book(),
+            Err(e) => object!{ "error" => e }
+        }
     }
 
     /// Create a new address, deriving it from the seed.
@@ -564,7 +1396,7 @@ impl LightClient {
         // This will hold the latest block fetched from the RPC
         let latest_block_height = Arc::new(AtomicU64::new(0));
         let lbh = latest_block_height.clone();
-        fetch_latest_block(&self.get_server_uri(), self.config.no_cert_verification, move |block: BlockId| {
+        fetch_latest_block(&self.get_server_uri(), &self.config.tls_options, move |block: BlockId| {
                 lbh.store(block.height, Ordering::SeqCst);
             });
         let latest_block = latest_block_height.load(Ordering::SeqCst);
@@ -614,9 +1446,13 @@ impl LightClient {
             info!("Fetching blocks {}-{}", start_height, end_height);
             let all_txs = all_new_txs.clone();
 
+            // Timestamps of the blocks in this batch, for the transparent txs below
+            let block_times = Arc::new(RwLock::new(HashMap::new()));
+            let block_times_inner = block_times.clone();
+
             let last_invalid_height = Arc::new(AtomicI32::new(0));
             let last_invalid_height_inner = last_invalid_height.clone();
-            fetch_blocks(&self.get_server_uri(), start_height, end_height, self.config.no_cert_verification,
+            fetch_blocks(&self.get_server_uri(), start_height, end_height, &self.config.tls_options,
                 move |encoded_block: &[u8], height: u64| {
                     // Process the block only if there were no previous errors
                     if last_invalid_height_inner.load(Ordering::SeqCst) > 0 {
@@ -626,6 +1462,9 @@ impl LightClient {
                     match local_light_wallet.scan_block(encoded_block) {
                         Ok(block_txns) => {
                             all_txs.write().unwrap().extend_from_slice(&block_txns.iter().map(|txid| (txid.clone(), height as i32)).collect::<Vec<_>>()[..]);
+                            if let Some(time) = local_light_wallet.get_block_time(height as i32) {
+                                block_times_inner.write().unwrap().insert(height, time as u64);
+                            }
                         },
                         Err(invalid_height) => {
                             // Block at this height seems to be invalid, so invalidate up till that point
@@ -668,12 +1507,13 @@ impl LightClient {
             // TODO: Use for all t addresses
             let address = self.wallet.address_from_sk(&self.wallet.tkeys.read().unwrap()[0]);
             let wallet = self.wallet.clone();
-            fetch_transparent_txids(&self.get_server_uri(), address, start_height, end_height, self.config.no_cert_verification,
+            fetch_transparent_txids(&self.get_server_uri(), address, start_height, end_height, &self.config.tls_options,
                 move |tx_bytes: &[u8], height: u64 | {
                     let tx = Transaction::read(tx_bytes).unwrap();
+                    let datetime = block_times.read().unwrap().get(&height).cloned().unwrap_or(0);
 
                     // Scan this Tx for transparent inputs and outputs
-                    wallet.scan_full_tx(&tx, height as i32); 
+                    wallet.scan_full_tx(&tx, height as i32, datetime); 
                 }
             );
             
@@ -719,30 +1559,720 @@ impl LightClient {
             let light_wallet_clone = self.wallet.clone();
             info!("Fetching full Tx: {}", txid);
 
-            fetch_full_tx(&self.get_server_uri(), txid, self.config.no_cert_verification, move |tx_bytes: &[u8] | {
+            fetch_full_tx(&self.get_server_uri(), txid, &self.config.tls_options, move |tx_bytes: &[u8] | {
                 let tx = Transaction::read(tx_bytes).unwrap();
 
-                light_wallet_clone.scan_full_tx(&tx, height);
+                // The wallet already has the time from the block scan
+                light_wallet_clone.scan_full_tx(&tx, height, 0);
             });
         };
 
+        // Send the transactions that still haven't been mined again, in case the server lost them
+        for sent in self.wallet.update_sent_txs() {
+            match broadcast_raw_tx(&self.get_server_uri(), &self.config.tls_options, sent.raw.into_boxed_slice()) {
+                Ok(_)  => info!("Rebroadcast pending tx {}", sent.txid),
+                Err(e) => warn!("Couldn't rebroadcast pending tx {}: {}", sent.txid, e),
+            }
+        }
+
         responses.join("\n")
     }
 
-    pub fn do_send(&self, addr: &str, value: u64, memo: Option<String>) -> String {
+    /// Broadcast the pending sent transaction `txid` again, or all of them if `txid` is None
+    pub fn do_rebroadcast(&self, txid: Option<&str>) -> JsonValue {
+        let pending = self.wallet.sent_txs().into_iter()
+            .filter(|sent| txid.map_or(true, |t| format!("{}", sent.txid) == t))
+            .collect::<Vec<_>>();
+
+        if pending.is_empty() {
+            return match txid {
+                Some(t) => object!{ "error" => format!("No pending transaction {}", t) },
+                None    => JsonValue::new_array(),
+            };
+        }
+
+        pending.into_iter().map(|sent| {
+            let mut res = object!{
+                "txid"              => format!("{}", sent.txid),
+                "expiry_height"     => sent.expiry_height,
+            };
+
+            match broadcast_raw_tx(&self.get_server_uri(), &self.config.tls_options, sent.raw.into_boxed_slice()) {
+                Ok(k)  => res["result"] = k.into(),
+                Err(e) => res["error"] = e.into(),
+            };
+
+            res
+        }).collect::<Vec<JsonValue>>().into()
+    }
+
+    /// Send `value` to `addr`. `expiry_delta` overrides the wallet's expiry delta for this send.
+    pub fn do_send(&self, addr: &str, value: u64, memo: Option<String>, expiry_delta: Option<u32>) -> String {
         info!("Creating transaction");
-        let rawtx = self.wallet.send_to_address(
-            u32::from_str_radix(&self.config.consensus_branch_id, 16).unwrap(),   // Blossom ID
-            &self.sapling_spend, &self.sapling_output,
-            vec![(&addr, value, memo)]
-        );
+        let addr = self.wallet.resolve_address(addr);
+        self.send_new_proposal(self.wallet.create_proposal(vec![(&addr, value, memo)]), expiry_delta)
+    }
+
+    /// Send all the spendable funds, or all the spendable funds of the address `from`, to `addr`
+    pub fn do_send_all(&self, addr: &str, memo: Option<String>, from: Option<String>, expiry_delta: Option<u32>) -> String {
+        info!("Creating transaction sending all funds");
+        let addr = self.wallet.resolve_address(addr);
+        let from = from.map(|f| self.wallet.resolve_address(&f));
+        self.send_new_proposal(self.wallet.create_sweep_proposal(&addr, memo, from), expiry_delta)
+    }
+
+    fn send_new_proposal(&self, proposal: Result<TxProposal, String>, expiry_delta: Option<u32>) -> String {
+        let rawtx = proposal.and_then(|mut proposal| {
+            set_expiry_delta(&mut proposal, expiry_delta)?;
+            self.wallet.send_proposal(&self.get_prover()?, &proposal)
+        });
         
         match rawtx {
-            Ok(txbytes)   => match broadcast_raw_tx(&self.get_server_uri(), self.config.no_cert_verification, txbytes) {
+            Ok(txbytes)   => match broadcast_raw_tx(&self.get_server_uri(), &self.config.tls_options, txbytes) {
+                Ok(k)  => k,
+                Err(e) => e,
+            },
+            Err(e)        => format!("No Tx to broadcast. Error was: {}", e)
+        }
+    }
+
+    /// Pay all the rows of an `address,amount,memo` CSV file, in as few transactions as possible.
+    /// The result of every row is written to `report_path`, or `<path>.report.csv`, and rows that
+    /// the report already has a transaction for are skipped, so a batch can be safely run again.
+    /// A row only counts as paid while its transaction is pending or mined. If it expired, the
+    /// row is paid again.
+    pub fn do_send_batch(&self, path: &str, report_path: Option<&str>) -> JsonValue {
+        let report_path = report_path.map(|r| r.to_string()).unwrap_or(format!("{}.report.csv", path));
+
+        let rows = match read_batch_file(path) {
+            Ok(r)  => r,
+            Err(e) => {
+                let e = format!("Couldn't read {}: {}", path, e);
+                error!("{}", e);
+                return object!{ "error" => e };
+            }
+        };
+
+        // Make sure every row is valid before sending anything
+        let errors = rows.iter().filter_map(|row| {
+            self.wallet.proposal_outputs(&[(row.address.as_str(), row.amount, row.memo.clone())])
+                .err()
+                .map(|e| format!("Line {}: {}", row.line, e))
+        }).collect::<Vec<String>>();
+        if !errors.is_empty() {
+            return object!{ "error" => errors.join("\n") };
+        }
+
+        // Rows that were paid by an earlier run. The same payment can be in the file more than once,
+        // so count them instead of matching line numbers, which change if the file is edited.
+        let live_txids = self.wallet.txs.read().unwrap().keys().map(|txid| format!("{}", txid))
+            .chain(self.wallet.sent_txs().iter().map(|sent| format!("{}", sent.txid)))
+            .collect::<HashSet<String>>();
+
+        let mut paid: HashMap<(String, u64, String), Vec<(String, String)>> = HashMap::new();
+        if Path::new(&report_path).exists() {
+            match read_batch_report(&report_path) {
+                Ok(report) => for r in report.into_iter().filter(|r| live_txids.contains(&r.txid)) {
+                    paid.entry((r.address, r.amount, r.memo)).or_default().push((r.txid, r.result));
+                },
+                Err(e) => {
+                    let e = format!("Couldn't read the report {}: {}", report_path, e);
+                    error!("{}", e);
+                    return object!{ "error" => e };
+                }
+            }
+        }
+
+        let mut results = rows.iter().map(|row| {
+            let key = (row.address.clone(), row.amount, row.memo.clone().unwrap_or_default());
+            match paid.get_mut(&key).filter(|p| !p.is_empty()) {
+                Some(p) => Some(p.remove(0)),
+                None    => None,
+            }
+        }).collect::<Vec<Option<(String, String)>>>();
+
+        let already_paid = results.iter().filter(|r| r.is_some()).count();
+        let mut pending = (0..rows.len()).filter(|i| results[*i].is_none()).collect::<Vec<usize>>();
+
+        let prover = match self.get_prover() {
+            Ok(p)  => p,
+            Err(e) => return object!{ "error" => e }
+        };
+        let mut txids = vec![];
+        let mut error = None;
+
+        while !pending.is_empty() {
+            // Find the most rows that fit in one transaction. More rows need more funds and more space,
+            // so if some rows don't fit, more rows won't either.
+            let (mut lo, mut hi) = (0, pending.len());
+            let mut proposal = None;
+            let mut last_error = String::new();
+            while lo < hi {
+                let mid = (lo + hi + 1) / 2;
+                let tos = pending[..mid].iter()
+                            .map(|i| (rows[*i].address.as_str(), rows[*i].amount, rows[*i].memo.clone()))
+                            .collect::<Vec<_>>();
+
+                match self.wallet.create_proposal(tos) {
+                    Ok(p) if self.wallet.estimated_tx_size(&p) <= MAX_TX_SIZE => {
+                        lo = mid;
+                        proposal = Some(p);
+                    },
+                    Ok(_) => {
+                        hi = mid - 1;
+                        last_error = format!("The transaction would be bigger than {} bytes", MAX_TX_SIZE);
+                    },
+                    Err(e) => {
+                        hi = mid - 1;
+                        last_error = e;
+                    }
+                }
+            }
+
+            let proposal = match proposal {
+                Some(p) => p,
+                None    => {
+                    error = Some(last_error);
+                    break;
+                }
+            };
+
+            let txbytes = match self.wallet.send_proposal(&prover, &proposal) {
+                Ok(b)  => b,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            };
+            let txid = format!("{}", Transaction::read(&txbytes[..]).unwrap().txid());
+
+            // Even if the broadcast fails, the transaction is kept and rebroadcast, so the rows
+            // have to be recorded as paid
+            let result = match broadcast_raw_tx(&self.get_server_uri(), &self.config.tls_options, txbytes) {
+                Ok(_)  => "sent".to_string(),
+                Err(e) => format!("broadcast failed, will be retried: {}", e),
+            };
+            info!("Sent batch transaction {} paying {} rows: {}", txid, lo, result);
+
+            for i in pending.drain(..lo) {
+                results[i] = Some((txid.clone(), result.clone()));
+            }
+            txids.push(txid);
+
+            // Write the report after every transaction, so nothing is paid twice if we're interrupted
+            if let Err(e) = write_batch_report(&report_path, &rows, &results, &error) {
+                error!("Couldn't write the report {}: {}", report_path, e);
+            }
+        }
+
+        if let Err(e) = write_batch_report(&report_path, &rows, &results, &error) {
+            let e = format!("Couldn't write the report {}: {}", report_path, e);
+            error!("{}", e);
+            return object!{ "error" => e, "txids" => txids };
+        }
+
+        let mut res = object!{
+            "txids"        => txids,
+            "paid"         => rows.len() - already_paid - pending.len(),
+            "already_paid" => already_paid,
+            "unpaid"       => pending.len(),
+            "report"       => report_path,
+        };
+        if let Some(e) = error {
+            res["error"] = e.into();
+        }
+
+        res
+    }
+
+    /// Merge the smallest spendable notes into one note at `address`, `max_inputs` notes per transaction,
+    /// until there is at most one spendable note left.
+    pub fn do_consolidate(&self, max_inputs: Option<usize>, address: Option<String>) -> JsonValue {
+        let max_inputs = max_inputs.unwrap_or(DEFAULT_CONSOLIDATION_INPUTS);
+        let prover = match self.get_prover() {
+            Ok(p)  => p,
+            Err(e) => return object!{ "error" => e }
+        };
+
+        let mut txids = vec![];
+        let mut notes = 0;
+        let mut fees = 0;
+        let mut error = None;
+
+        loop {
+            let proposal = match self.wallet.create_consolidation_proposal(max_inputs, address.clone()) {
+                Ok(Some(p)) => p,
+                Ok(None)    => break,
+                Err(e)      => {
+                    error = Some(e);
+                    break;
+                }
+            };
+
+            if self.wallet.estimated_tx_size(&proposal) > MAX_TX_SIZE {
+                error = Some(format!("Merging {} notes would make the transaction bigger than {} bytes. Use a smaller --max-inputs",
+                                     proposal.notes.len(), MAX_TX_SIZE));
+                break;
+            }
+
+            let txbytes = match self.wallet.send_proposal(&prover, &proposal) {
+                Ok(b)  => b,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            };
+            let txid = format!("{}", Transaction::read(&txbytes[..]).unwrap().txid());
+
+            // A failed broadcast is retried at the next sync, so keep going
+            if let Err(e) = broadcast_raw_tx(&self.get_server_uri(), &self.config.tls_options, txbytes) {
+                warn!("Couldn't broadcast consolidation transaction {}, it will be retried: {}", txid, e);
+            }
+            info!("Merged {} notes in {}", proposal.notes.len(), txid);
+
+            notes += proposal.notes.len();
+            fees += proposal.fee;
+            txids.push(txid);
+        }
+
+        let mut res = object!{
+            "txids"        => txids,
+            "merged_notes" => notes,
+            "fees"         => fees,
+        };
+        if let Some(e) = error {
+            res["error"] = e.into();
+        }
+
+        res
+    }
+
+    /// Pay all the payments in a ZIP-321 payment request URI in one transaction. `expiry_delta`
+    /// overrides the wallet's expiry delta, like for `do_send`.
+    pub fn do_pay(&self, uri: &str, expiry_delta: Option<u32>) -> String {
+        let payments = match zip321::parse_uri(uri, &self.config) {
+            Ok(p)  => p,
+            Err(e) => return format!("Couldn't parse payment request: {}", e)
+        };
+
+        if let Some(p) = payments.iter().find(|p| p.amount.is_none()) {
+            return format!("The payment request doesn't have an amount for {}", p.address);
+        }
+
+        // Memos are passed on as hex, so binary memos in the request are sent as-is
+        let tos = payments.iter().map(|p| {
+            let memo = p.memo.as_ref()
+                        .filter(|m| !m.is_empty())
+                        .map(|m| format!("0x{}", hex::encode(m)));
+            (p.address.as_str(), p.amount.unwrap(), memo)
+        }).collect::<Vec<_>>();
+
+        info!("Creating transaction for payment request");
+        self.send_new_proposal(self.wallet.create_proposal(tos), expiry_delta)
+    }
+
+    /// Create a ZIP-321 payment request URI asking for `value` to be paid to our first z address
+    pub fn do_payment_request(&self, value: u64, memo: Option<String>) -> JsonValue {
+        let address = encode_payment_address(self.config.hrp_sapling_address(),
+                            &self.wallet.address.read().unwrap()[0]);
+
+        let mut payment = zip321::Payment::new(address.clone());
+        payment.amount = Some(value);
+
+        if let Some(m) = memo {
+            match LightWallet::parse_memo(&m) {
+                Ok(memo) => {
+                    // Memos are padded with zeros, which don't need to be in the URI
+                    let bytes = memo.as_bytes();
+                    let len = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+                    payment.memo = Some(bytes[..len].to_vec());
+                },
+                Err(e) => return object!{ "error" => e }
+            }
+        }
+
+        object!{
+            "address" => address,
+            "uri"     => zip321::create_uri(&[payment]),
+        }
+    }
+
+    /// Create a transaction proposal without building or proving it. The proposal is kept
+    /// around, so it can be sent later with `do_confirm_send`
+    pub fn do_send_preview(&self, addr: &str, value: u64, memo: Option<String>, expiry_delta: Option<u32>) -> JsonValue {
+        let addr = self.wallet.resolve_address(addr);
+        self.preview_proposal(self.wallet.create_proposal(vec![(&addr, value, memo)]), expiry_delta)
+    }
+
+    /// Same as `do_send_preview`, but for sending all the spendable funds like `do_send_all`
+    pub fn do_send_all_preview(&self, addr: &str, memo: Option<String>, from: Option<String>, expiry_delta: Option<u32>) -> JsonValue {
+        let addr = self.wallet.resolve_address(addr);
+        let from = from.map(|f| self.wallet.resolve_address(&f));
+        self.preview_proposal(self.wallet.create_sweep_proposal(&addr, memo, from), expiry_delta)
+    }
+
+    fn preview_proposal(&self, proposal: Result<TxProposal, String>, expiry_delta: Option<u32>) -> JsonValue {
+        let mut proposal = match proposal {
+            Ok(p)  => p,
+            Err(e) => return object!{ "error" => e }
+        };
+
+        if let Err(e) = set_expiry_delta(&mut proposal, expiry_delta) {
+            return object!{ "error" => e };
+        }
+
+        let change = match proposal.change() {
+            Ok(c)  => c,
+            Err(e) => return object!{ "error" => e }
+        };
+
+        let notes = proposal.notes.iter().map(|selected| {
+            let address = selected.extfvk.fvk.vk
+                            .into_payment_address(selected.diversifier, &JUBJUB)
+                            .map(|pa| encode_payment_address(self.config.hrp_sapling_address(), &pa));
+            object!{
+                "created_in_txid" => format!("{}", selected.txid),
+                "value"           => selected.note.value,
+                "address"         => address,
+            }
+        }).collect::<Vec<JsonValue>>();
+
+        let utxos = proposal.utxos.iter().map(|utxo| {
+            object!{
+                "created_in_txid" => format!("{}", utxo.txid),
+                "output_index"    => utxo.output_index,
+                "value"           => utxo.value,
+                "address"         => utxo.address.clone(),
+            }
+        }).collect::<Vec<JsonValue>>();
+
+        let outputs = proposal.outputs.iter().map(|out| {
+            object!{
+                "address" => out.address.clone(),
+                "value"   => out.value,
+                "memo"    => LightWallet::memo_str(&out.memo),
+                "memo_hex"=> LightWallet::memo_hex(&out.memo),
+            }
+        }).collect::<Vec<JsonValue>>();
+
+        let res = object!{
+            "target_height"  => proposal.target_height,
+            "expiry_height"  => proposal.expiry_height,
+            "total"          => proposal.total_value(),
+            "fee"            => proposal.fee,
+            "selected_value" => proposal.selected_value(),
+            "change"         => change,
+            "notes"          => notes,
+            "utxos"          => utxos,
+            "outputs"        => outputs,
+        };
+
+        *self.proposal.write().unwrap() = Some(proposal);
+
+        res
+    }
+
+    /// Build, prove and broadcast the transaction from the last `do_send_preview`
+    pub fn do_confirm_send(&self) -> String {
+        let proposal = match self.proposal.write().unwrap().take() {
+            Some(p) => p,
+            None    => return format!("No transaction proposal to send. Create one with 'send --dry-run' first")
+        };
+
+        info!("Creating transaction from proposal");
+        let rawtx = self.get_prover().and_then(|prover| self.wallet.send_proposal(&prover, &proposal));
+
+        match rawtx {
+            Ok(txbytes)   => match broadcast_raw_tx(&self.get_server_uri(), &self.config.tls_options, txbytes) {
                 Ok(k)  => k,
                 Err(e) => e,
             },
             Err(e)        => format!("No Tx to broadcast. Error was: {}", e)
         }
     }
+
+    /// Write the proposal from the last `do_send_preview` to a file, so it can be signed by
+    /// a wallet that has the spending keys with `do_sign_proposal`. The file is hex encoded.
+    pub fn do_export_proposal(&self, path: &str) -> String {
+        let mut proposal = self.proposal.write().unwrap();
+        if proposal.is_none() {
+            return format!("No transaction proposal to export. Create one with 'send --dry-run' first");
+        }
+
+        let mut bytes = vec![];
+        proposal.as_ref().unwrap().write(&mut bytes).unwrap();
+
+        if let Err(e) = File::create(path).and_then(|mut f| f.write_all(hex::encode(bytes).as_bytes())) {
+            let e = format!("Couldn't write the proposal to {}: {}", path, e);
+            error!("{}", e);
+            return e;
+        }
+
+        // The exported proposal will be sent with `do_send_signed`, so it can't be confirmed here too
+        *proposal = None;
+        info!("Exported transaction proposal to {}", path);
+
+        format!("Exported transaction proposal to {}", path)
+    }
+
+    /// Build, prove and sign the transaction proposal in `in_path`, and write the raw transaction
+    /// to `out_path`. This doesn't need a connection to the server, and doesn't change the wallet.
+    pub fn do_sign_proposal(&self, in_path: &str, out_path: &str) -> String {
+        let proposal = match read_hex_file(in_path)
+                .and_then(|bytes| TxProposal::read(&bytes[..])) {
+            Ok(p)  => p,
+            Err(e) => {
+                let e = format!("Couldn't read the proposal from {}: {}", in_path, e);
+                error!("{}", e);
+                return e;
+            }
+        };
+
+        info!("Signing transaction proposal from {}", in_path);
+        let txbytes = match self.get_prover().and_then(|prover| self.wallet.sign_proposal(&prover, &proposal)) {
+            Ok(b)  => b,
+            Err(e) => return format!("Couldn't sign the proposal. Error was: {}", e)
+        };
+
+        if let Err(e) = File::create(out_path).and_then(|mut f| f.write_all(hex::encode(txbytes).as_bytes())) {
+            let e = format!("Couldn't write the signed transaction to {}: {}", out_path, e);
+            error!("{}", e);
+            return e;
+        }
+
+        format!("Wrote signed transaction to {}", out_path)
+    }
+
+    /// Broadcast a signed transaction from `do_sign_proposal`. It is kept like the wallet's
+    /// own sends, and the notes and utxos it spends are marked as pending.
+    pub fn do_send_signed(&self, path: &str) -> String {
+        let txbytes = match read_hex_file(path) {
+            Ok(b)  => b,
+            Err(e) => {
+                let e = format!("Couldn't read the signed transaction from {}: {}", path, e);
+                error!("{}", e);
+                return e;
+            }
+        };
+
+        // Keep the transaction, so it can be rebroadcast if this fails
+        if let Err(e) = self.wallet.add_sent_tx(&txbytes) {
+            return format!("{} doesn't contain a valid transaction: {}", path, e);
+        }
+
+        match broadcast_raw_tx(&self.get_server_uri(), &self.config.tls_options, txbytes.into_boxed_slice()) {
+            Ok(k)  => k,
+            Err(e) => e,
+        }
+    }
+}
+
+// A payment in a batch file
+struct BatchRow {
+    line   : u64,
+    address: String,
+    amount : u64,
+    memo   : Option<String>,
+}
+
+// A row of a batch report, from an earlier run
+struct BatchReportRow {
+    address: String,
+    amount : u64,
+    memo   : String,
+    txid   : String,
+    result : String,
+}
+
+fn read_batch_file(path: &str) -> io::Result<Vec<BatchRow>> {
+    let mut reader = csv::ReaderBuilder::new()
+                        .has_headers(false)
+                        .flexible(true)
+                        .trim(csv::Trim::All)
+                        .from_path(path)?;
+
+    let mut rows = vec![];
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line());
+
+        // Allow a header row, and skip empty lines
+        if (rows.is_empty() && record.get(0) == Some("address")) || record.iter().all(|f| f.is_empty()) {
+            continue;
+        }
+
+        let address = record.get(0).unwrap_or("").to_string();
+        let amount = record.get(1).unwrap_or("").parse::<u64>().map_err(|e|
+            Error::new(ErrorKind::InvalidData, format!("Line {}: Couldn't parse the amount: {}", line, e)))?;
+        let memo = record.get(2).filter(|m| !m.is_empty()).map(|m| m.to_string());
+
+        rows.push(BatchRow { line, address, amount, memo });
+    }
+
+    Ok(rows)
+}
+
+fn read_batch_report(path: &str) -> io::Result<Vec<BatchReportRow>> {
+    let mut reader = csv::Reader::from_path(path)?;
+
+    reader.records().map(|record| {
+        let record = record?;
+        let field = |i| record.get(i).unwrap_or("").to_string();
+
+        Ok(BatchReportRow {
+            address: field(1),
+            amount : field(2).parse::<u64>().map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?,
+            memo   : field(3),
+            txid   : field(4),
+            result : field(5),
+        })
+    }).collect()
+}
+
+fn write_batch_report(path: &str, rows: &[BatchRow], results: &[Option<(String, String)>],
+                      error: &Option<String>) -> io::Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(&["line", "address", "amount", "memo", "txid", "result"])?;
+
+    for (row, result) in rows.iter().zip(results.iter()) {
+        let (txid, result) = match result {
+            Some((txid, result)) => (txid.clone(), result.clone()),
+            None => ("".to_string(), format!("not sent{}", error.as_ref().map_or("".to_string(), |e| format!(": {}", e)))),
+        };
+
+        writer.write_record(&[row.line.to_string(), row.address.clone(), row.amount.to_string(),
+                              row.memo.clone().unwrap_or_default(), txid, result])?;
+    }
+
+    writer.flush()
+}
+
+fn write_history_csv(path: &str, rows: &[JsonValue]) -> io::Result<()> {
+    const COLUMNS: [&str; 12] = ["txid", "block_height", "datetime", "date", "direction", "address", "amount", "fee", "memo",
+                                 "annotation", "tags", "balance"];
+
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(&COLUMNS)?;
+
+    for row in rows {
+        // Nulls are empty cells
+        writer.write_record(COLUMNS.iter().map(|c| if row[*c].is_null() { "".to_string() } else { row[*c].to_string() }))?;
+    }
+
+    writer.flush()
+}
+
+fn read_hex_file(path: &str) -> io::Result<Vec<u8>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+
+    hex::decode(contents.trim()).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+}
+
+/// Make the proposal expire `expiry_delta` blocks after its target height, if it's given
+fn set_expiry_delta(proposal: &mut TxProposal, expiry_delta: Option<u32>) -> Result<(), String> {
+    if let Some(delta) = expiry_delta {
+        proposal.expiry_height = match proposal.target_height.checked_add(delta) {
+            Some(h) => h,
+            None    => {
+                let e = format!("Expiry delta {} is too large", delta);
+                error!("{}", e);
+                return Err(e);
+            }
+        };
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::sync::{Arc, Mutex, RwLock};
+
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    use super::{LightClient, SaplingProver};
+    use crate::lightwallet::LightWallet;
+    use crate::lightwallet::tests::get_test_config;
+
+    fn get_test_client() -> LightClient {
+        let config = get_test_config();
+
+        LightClient {
+            wallet          : Arc::new(LightWallet::new(None, &config, 0).unwrap()),
+            config          : config.clone(),
+            prover          : Arc::new(Mutex::new(SaplingProver::NotLoaded)),
+            proposal        : Arc::new(RwLock::new(None)),
+            full_txs        : Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    #[test]
+    fn test_sign_bad_proposals() {
+        let lc = get_test_client();
+
+        let dir = std::env::temp_dir();
+        let in_path = dir.join("zecwallet-test-bad-proposal.hex");
+        let out_path = dir.join("zecwallet-test-bad-proposal.signed");
+        let (in_path, out_path) = (in_path.to_str().unwrap(), out_path.to_str().unwrap());
+
+        // A version 1 proposal header: version, target height, expiry height, branch id,
+        // no anchor and no notes
+        let mut header = vec![];
+        header.write_u64::<LittleEndian>(1).unwrap();
+        header.write_u32::<LittleEndian>(100).unwrap();
+        header.write_u32::<LittleEndian>(120).unwrap();
+        header.write_u32::<LittleEndian>(0x2bb40e60).unwrap();
+        header.push(0);
+        header.push(0);
+
+        // Truncated
+        let truncated = header[..12].to_vec();
+
+        // A utxo with an unknown version, and one with a negative address length
+        let mut bad_utxo_version = header.clone();
+        bad_utxo_version.push(1);
+        bad_utxo_version.write_u64::<LittleEndian>(99).unwrap();
+
+        let mut bad_address_len = header.clone();
+        bad_address_len.push(1);
+        bad_address_len.write_u64::<LittleEndian>(2).unwrap();
+        bad_address_len.write_i32::<LittleEndian>(-1).unwrap();
+
+        // Huge numbers of utxos, and an output with a huge address
+        let mut many_utxos = header.clone();
+        many_utxos.push(0xfe);
+        many_utxos.write_u32::<LittleEndian>(0x01ff_ffff).unwrap();
+
+        let mut long_address = header.clone();
+        long_address.push(0);
+        long_address.push(1);
+        long_address.write_u64::<LittleEndian>(u64::max_value()).unwrap();
+
+        let bad_proposals = vec![
+            hex::encode(vec![0xff; 64]),
+            hex::encode(truncated),
+            hex::encode(bad_utxo_version),
+            hex::encode(bad_address_len),
+            hex::encode(many_utxos),
+            hex::encode(long_address),
+            "".to_string(),
+            "not a proposal".to_string(),
+        ];
+
+        for bad in bad_proposals {
+            fs::write(in_path, &bad).unwrap();
+            let res = lc.do_sign_proposal(in_path, out_path);
+            assert!(res.starts_with("Couldn't read the proposal"), "{} for {}", res, bad);
+        }
+
+        fs::remove_file(in_path).unwrap();
+        let res = lc.do_sign_proposal(in_path, out_path);
+        assert!(res.starts_with("Couldn't read the proposal"));
+
+        // Nothing was signed
+        assert!(!std::path::Path::new(out_path).exists());
+    }
 }
@@ -34,7 +34,7 @@ use zcash_primitives::{
     },
      legacy::{Script, TransparentAddress},
     note_encryption::{Memo, try_sapling_note_decryption, try_sapling_output_recovery},
-    zip32::{ExtendedFullViewingKey, ExtendedSpendingKey, ChildIndex},
+    zip32::{ExtendedFullViewingKey, ExtendedSpendingKey, ChildIndex, DiversifierIndex},
     JUBJUB,
     primitives::{PaymentAddress},
 };
@@ -46,13 +46,34 @@ mod data;
 mod extended_key;
 mod utils;
 mod address;
-mod prover;
+pub mod prover;
+pub mod zip321;
 
-use data::{BlockData, WalletTx, Utxo, SaplingNoteData, SpendableNote, OutgoingTxMetadata};
+use data::{BlockData, WalletTx, Utxo, SaplingNoteData, SpendableNote, OutgoingTxMetadata, ProposedOutput,
+           WalletOptions, DEFAULT_EXPIRY_DELTA};
+pub use data::{TxProposal, SentTx, ChangePolicy, TxAnnotation, BalanceBreakdown, ReceivedByAddress};
 use extended_key::{KeyIndex, ExtendedPrivKey};
 
 pub const MAX_REORG: usize = 100;
 
+// Transaction proposals that are older than this many blocks have to be recreated before they're sent
+pub const MAX_PROPOSAL_AGE: u32 = 10;
+
+// zcashd won't accept transactions that expire within 3 blocks
+pub const MIN_EXPIRY_DELTA: u32 = 4;
+
+// Expiry heights from here on are reserved by ZIP-203
+const MAX_EXPIRY_HEIGHT: u32 = 499_999_999;
+
+// zcashd won't relay transactions that are bigger than this
+pub const MAX_TX_SIZE: usize = 100_000;
+
+// Default number of notes that are merged in one consolidation transaction
+pub const DEFAULT_CONSOLIDATION_INPUTS: usize = 50;
+
+// Value of the notes that carry a message, like a reply to a memo
+pub const MESSAGE_VALUE: u64 = 1000;
+
 fn now() -> f64 {
     SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as f64
 }
@@ -105,6 +126,22 @@ pub struct LightWallet {
     blocks: Arc<RwLock<Vec<BlockData>>>,
     pub txs: Arc<RwLock<HashMap<TxId, WalletTx>>>,
 
+    // The user's notes and tags for transactions. They're kept separately from txs, so that
+    // they survive a rescan.
+    annotations: Arc<RwLock<HashMap<TxId, TxAnnotation>>>,
+
+    // Raw transactions that we sent, but haven't been mined yet
+    sent_txs: Arc<RwLock<Vec<SentTx>>>,
+
+    // Where the change of the transactions we sent went, txid -> address. Change to a t address
+    // can't be told apart from a payment to ourselves on chain, so it is kept to flag it.
+    change_addresses: Arc<RwLock<HashMap<TxId, String>>>,
+
+    options: Arc<RwLock<WalletOptions>>,
+
+    // Labels for our own addresses and the addresses we pay, address -> label
+    address_book: Arc<RwLock<HashMap<String, String>>>,
+
     // The block at which this wallet was born. Rescans
     // will start from here.
     birthday: u64,
@@ -115,7 +152,7 @@ pub struct LightWallet {
 
 impl LightWallet {
     pub fn serialized_version() -> u64 {
-        return 3;
+        return 8;
     }
 
     fn get_taddr_from_bip39seed(config: &LightClientConfig, bip39_seed: &[u8], pos: u32) -> SecretKey {
@@ -179,6 +216,11 @@ impl LightWallet {
             tkeys:    Arc::new(RwLock::new(vec![tpk])),
             blocks:   Arc::new(RwLock::new(vec![])),
             txs:      Arc::new(RwLock::new(HashMap::new())),
+            annotations: Arc::new(RwLock::new(HashMap::new())),
+            sent_txs: Arc::new(RwLock::new(vec![])),
+            change_addresses: Arc::new(RwLock::new(HashMap::new())),
+            options:  Arc::new(RwLock::new(WalletOptions::new())),
+            address_book: Arc::new(RwLock::new(HashMap::new())),
             config:   config.clone(),
             birthday: latest_block,
         })
@@ -229,7 +271,51 @@ impl LightWallet {
 
         let birthday = reader.read_u64::<LittleEndian>()?;
 
-        Ok(LightWallet{
+        let sent_txs = if version >= 4 {
+            Vector::read(&mut reader, |r| SentTx::read(r))?
+        } else {
+            vec![]
+        };
+
+        let options = if version >= 5 {
+            WalletOptions::read(&mut reader)?
+        } else {
+            WalletOptions::new()
+        };
+
+        let address_book = if version >= 6 {
+            Vector::read(&mut reader, |r| {
+                let address = utils::read_string(&mut *r)?;
+                let label = utils::read_string(&mut *r)?;
+                Ok((address, label))
+            })?.into_iter().collect::<HashMap<String, String>>()
+        } else {
+            HashMap::new()
+        };
+
+        let annotations = if version >= 7 {
+            Vector::read(&mut reader, |r| {
+                let mut txid_bytes = [0u8; 32];
+                r.read_exact(&mut txid_bytes)?;
+
+                Ok((TxId{0: txid_bytes}, TxAnnotation::read(r)?))
+            })?.into_iter().collect::<HashMap<TxId, TxAnnotation>>()
+        } else {
+            HashMap::new()
+        };
+
+        let change_addresses = if version >= 8 {
+            Vector::read(&mut reader, |r| {
+                let mut txid_bytes = [0u8; 32];
+                r.read_exact(&mut txid_bytes)?;
+
+                Ok((TxId{0: txid_bytes}, utils::read_string(r)?))
+            })?.into_iter().collect::<HashMap<TxId, String>>()
+        } else {
+            HashMap::new()
+        };
+
+        let wallet = LightWallet{
             seed:    seed_bytes,
             extsks:  Arc::new(RwLock::new(extsks)),
             extfvks: Arc::new(RwLock::new(extfvks)),
@@ -237,9 +323,24 @@ impl LightWallet {
             tkeys:   Arc::new(RwLock::new(tkeys)),
             blocks:  Arc::new(RwLock::new(blocks)),
             txs:     Arc::new(RwLock::new(txs)),
+            annotations: Arc::new(RwLock::new(annotations)),
+            sent_txs: Arc::new(RwLock::new(vec![])),
+            change_addresses: Arc::new(RwLock::new(change_addresses)),
+            options: Arc::new(RwLock::new(options)),
+            address_book: Arc::new(RwLock::new(address_book)),
             config:  config.clone(),
             birthday,
-        })
+        };
+
+        // The unconfirmed spends aren't saved, so mark the inputs of the transactions
+        // that are still pending again
+        for sent in sent_txs {
+            if let Err(e) = wallet.add_sent_tx(&sent.raw) {
+                warn!("Dropping unreadable sent transaction {}: {}", sent.txid, e);
+            }
+        }
+
+        Ok(wallet)
     }
 
     pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
@@ -276,6 +377,39 @@ impl LightWallet {
         // have a tx that is before the current birthday
         writer.write_u64::<LittleEndian>(self.get_birthday())?;
 
+        Vector::write(&mut writer, &self.sent_txs.read().unwrap(), |w, t| t.write(w))?;
+
+        self.options.read().unwrap().write(&mut writer)?;
+
+        // Sorted, so the same address book is always written the same way
+        let mut address_book = self.address_book.read().unwrap().iter()
+                                    .map(|(a, l)| (a.clone(), l.clone()))
+                                    .collect::<Vec<(String, String)>>();
+        address_book.sort();
+        Vector::write(&mut writer, &address_book, |w, (address, label)| {
+            utils::write_string(&mut *w, address)?;
+            utils::write_string(&mut *w, label)
+        })?;
+
+        // Sorted by txid, like the address book
+        let annotations = self.annotations.read().unwrap();
+        let mut sorted_annotations = annotations.iter().collect::<Vec<(&TxId, &TxAnnotation)>>();
+        sorted_annotations.sort_by_key(|(txid, _)| txid.0);
+        Vector::write(&mut writer, &sorted_annotations,
+                        |w, (k, v)| {
+                            w.write_all(&k.0)?;
+                            v.write(w)
+                        })?;
+
+        let change_addresses = self.change_addresses.read().unwrap();
+        let mut sorted_change_addresses = change_addresses.iter().collect::<Vec<(&TxId, &String)>>();
+        sorted_change_addresses.sort_by_key(|(txid, _)| txid.0);
+        Vector::write(&mut writer, &sorted_change_addresses,
+                        |w, (k, v)| {
+                            w.write_all(&k.0)?;
+                            utils::write_string(w, v)
+                        })?;
+
         Ok(())
     }
 
@@ -286,6 +420,194 @@ impl LightWallet {
         }
     }
 
+    pub fn get_expiry_delta(&self) -> u32 {
+        self.options.read().unwrap().expiry_delta
+    }
+
+    pub fn set_expiry_delta(&self, expiry_delta: u32) -> Result<(), String> {
+        if expiry_delta < MIN_EXPIRY_DELTA {
+            return Err(format!("Expiry delta has to be at least {} blocks", MIN_EXPIRY_DELTA));
+        }
+
+        self.options.write().unwrap().expiry_delta = expiry_delta;
+        Ok(())
+    }
+
+    pub fn get_change_policy(&self) -> ChangePolicy {
+        self.options.read().unwrap().change_policy.clone()
+    }
+
+    pub fn set_change_policy(&self, change_policy: ChangePolicy) -> Result<(), String> {
+        // Don't send change to an address that isn't ours
+        if let ChangePolicy::Fixed(address) = &change_policy {
+            let zaddrs = self.address.read().unwrap().iter()
+                            .map(|pa| encode_payment_address(self.config.hrp_sapling_address(), pa))
+                            .collect::<Vec<String>>();
+            let taddrs = self.tkeys.read().unwrap().iter()
+                            .map(|sk| self.address_from_sk(sk))
+                            .collect::<Vec<String>>();
+
+            if !zaddrs.contains(address) && !taddrs.contains(address) {
+                return Err(format!("Change address {} is not in this wallet", address));
+            }
+        }
+
+        self.options.write().unwrap().change_policy = change_policy;
+        Ok(())
+    }
+
+    // If the payment address was derived from one of our viewing keys, with any diversifier
+    fn is_wallet_zaddress(&self, pa: &PaymentAddress<Bls12>) -> bool {
+        self.extfvks.read().unwrap().iter().any(|extfvk|
+            extfvk.fvk.vk.into_payment_address(pa.diversifier, &JUBJUB).as_ref() == Some(pa)
+        )
+    }
+
+    // If the address is one of our z addresses (with any diversifier) or t addresses
+    pub fn is_wallet_address(&self, address: &str) -> bool {
+        match address::RecipientAddress::from_str(address,
+                    self.config.hrp_sapling_address(),
+                    self.config.base58_pubkey_address(),
+                    self.config.base58_script_address()) {
+            Some(address::RecipientAddress::Shielded(pa)) => self.is_wallet_zaddress(&pa),
+            Some(address::RecipientAddress::Transparent(_)) => self.tkeys.read().unwrap().iter()
+                                                                .any(|sk| self.address_from_sk(sk) == address),
+            None => false,
+        }
+    }
+
+    /// Label `address` in the address book, replacing its old label. The address can be one of ours or
+    /// someone else's, but labels have to be unique, since they can be used in place of the address.
+    pub fn set_label(&self, address: &str, label: &str) -> Result<(), String> {
+        let label = label.trim();
+        if label.is_empty() {
+            return Err("The label can't be empty".to_string());
+        }
+
+        let parse = |a: &str| address::RecipientAddress::from_str(a,
+                                self.config.hrp_sapling_address(),
+                                self.config.base58_pubkey_address(),
+                                self.config.base58_script_address());
+
+        if parse(address).is_none() {
+            let e = format!("Invalid address: {}", address);
+            error!("{}", e);
+            return Err(e);
+        }
+
+        if parse(label).is_some() {
+            return Err(format!("The label {} can't be an address", label));
+        }
+
+        let mut address_book = self.address_book.write().unwrap();
+        if let Some((other, _)) = address_book.iter().find(|(a, l)| l.as_str() == label && a.as_str() != address) {
+            return Err(format!("The label {} is already used for {}", label, other));
+        }
+
+        address_book.insert(address.to_string(), label.to_string());
+        Ok(())
+    }
+
+    /// Remove the label of an address, given either the address or the label. Returns the address
+    pub fn remove_label(&self, address_or_label: &str) -> Result<String, String> {
+        let address = self.resolve_address(address_or_label);
+
+        match self.address_book.write().unwrap().remove(&address) {
+            Some(_) => Ok(address),
+            None    => Err(format!("{} is not in the address book", address_or_label)),
+        }
+    }
+
+    pub fn get_label(&self, address: &str) -> Option<String> {
+        self.address_book.read().unwrap().get(address).cloned()
+    }
+
+    /// If `address_or_label` is a label from the address book, its address. Otherwise it is returned as is.
+    pub fn resolve_address(&self, address_or_label: &str) -> String {
+        self.address_book.read().unwrap().iter()
+            .find(|(_, l)| l.as_str() == address_or_label)
+            .map_or(address_or_label.to_string(), |(a, _)| a.clone())
+    }
+
+    /// All the (address, label) entries, sorted by label
+    pub fn get_address_book(&self) -> Vec<(String, String)> {
+        let mut entries = self.address_book.read().unwrap().iter()
+                            .map(|(a, l)| (a.clone(), l.clone()))
+                            .collect::<Vec<(String, String)>>();
+        entries.sort_by(|a, b| a.1.cmp(&b.1));
+
+        entries
+    }
+
+    /// Parse a txid as it is displayed, which is the reverse of its byte order
+    pub fn parse_txid(txid: &str) -> Result<TxId, String> {
+        match hex::decode(txid) {
+            Ok(mut bytes) if bytes.len() == 32 => {
+                bytes.reverse();
+                let mut txid_bytes = [0u8; 32];
+                txid_bytes.copy_from_slice(&bytes);
+                Ok(TxId{0: txid_bytes})
+            },
+            _ => Err(format!("Invalid txid: {}", txid))
+        }
+    }
+
+    // Update the annotation of one of our transactions, dropping it when nothing is left in it
+    fn update_annotation<F>(&self, txid: &TxId, f: F) -> Result<(), String>
+        where F: FnOnce(&mut TxAnnotation) -> Result<(), String> {
+        if !self.txs.read().unwrap().contains_key(txid)
+                && !self.sent_txs.read().unwrap().iter().any(|s| s.txid == *txid) {
+            return Err(format!("Transaction {} is not in this wallet", txid));
+        }
+
+        let mut annotations = self.annotations.write().unwrap();
+        let mut annotation = annotations.get(txid).cloned().unwrap_or_default();
+        f(&mut annotation)?;
+
+        if annotation.is_empty() {
+            annotations.remove(txid);
+        } else {
+            annotations.insert(txid.clone(), annotation);
+        }
+
+        Ok(())
+    }
+
+    /// Set the note for a transaction, replacing the old one. An empty text removes it.
+    pub fn set_annotation(&self, txid: &TxId, text: &str) -> Result<(), String> {
+        self.update_annotation(txid, |a| {
+            a.text = Some(text.trim().to_string()).filter(|t| !t.is_empty());
+            Ok(())
+        })
+    }
+
+    pub fn add_tag(&self, txid: &TxId, tag: &str) -> Result<(), String> {
+        let tag = tag.trim();
+        if tag.is_empty() || tag.contains(',') {
+            return Err(format!("Invalid tag '{}'. Tags can't be empty or contain commas", tag));
+        }
+
+        self.update_annotation(txid, |a| {
+            if !a.tags.iter().any(|t| t == tag) {
+                a.tags.push(tag.to_string());
+            }
+            Ok(())
+        })
+    }
+
+    pub fn remove_tag(&self, txid: &TxId, tag: &str) -> Result<(), String> {
+        self.update_annotation(txid, |a| {
+            match a.tags.iter().position(|t| t == tag.trim()) {
+                Some(i) => { a.tags.remove(i); Ok(()) },
+                None    => Err(format!("Transaction {} isn't tagged {}", txid, tag)),
+            }
+        })
+    }
+
+    pub fn get_annotation(&self, txid: &TxId) -> Option<TxAnnotation> {
+        self.annotations.read().unwrap().get(txid).cloned()
+    }
+
     pub fn get_birthday(&self) -> u64 {
         cmp::min(self.get_first_tx_block(), self.birthday)
     }
@@ -349,6 +671,13 @@ impl LightWallet {
         self.address_from_sk(&sk)
     }
 
+    /// The timestamp of one of the recently scanned blocks
+    pub fn get_block_time(&self, height: i32) -> Option<u32> {
+        self.blocks.read().unwrap().iter().rev()
+            .find(|b| b.height == height)
+            .map(|b| b.time)
+    }
+
     /// Clears all the downloaded blocks and resets the state back to the initial block.
     /// After this, the wallet's initial state will need to be set
     /// and the wallet will need to be rescanned
@@ -384,7 +713,7 @@ impl LightWallet {
         };
 
         if let Ok(tree) = CommitmentTree::read(&sapling_tree[..]) {
-            blocks.push(BlockData { height, hash, tree });
+            blocks.push(BlockData { height, hash, tree, time: 0 });
             true
         } else {
             false
@@ -441,6 +770,16 @@ impl LightWallet {
         }
     }
 
+    /// Format a unix timestamp as an ISO 8601 date in UTC. None if the time isn't known.
+    pub fn datetime_str(datetime: u64) -> Option<String> {
+        if datetime == 0 {
+            return None;
+        }
+
+        Some(chrono::NaiveDateTime::from_timestamp(datetime as i64, 0)
+                .format("%Y-%m-%dT%H:%M:%SZ").to_string())
+    }
+
     pub fn memo_str(memo: &Option<Memo>) -> Option<String> {
         match memo {
             Some(memo) => {
@@ -453,6 +792,74 @@ impl LightWallet {
         }
     }
 
+    /// Split a memo that follows ZecWallet's reply-to convention into the message and the sender's address.
+    /// The address is on the last line, after a line that is just "Reply-To:"
+    pub fn parse_reply_to(memo: &str) -> (String, Option<String>) {
+        let lines = memo.trim_end().lines().collect::<Vec<&str>>();
+
+        if lines.len() >= 2 && lines[lines.len() - 2].trim() == "Reply-To:" {
+            let address = lines[lines.len() - 1].trim();
+            if !address.is_empty() {
+                return (lines[..lines.len() - 2].join("\n"), Some(address.to_string()));
+            }
+        }
+
+        (memo.to_string(), None)
+    }
+
+    /// A memo with `address` as the reply-to address, in the same convention as `parse_reply_to`
+    pub fn memo_with_reply_to(message: &str, address: &str) -> String {
+        format!("{}\nReply-To:\n{}", message, address)
+    }
+
+    // ZIP-302 empty memo, which is 0xF6 followed by all zeros
+    pub fn is_empty_memo(memo: &Memo) -> bool {
+        let bytes = memo.as_bytes();
+        bytes[0] == 0xF6 && bytes[1..].iter().all(|b| *b == 0)
+    }
+
+    /// Hex encoding of memos that aren't text and aren't empty, for eg. the ZIP-302 arbitrary
+    /// data (0xFF) memos, or text memos that aren't valid UTF-8. Trailing zeros are dropped.
+    pub fn memo_hex(memo: &Option<Memo>) -> Option<String> {
+        match memo {
+            Some(m) if LightWallet::memo_str(memo).is_none() && !LightWallet::is_empty_memo(m) => {
+                let bytes = m.as_bytes();
+                let len = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+                Some(hex::encode(&bytes[..len]))
+            },
+            _ => None
+        }
+    }
+
+    /// Parse a memo entered by the user. Memos starting with "0x" are hex encoded binary memos,
+    /// anything else is sent as text.
+    pub fn parse_memo(memo: &str) -> Result<Memo, String> {
+        if memo.starts_with("0x") {
+            let bytes = match hex::decode(&memo[2..]) {
+                Ok(b)  => b,
+                Err(e) => return Err(format!("Invalid hex memo: {}", e))
+            };
+
+            if bytes.is_empty() {
+                return Err(format!("Hex memo is empty"));
+            }
+            if bytes.len() > 512 {
+                return Err(format!("Memo is too long ({} bytes, the maximum is 512)", bytes.len()));
+            }
+
+            // According to ZIP-302, a memo starting with a byte up to 0xF4 is UTF-8 text,
+            // so don't send anything else that would be read as broken text
+            if bytes[0] <= 0xF4 && std::str::from_utf8(&bytes).is_err() {
+                return Err(format!("Hex memos starting with a byte up to 0xF4 have to be valid UTF-8. Use 0xFF as the first byte for arbitrary data"));
+            }
+
+            Ok(Memo::from_bytes(&bytes).unwrap())
+        } else {
+            Memo::from_str(memo).map_err(|_|
+                format!("Memo is too long ({} bytes, the maximum is 512)", memo.as_bytes().len()))
+        }
+    }
+
     pub fn address_from_sk(&self, sk: &secp256k1::SecretKey) -> String {
         let secp = secp256k1::Secp256k1::new();
         let pk = secp256k1::PublicKey::from_secret_key(&secp, &sk);
@@ -483,8 +890,24 @@ impl LightWallet {
     }
 
     pub fn zbalance(&self, addr: Option<String>) -> u64 {
+        self.zbalance_minconf(addr, 0)
+    }
+
+    /// Number of blocks that include a Tx mined at `height`, counting its own block. 0 if it isn't mined yet.
+    pub fn confirmations(&self, height: i32) -> u32 {
+        let last_height = self.last_scanned_height();
+        if height > last_height {
+            0
+        } else {
+            (last_height - height + 1) as u32
+        }
+    }
+
+    /// The unspent notes with at least `minconf` confirmations, like zcashd's `z_getbalance addr minconf`
+    pub fn zbalance_minconf(&self, addr: Option<String>, minconf: u32) -> u64 {
         self.txs.read().unwrap()
             .values()
+            .filter(|tx| self.confirmations(tx.block) >= minconf)
             .map(|tx| {
                 tx.notes.iter()
                     .filter(|nd| {  // TODO, this whole section is shared with verified_balance. Refactor it. 
@@ -516,7 +939,12 @@ impl LightWallet {
     }
 
     pub fn tbalance(&self, addr: Option<String>) -> u64 {
+        self.tbalance_minconf(addr, 0)
+    }
+
+    pub fn tbalance_minconf(&self, addr: Option<String>, minconf: u32) -> u64 {
         self.get_utxos().iter()
+            .filter(|utxo| self.confirmations(utxo.height) >= minconf)
             .filter(|utxo| {
                 match addr.clone() {
                     Some(a) => utxo.address == a,
@@ -560,12 +988,106 @@ impl LightWallet {
             .sum::<u64>()
     }
 
-    fn add_toutput_to_wtx(&self, height: i32, txid: &TxId, vout: &TxOut, n: u64) {
+    /// Split the unspent funds of the wallet, or of the address `addr`, by whether they can be spent now.
+    /// Notes can be spent once they are at or below the anchor height, `anchor_offset` blocks back.
+    pub fn balance_breakdown(&self, addr: Option<String>) -> BalanceBreakdown {
+        let mut breakdown = BalanceBreakdown::default();
+
+        let anchor_height = match self.get_target_height_and_anchor_offset() {
+            Some((height, anchor_offset)) => (height - anchor_offset as u32) as i32,
+            None => return breakdown,
+        };
+
+        for tx in self.txs.read().unwrap().values() {
+            for nd in tx.notes.iter().filter(|nd| nd.spent.is_none()) {
+                if addr.is_some() && self.note_address(nd) != addr {
+                    continue;
+                }
+
+                if nd.unconfirmed_spent.is_some() {
+                    breakdown.pending_spent += nd.note.value;
+                } else if tx.block <= anchor_height {
+                    breakdown.spendable += nd.note.value;
+                } else {
+                    if nd.is_change {
+                        breakdown.unconfirmed_change += nd.note.value;
+                    } else {
+                        breakdown.unconfirmed += nd.note.value;
+                    }
+                    breakdown.blocks_until_spendable = cmp::max(breakdown.blocks_until_spendable,
+                                                                (tx.block - anchor_height) as u32);
+                }
+            }
+
+            for utxo in tx.utxos.iter().filter(|u| u.spent.is_none()) {
+                if addr.as_ref().map_or(false, |a| *a != utxo.address) {
+                    continue;
+                }
+
+                if utxo.unconfirmed_spent.is_some() {
+                    breakdown.pending_spent_transparent += utxo.value;
+                } else if utxo.height <= anchor_height {
+                    breakdown.confirmed_transparent += utxo.value;
+                } else {
+                    breakdown.unconfirmed_transparent += utxo.value;
+                }
+            }
+        }
+
+        breakdown
+    }
+
+    /// The funds received by every address of the wallet, including the diversified addresses that
+    /// received notes. Only the notes and utxos with at least `minconf` confirmations are counted.
+    pub fn received_by_address(&self, minconf: u32) -> Vec<ReceivedByAddress> {
+        let mut received: HashMap<String, ReceivedByAddress> = HashMap::new();
+
+        // All our addresses are listed, even if they didn't receive anything
+        let addresses = self.address.read().unwrap().iter()
+                            .map(|pa| encode_payment_address(self.config.hrp_sapling_address(), pa))
+                            .chain(self.tkeys.read().unwrap().iter().map(|sk| self.address_from_sk(sk)))
+                            .collect::<Vec<String>>();
+        for address in addresses.iter() {
+            received.insert(address.clone(), ReceivedByAddress{ address: address.clone(), ..Default::default() });
+        }
+
+        let mut add = |address: String, value: u64, is_change: bool, unspent: bool| {
+            let entry = received.entry(address.clone())
+                                .or_insert(ReceivedByAddress{ address, ..Default::default() });
+            if !is_change {
+                entry.received += value;
+                entry.count += 1;
+            }
+            if unspent {
+                entry.unspent += value;
+            }
+        };
+
+        for tx in self.txs.read().unwrap().values().filter(|tx| self.confirmations(tx.block) >= minconf) {
+            for nd in tx.notes.iter() {
+                if let Some(address) = self.note_address(nd) {
+                    add(address, nd.note.value, nd.is_change, nd.spent.is_none());
+                }
+            }
+
+            for utxo in tx.utxos.iter() {
+                add(utxo.address.clone(), utxo.value, utxo.is_change, utxo.spent.is_none());
+            }
+        }
+
+        // In the order of the wallet's addresses, and then the diversified addresses
+        let mut received = received.values().cloned().collect::<Vec<ReceivedByAddress>>();
+        received.sort_by_key(|r| (addresses.iter().position(|a| *a == r.address).unwrap_or(addresses.len()), r.address.clone()));
+
+        received
+    }
+
+    fn add_toutput_to_wtx(&self, height: i32, datetime: u64, txid: &TxId, vout: &TxOut, n: u64) {
         let mut txs = self.txs.write().unwrap();
 
         // Find the existing transaction entry, or create a new one.
         if !txs.contains_key(&txid) {
-            let tx_entry = WalletTx::new(height, &txid);
+            let tx_entry = WalletTx::new(height, datetime, &txid);
             txs.insert(txid.clone(), tx_entry);
         }
         let tx_entry = txs.get_mut(&txid).unwrap();
@@ -593,6 +1115,7 @@ impl LightWallet {
                         height,
                         spent: None,
                         unconfirmed_spent: None,
+                        is_change: false,
                     });
                 }
             }
@@ -600,13 +1123,19 @@ impl LightWallet {
     }
 
     // Scan the full Tx and update memos for incoming shielded transactions
-    pub fn scan_full_tx(&self, tx: &Transaction, height: i32) {
+    /// Scan a full transaction that was mined at `height`, in a block with the timestamp `datetime`.
+    /// Pass 0 as the `datetime` if it isn't known.
+    pub fn scan_full_tx(&self, tx: &Transaction, height: i32, datetime: u64) {
         // Scan all the inputs to see if we spent any transparent funds in this tx
         
+        // Shielded outputs that our ovk recovered, and that went to one of our own addresses
+        let mut notes_to_self = vec![];
+
         // TODO: Save this object
         let secp = secp256k1::Secp256k1::new();
 
         let mut total_transparent_spend: u64 = 0;
+        let mut spent_taddrs = HashSet::new();
 
         for vin in tx.vin.iter() {    
             // Find the txid in the list of utxos that we have.
@@ -626,6 +1155,7 @@ impl LightWallet {
                             su.unconfirmed_spent = None;
 
                             total_transparent_spend += su.value;
+                            spent_taddrs.insert(su.address.clone());
                         },
                         _ => {}
                     }
@@ -639,7 +1169,7 @@ impl LightWallet {
             let mut txs = self.txs.write().unwrap();
 
             if !txs.contains_key(&tx.txid()) {
-                let tx_entry = WalletTx::new(height, &tx.txid());
+                let tx_entry = WalletTx::new(height, datetime, &tx.txid());
                 txs.insert(tx.txid().clone(), tx_entry);
             }
             
@@ -661,7 +1191,7 @@ impl LightWallet {
                     Some(TransparentAddress::PublicKey(hash)) => {
                         if hash[..] == ripemd160::Ripemd160::digest(&Sha256::digest(&pubkey))[..] {
                             // This is our address. Add this as an output to the txid
-                            self.add_toutput_to_wtx(height, &tx.txid(), &vout, n as u64);
+                            self.add_toutput_to_wtx(height, datetime, &tx.txid(), &vout, n as u64);
                         }
                     },
                     _ => {}
@@ -743,12 +1273,6 @@ impl LightWallet {
             // If it can, then we sent this transaction, so we should be able to get
             // the memo and value for our records
 
-            // First, collect all our z addresses, to check for change
-            // Collect z addresses
-            let z_addresses = self.address.read().unwrap().iter().map( |ad| {
-                encode_payment_address(self.config.hrp_sapling_address(), &ad)
-            }).collect::<HashSet<String>>();
-
             // Search all ovks that we have
             let ovks: Vec<_> = self.extfvks.read().unwrap().iter().map(
                 |extfvk| extfvk.fvk.ovk.clone()
@@ -762,14 +1286,16 @@ impl LightWallet {
                     &output.enc_ciphertext,
                     &output.out_ciphertext) {
                         Some((note, payment_address, memo)) => {
-                            let address = encode_payment_address(self.config.hrp_sapling_address(), 
-                                            &payment_address);
-
-                            // Check if this is a change address
-                            if z_addresses.contains(&address) {
+                            // Outputs we sent to ourselves aren't outgoing. They can go to any of
+                            // our diversified addresses, not just the default ones
+                            if self.is_wallet_zaddress(&payment_address) {
+                                notes_to_self.push(note);
                                 continue;
                             }
 
+                            let address = encode_payment_address(self.config.hrp_sapling_address(), 
+                                            &payment_address);
+
                             // Update the WalletTx 
                             // Do it in a short scope because of the write lock.
                             {
@@ -798,9 +1324,49 @@ impl LightWallet {
 
         // Mark this Tx as scanned
         {
+            let change_address = self.change_addresses.read().unwrap().get(&tx.txid()).cloned();
             let mut txs = self.txs.write().unwrap();
+
+            // The accounts whose notes paid for this Tx. A Tx that only spends utxos is paid for
+            // by the first account.
+            let txid = tx.txid();
+            let mut paying_accounts = txs.values()
+                .flat_map(|wtx| wtx.notes.iter())
+                .filter(|nd| nd.spent.as_ref() == Some(&txid))
+                .map(|nd| nd.account)
+                .collect::<HashSet<usize>>();
+            if paying_accounts.is_empty() && !spent_taddrs.is_empty() {
+                paying_accounts.insert(0);
+            }
+
             match txs.get_mut(&tx.txid()) {
-                Some(wtx) => wtx.full_tx_scanned = true,
+                Some(wtx) => {
+                    wtx.full_tx_scanned = true;
+                    wtx.expiry_height = tx.expiry_height;
+                    if wtx.datetime == 0 {
+                        wtx.datetime = datetime;
+                    }
+
+                    // If we paid for this Tx, flag the outputs that came back to the paying account as
+                    // change. This only depends on the Tx itself, so a rescan flags the same outputs.
+                    // The compact block scan only flags notes when the same account spent a sapling note.
+                    // Transparent outputs can't be told apart from a payment to ourselves, so they are
+                    // only change if they go to the change address we sent this Tx with, or back to an
+                    // address that this Tx spent from.
+                    if wtx.total_shielded_value_spent + wtx.total_transparent_value_spent > 0 {
+                        for nd in wtx.notes.iter_mut() {
+                            if paying_accounts.contains(&nd.account) && notes_to_self.contains(&nd.note) {
+                                nd.is_change = true;
+                            }
+                        }
+
+                        for utxo in wtx.utxos.iter_mut() {
+                            if spent_taddrs.contains(&utxo.address) || change_address.as_ref() == Some(&utxo.address) {
+                                utxo.is_change = true;
+                            }
+                        }
+                    }
+                },
                 None => {},
             };
         }
@@ -914,6 +1480,7 @@ impl LightWallet {
                 .last()
                 .map(|block| block.tree.clone())
                 .unwrap_or(CommitmentTree::new()),
+            time: block.time,
         };
 
         // Create a write lock that will last for the rest of the function.
@@ -1013,11 +1580,12 @@ impl LightWallet {
 
             // Find the existing transaction entry, or create a new one.
             if !txs.contains_key(&tx.txid) {
-                let tx_entry = WalletTx::new(block_data.height as i32, &tx.txid);
+                let tx_entry = WalletTx::new(block_data.height as i32, block.time as u64, &tx.txid);
                 txs.insert(tx.txid, tx_entry);
             }
             let tx_entry = txs.get_mut(&tx.txid).unwrap();
             tx_entry.total_shielded_value_spent = total_shielded_value_spent;
+            tx_entry.datetime = block.time as u64;
 
             // Save notes.
             for output in tx.shielded_outputs
@@ -1061,12 +1629,23 @@ impl LightWallet {
     pub fn send_to_address(
         &self,
         consensus_branch_id: u32,
-        spend_params: &[u8],
-        output_params: &[u8],
+        prover: &prover::InMemTxProver,
         tos: Vec<(&str, u64, Option<String>)>
     ) -> Result<Box<[u8]>, String> {
-        let start_time = now();
+        let mut proposal = self.create_proposal(tos)?;
+        proposal.consensus_branch_id = consensus_branch_id;
 
+        self.send_proposal(prover, &proposal)
+    }
+
+    /// Select the notes and utxos needed to send to `tos`, and work out the fee and the change.
+    /// This doesn't build or prove the transaction, and doesn't mark anything as spent, so
+    /// it is cheap to call, and it only needs the viewing keys. Use `send_proposal` to actually
+    /// create the transaction, or `sign_proposal` on a wallet that has the spending keys.
+    pub fn create_proposal(
+        &self,
+        tos: Vec<(&str, u64, Option<String>)>
+    ) -> Result<TxProposal, String> {
         let total_value = tos.iter().map(|to| to.1).sum::<u64>();
 
         println!(
@@ -1074,24 +1653,9 @@ impl LightWallet {
             total_value, tos.len()
         );
 
-        // Convert address (str) to RecepientAddress and value to Amount
-        let tos = tos.iter().map(|to| {
-            let ra = match address::RecipientAddress::from_str(to.0, 
-                            self.config.hrp_sapling_address(), 
-                            self.config.base58_pubkey_address(), 
-                            self.config.base58_script_address()) {
-                Some(to) => to,
-                None => {
-                    let e = format!("Invalid recipient address: {}", to.0);
-                    error!("{}", e);
-                    return Err(e);
-                }
-            };
-
-            let value = Amount::from_u64(to.1).unwrap();
-
-            Ok((ra, value, to.2.clone()))
-        }).collect::<Result<Vec<(address::RecipientAddress, Amount, Option<String>)>, String>>()?;
+        // Make sure all the addresses are valid before we select any notes
+        let outputs = self.proposal_outputs(&tos)?;
+        let consensus_branch_id = self.parsed_consensus_branch_id()?;
 
         // Target the next block, assuming we are up-to-date.
         let (height, anchor_offset) = match self.get_target_height_and_anchor_offset() {
@@ -1104,13 +1668,12 @@ impl LightWallet {
         };
 
         // Select notes to cover the target value
-        println!("{}: Selecting notes", now() - start_time);
         let target_value = Amount::from_u64(total_value).unwrap() + DEFAULT_FEE ;
         let notes: Vec<_> = self.txs.read().unwrap().iter()
             .map(|(txid, tx)| tx.notes.iter().map(move |note| (*txid, note)))
             .flatten()
             .filter_map(|(txid, note)|
-                SpendableNote::from(txid, note, anchor_offset, &self.extsks.read().unwrap()[note.account])
+                SpendableNote::from(txid, note, anchor_offset)
             )
             .scan(0, |running_total, spendable| {
                 let value = spendable.note.value;
@@ -1124,8 +1687,6 @@ impl LightWallet {
             })
             .collect();
 
-        let mut builder = Builder::new(height);
-
         // A note on t addresses
         // Funds received by t-addresses can't be explicitly spent in ZecWallet. 
         // ZecWallet will lazily consolidate all t address funds into your shielded addresses. 
@@ -1136,43 +1697,22 @@ impl LightWallet {
                                 .filter(|utxo| utxo.unconfirmed_spent.is_none()) // Remove any unconfirmed spends
                                 .map(|utxo| utxo.clone())
                                 .collect();
-        
-        // Create a map from address -> sk for all taddrs, so we can spend from the 
-        // right address
-        let address_to_sk: HashMap<_, _> = self.tkeys.read().unwrap().iter().map(|sk|
-                                                (self.address_from_sk(&sk), sk.clone())
-                                            ).collect();
 
-        // Add all tinputs
-        tinputs.iter()
-            .map(|utxo| {
-                let outpoint: OutPoint = utxo.to_outpoint();
-        
-                let coin = TxOut {
-                    value: Amount::from_u64(utxo.value).unwrap(),
-                    script_pubkey: Script { 0: utxo.script.clone() },
-                };
+        let change_address = self.change_address_for(&notes)?;
 
-                match address_to_sk.get(&utxo.address) {
-                    Some(sk) => builder.add_transparent_input(*sk, outpoint.clone(), coin.clone()),
-                    None     => {
-                        // Something is very wrong
-                        let e = format!("Couldn't find the secreykey for taddr {}", utxo.address);
-                        error!("{}", e);
-
-                        Err(zcash_primitives::transaction::builder::Error::InvalidAddress)
-                    }
-                }
-                
-            })
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| format!("{}", e))?;
-        
+        let proposal = TxProposal {
+            target_height: height,
+            expiry_height: height + self.get_expiry_delta(),
+            consensus_branch_id,
+            notes,
+            utxos: tinputs,
+            outputs,
+            fee: u64::from(DEFAULT_FEE),
+            change_address: Some(change_address),
+        };
 
         // Confirm we were able to select sufficient value
-        let selected_value = notes.iter().map(|selected| selected.note.value).sum::<u64>() 
-                             + tinputs.iter().map::<u64, _>(|utxo| utxo.value.into()).sum::<u64>();
-
+        let selected_value = proposal.selected_value();
         if selected_value < u64::from(target_value) {
             let e = format!(
                 "Insufficient verified funds (have {}, need {:?}).\n Note, funds need {} confirmations before they can be spent",
@@ -1182,12 +1722,375 @@ impl LightWallet {
             return Err(e);
         }
 
+        Ok(proposal)
+    }
+
+    /// Roughly how many bytes the transaction built from the proposal will be, including the change output
+    pub fn estimated_tx_size(&self, proposal: &TxProposal) -> usize {
+        // Header, value balance and binding signature
+        const OVERHEAD: usize = 100;
+        const SPEND_SIZE: usize = 384;
+        const OUTPUT_SIZE: usize = 948;
+        const TIN_SIZE: usize = 150;
+        const TOUT_SIZE: usize = 34;
+
+        let touts = proposal.outputs.iter()
+                        .filter(|o| !o.address.starts_with(self.config.hrp_sapling_address()))
+                        .count();
+        let zouts = proposal.outputs.len() - touts + 1;
+
+        OVERHEAD + proposal.notes.len() * SPEND_SIZE + zouts * OUTPUT_SIZE
+            + proposal.utxos.len() * TIN_SIZE + touts * TOUT_SIZE
+    }
+
+    /// Check the addresses and memos of the outputs for a proposal
+    pub fn proposal_outputs(&self, tos: &[(&str, u64, Option<String>)]) -> Result<Vec<ProposedOutput>, String> {
+        tos.iter().map(|to| {
+            let address = match address::RecipientAddress::from_str(to.0,
+                            self.config.hrp_sapling_address(),
+                            self.config.base58_pubkey_address(),
+                            self.config.base58_script_address()) {
+                Some(a) => a,
+                None    => {
+                    let e = format!("Invalid recipient address: {}", to.0);
+                    error!("{}", e);
+                    return Err(e);
+                }
+            };
+
+            let memo = match (&to.2, address) {
+                (None, _) => None,
+                (Some(_), address::RecipientAddress::Transparent(_)) => {
+                    let e = format!("Can't send a memo to the transparent address {}", to.0);
+                    error!("{}", e);
+                    return Err(e);
+                },
+                (Some(m), address::RecipientAddress::Shielded(_)) => match LightWallet::parse_memo(m) {
+                    Ok(memo) => Some(memo),
+                    Err(e)   => {
+                        error!("{}", e);
+                        return Err(e);
+                    }
+                }
+            };
+
+            Ok(ProposedOutput { address: to.0.to_string(), value: to.1, memo })
+        }).collect::<Result<Vec<ProposedOutput>, String>>()
+    }
+
+    // Pick the address that the change of a transaction spending `notes` goes to, according to
+    // the wallet's change policy. Change only goes to a t address if the policy is a fixed t address.
+    fn change_address_for(&self, notes: &[SpendableNote]) -> Result<String, String> {
+        // The account of the first note pays for the transaction, or the first account if only utxos are spent
+        let extfvk = match notes.first() {
+            Some(n) => n.extfvk.clone(),
+            None    => self.extfvks.read().unwrap()[0].clone(),
+        };
+
+        let pa = match self.get_change_policy() {
+            ChangePolicy::Fixed(address) => return Ok(address),
+            // If only utxos are spent, the change is shielded, so it can't be linked to them
+            ChangePolicy::SameAsInput => match notes.first() {
+                Some(n) => n.extfvk.fvk.vk.into_payment_address(n.diversifier, &JUBJUB),
+                None    => extfvk.default_address().ok().map(|(_, pa)| pa),
+            },
+            ChangePolicy::FreshDiversified => {
+                // Start searching for a valid diversifier at a random index, so change addresses
+                // can't be linked to each other or to the addresses we've given out
+                let mut index = [0u8; 11];
+                OsRng.fill(&mut index);
+                extfvk.address(DiversifierIndex(index)).ok().map(|(_, pa)| pa)
+            }
+        };
+
+        match pa {
+            Some(pa) => Ok(encode_payment_address(self.config.hrp_sapling_address(), &pa)),
+            None     => {
+                let e = format!("Couldn't derive a change address");
+                error!("{}", e);
+                Err(e)
+            }
+        }
+    }
+
+    fn parsed_consensus_branch_id(&self) -> Result<u32, String> {
+        u32::from_str_radix(&self.config.consensus_branch_id, 16).map_err(|e| {
+            let e = format!("Invalid consensus branch id {}: {}", self.config.consensus_branch_id, e);
+            error!("{}", e);
+            e
+        })
+    }
+
+    /// Create a proposal that sends everything that can be spent right now to `to`, minus the fee.
+    /// If `from` is set, only the notes and utxos of that address are spent. Since everything
+    /// is spent, the transaction has no change output.
+    pub fn create_sweep_proposal(
+        &self,
+        to: &str,
+        memo: Option<String>,
+        from: Option<String>
+    ) -> Result<TxProposal, String> {
+        println!("0: Creating transaction sending all funds{} to {}",
+                 from.as_ref().map_or("".to_string(), |f| format!(" from {}", f)), to);
+
+        // Check the address and memo first
+        self.proposal_outputs(&[(to, 0, memo.clone())])?;
+        let consensus_branch_id = self.parsed_consensus_branch_id()?;
+
+        let (height, anchor_offset) = match self.get_target_height_and_anchor_offset() {
+            Some(res) => res,
+            None => {
+                let e = format!("Cannot send funds before scanning any blocks");
+                error!("{}", e);
+                return Err(e);
+            }
+        };
+
+        // Every note that has enough confirmations to be spent
+        let notes: Vec<_> = self.txs.read().unwrap().iter()
+            .map(|(txid, tx)| tx.notes.iter().map(move |note| (*txid, note)))
+            .flatten()
+            .filter(|(_, note)| from.is_none() || self.note_address(note) == from)
+            .filter_map(|(txid, note)| SpendableNote::from(txid, note, anchor_offset))
+            .collect();
+
+        let tinputs: Vec<_> = self.get_utxos().into_iter()
+            .filter(|utxo| utxo.unconfirmed_spent.is_none())
+            .filter(|utxo| from.is_none() || Some(&utxo.address) == from.as_ref())
+            .collect();
+
+        let fee = u64::from(DEFAULT_FEE);
+        let selected_value = notes.iter().map(|n: &SpendableNote| n.note.value).sum::<u64>()
+                                + tinputs.iter().map(|u| u.value).sum::<u64>();
+        if selected_value <= fee {
+            let e = format!(
+                "Insufficient verified funds to pay the fee (have {}, the fee is {}).\n Note, funds need {} confirmations before they can be spent",
+                selected_value, fee, self.config.anchor_offset
+            );
+            error!("{}", e);
+            return Err(e);
+        }
+
+        let outputs = self.proposal_outputs(&[(to, selected_value - fee, memo)])?;
+
+        Ok(TxProposal {
+            target_height: height,
+            expiry_height: height + self.get_expiry_delta(),
+            consensus_branch_id,
+            notes,
+            utxos: tinputs,
+            outputs,
+            fee,
+            change_address: None,   // Everything is sent, so there is no change
+        })
+    }
+
+    /// Create a proposal that merges up to `max_inputs` of the smallest spendable notes into one note
+    /// at `to`, which has to be one of our z addresses. The default is the first z address.
+    /// Returns None if there aren't at least two notes to merge.
+    pub fn create_consolidation_proposal(
+        &self,
+        max_inputs: usize,
+        to: Option<String>
+    ) -> Result<Option<TxProposal>, String> {
+        let zaddrs = self.address.read().unwrap().iter()
+                        .map(|pa| encode_payment_address(self.config.hrp_sapling_address(), pa))
+                        .collect::<Vec<String>>();
+        let to = match to {
+            Some(to) if !zaddrs.contains(&to) => {
+                let e = format!("{} is not a z address in this wallet", to);
+                error!("{}", e);
+                return Err(e);
+            },
+            Some(to) => to,
+            None     => zaddrs[0].clone(),
+        };
+
+        if max_inputs < 2 {
+            let e = format!("Need at least 2 inputs to consolidate notes");
+            error!("{}", e);
+            return Err(e);
+        }
+
+        let consensus_branch_id = self.parsed_consensus_branch_id()?;
+
+        let (height, anchor_offset) = match self.get_target_height_and_anchor_offset() {
+            Some(res) => res,
+            None => {
+                let e = format!("Cannot send funds before scanning any blocks");
+                error!("{}", e);
+                return Err(e);
+            }
+        };
+
+        // The smallest notes that have enough confirmations to be spent
+        let mut notes: Vec<_> = self.txs.read().unwrap().iter()
+            .map(|(txid, tx)| tx.notes.iter().map(move |note| (*txid, note)))
+            .flatten()
+            .filter_map(|(txid, note)| SpendableNote::from(txid, note, anchor_offset))
+            .collect();
+        notes.sort_by_key(|n| n.note.value);
+        notes.truncate(max_inputs);
+
+        if notes.len() < 2 {
+            return Ok(None);
+        }
+
+        let fee = u64::from(DEFAULT_FEE);
+        let selected_value = notes.iter().map(|n| n.note.value).sum::<u64>();
+        if selected_value <= fee {
+            let e = format!("The {} smallest notes are worth {}, which doesn't pay the fee of {}. Try a bigger --max-inputs",
+                            notes.len(), selected_value, fee);
+            error!("{}", e);
+            return Err(e);
+        }
+
+        let outputs = self.proposal_outputs(&[(&to, selected_value - fee, None)])?;
+
+        Ok(Some(TxProposal {
+            target_height: height,
+            expiry_height: height + self.get_expiry_delta(),
+            consensus_branch_id,
+            notes,
+            utxos: vec![],
+            outputs,
+            fee,
+            change_address: None,   // Everything is sent, so there is no change
+        }))
+    }
+
+    /// Build, prove and sign the transaction described by the proposal, and mark its inputs
+    /// as unconfirmed spent. Returns the raw transaction, so the caller can broadcast it.
+    pub fn send_proposal(
+        &self,
+        prover: &prover::InMemTxProver,
+        proposal: &TxProposal
+    ) -> Result<Box<[u8]>, String> {
+        // The proposal might have been created a while ago, so make sure it still makes sense
+        match self.get_target_height_and_anchor_offset() {
+            Some((height, _)) if height > proposal.target_height.saturating_add(MAX_PROPOSAL_AGE) => {
+                let e = format!("Transaction proposal for height {} is too old, please create a new one", proposal.target_height);
+                error!("{}", e);
+                return Err(e);
+            },
+            _ => {}
+        };
+
+        {
+            let txs = self.txs.read().unwrap();
+            let notes_available = proposal.notes.iter().all(|selected| {
+                txs.get(&selected.txid)
+                    .and_then(|wtx| wtx.notes.iter().find(|nd| nd.nullifier == selected.nullifier))
+                    .map_or(false, |nd| nd.spent.is_none() && nd.unconfirmed_spent.is_none())
+            });
+            let utxos_available = proposal.utxos.iter().all(|selected| {
+                txs.get(&selected.txid)
+                    .and_then(|wtx| wtx.utxos.iter().find(|u| u.output_index == selected.output_index))
+                    .map_or(false, |u| u.spent.is_none() && u.unconfirmed_spent.is_none())
+            });
+
+            if !notes_available || !utxos_available {
+                let e = format!("Some of the funds in the transaction proposal have already been spent, please create a new one");
+                error!("{}", e);
+                return Err(e);
+            }
+        }
+
+        let raw_tx = self.sign_proposal(prover, proposal)?;
+        let txid = self.add_sent_tx(&raw_tx)?;
+
+        if let Some(change_address) = &proposal.change_address {
+            self.change_addresses.write().unwrap().insert(txid, change_address.clone());
+        }
+
+        Ok(raw_tx)
+    }
+
+    /// Build, prove and sign the transaction described by the proposal, without looking at
+    /// or changing the wallet's notes. This is what an offline signer does with a proposal that
+    /// was exported from an online wallet, so the wallet doesn't need to be synced.
+    pub fn sign_proposal(
+        &self,
+        prover: &prover::InMemTxProver,
+        proposal: &TxProposal
+    ) -> Result<Box<[u8]>, String> {
+        if proposal.expiry_height < proposal.target_height.saturating_add(MIN_EXPIRY_DELTA)
+            || proposal.expiry_height > MAX_EXPIRY_HEIGHT {
+            let e = format!("Invalid expiry height {} for a transaction at height {}. It has to be at least {} blocks later",
+                            proposal.expiry_height, proposal.target_height, MIN_EXPIRY_DELTA);
+            error!("{}", e);
+            return Err(e);
+        }
+
+        let change = match proposal.change() {
+            Ok(c)  => c,
+            Err(e) => {
+                error!("{}", e);
+                return Err(e);
+            }
+        };
+
+        let start_time = now();
+
+        // The builder always sets the expiry to its height + DEFAULT_EXPIRY_DELTA. The height
+        // isn't used for anything else, so offset it to get the expiry we want.
+        let mut builder = Builder::new(proposal.expiry_height.saturating_sub(DEFAULT_EXPIRY_DELTA));
+
+        // Create a map from address -> sk for all taddrs, so we can spend from the 
+        // right address
+        let address_to_sk: HashMap<_, _> = self.tkeys.read().unwrap().iter().map(|sk|
+                                                (self.address_from_sk(&sk), sk.clone())
+                                            ).collect();
+
+        // Proposals can be read from a file, so the values are checked instead of unwrapped
+        let amount = |value: u64| Amount::from_u64(value).map_err(|_| {
+            let e = format!("Invalid amount {} in the transaction proposal", value);
+            error!("{}", e);
+            e
+        });
+
+        // Add all tinputs
+        proposal.utxos.iter()
+            .map(|utxo| {
+                let outpoint: OutPoint = utxo.to_outpoint();
+        
+                let coin = TxOut {
+                    value: amount(utxo.value)?,
+                    script_pubkey: Script { 0: utxo.script.clone() },
+                };
+
+                match address_to_sk.get(&utxo.address) {
+                    Some(sk) => builder.add_transparent_input(*sk, outpoint.clone(), coin.clone())
+                                    .map_err(|e| format!("{}", e)),
+                    None     => {
+                        // Something is very wrong
+                        let e = format!("Couldn't find the secreykey for taddr {}", utxo.address);
+                        error!("{}", e);
+
+                        Err(format!("{}", zcash_primitives::transaction::builder::Error::InvalidAddress))
+                    }
+                }
+                
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
         // Create the transaction
-        println!("{}: Adding {} notes and {} utxos", now() - start_time, notes.len(), tinputs.len());
+        println!("{}: Adding {} notes and {} utxos", now() - start_time, proposal.notes.len(), proposal.utxos.len());
+
+        for selected in proposal.notes.iter() {
+            // Find the spending key for the note's viewing key
+            let extsk = match self.extfvks.read().unwrap().iter().position(|fvk| *fvk == selected.extfvk) {
+                Some(pos) => self.extsks.read().unwrap()[pos].clone(),
+                None      => {
+                    let e = format!("Couldn't find the spending key for note {} in tx {}",
+                                    hex::encode(selected.nullifier), selected.txid);
+                    error!("{}", e);
+                    return Err(e);
+                }
+            };
 
-        for selected in notes.iter() {
             if let Err(e) = builder.add_sapling_spend(
-                selected.extsk.clone(),
+                extsk,
                 selected.diversifier,
                 selected.note.clone(),
                 selected.witness.clone(),
@@ -1198,21 +2101,62 @@ impl LightWallet {
             }
         }
 
-        // If no Sapling notes were added, add the change address manually. That is,
-        // send the change to our sapling address manually. Note that if a sapling note was spent,
-        // the builder will automatically send change to that address
-        if notes.len() == 0 {
-            builder.send_change_to(
-                ExtendedFullViewingKey::from(&self.extsks.read().unwrap()[0]).fvk.ovk,
-                self.extsks.read().unwrap()[0].default_address().unwrap().1);
-        }
-
         // TODO: We're using the first ovk to encrypt outgoing Txns. Is that Ok?
         let ovk = self.extfvks.read().unwrap()[0].fvk.ovk;
 
-        for (to, value, memo) in tos {
-            // Compute memo if it exists
-            let encoded_memo = memo.map(|s| Memo::from_str(&s).unwrap());
+        match &proposal.change_address {
+            Some(change_address) => match address::RecipientAddress::from_str(change_address,
+                            self.config.hrp_sapling_address(),
+                            self.config.base58_pubkey_address(),
+                            self.config.base58_script_address()) {
+                Some(address::RecipientAddress::Shielded(pa)) => builder.send_change_to(ovk, pa),
+                Some(address::RecipientAddress::Transparent(taddr)) => {
+                    // The builder can only send change to a sapling address, so add the change as
+                    // a regular output. That leaves nothing for the builder to send as change.
+                    if change > 0 {
+                        if let Err(e) = builder.add_transparent_output(&taddr, amount(change)?) {
+                            let e = format!("Error adding change output: {:?}", e);
+                            error!("{}", e);
+                            return Err(e);
+                        }
+                    }
+                },
+                None => {
+                    let e = format!("Invalid change address: {}", change_address);
+                    error!("{}", e);
+                    return Err(e);
+                }
+            },
+            None => {
+                // Proposals that send everything have no change. If no Sapling notes were added, still
+                // point the builder at our sapling address, so it never falls back to a transparent one.
+                // Note that if a sapling note was spent, the builder will use that address
+                if proposal.notes.len() == 0 {
+                    builder.send_change_to(
+                        ExtendedFullViewingKey::from(&self.extsks.read().unwrap()[0]).fvk.ovk,
+                        self.extsks.read().unwrap()[0].default_address().unwrap().1);
+                }
+            }
+        }
+
+        for out in proposal.outputs.iter() {
+            // The addresses were checked when the proposal was created, but it might have been
+            // read from a file since then
+            let to = match address::RecipientAddress::from_str(&out.address,
+                            self.config.hrp_sapling_address(),
+                            self.config.base58_pubkey_address(),
+                            self.config.base58_script_address()) {
+                Some(to) => to,
+                None     => {
+                    let e = format!("Invalid address in the transaction proposal: {}", out.address);
+                    error!("{}", e);
+                    return Err(e);
+                }
+            };
+            let value = amount(out.value)?;
+
+            // The memo was validated when the proposal was created
+            let encoded_memo = out.memo.clone();
             
             println!("{}: Adding output", now() - start_time);
 
@@ -1233,8 +2177,8 @@ impl LightWallet {
 
         println!("{}: Building transaction", now() - start_time);
         let (tx, _) = match builder.build(
-            consensus_branch_id,
-            prover::InMemTxProver::new(spend_params, output_params),
+            proposal.consensus_branch_id,
+            prover,
         ) {
             Ok(res) => res,
             Err(e) => {
@@ -1244,88 +2188,241 @@ impl LightWallet {
             }
         };
         println!("{}: Transaction created", now() - start_time);
-        println!("Transaction ID: {}", tx.txid());
-
-        // Mark notes as spent.
-        {
-            // Mark sapling notes as unconfirmed spent
-            let mut txs = self.txs.write().unwrap();
-            for selected in notes {
-                let mut spent_note = txs.get_mut(&selected.txid).unwrap()
-                                        .notes.iter_mut()
-                                        .find(|nd| &nd.nullifier[..] == &selected.nullifier[..])
-                                        .unwrap();
-                spent_note.unconfirmed_spent = Some(tx.txid());
-            }
 
-            // Mark this utxo as unconfirmed spent
-            for utxo in tinputs {
-                let mut spent_utxo = txs.get_mut(&utxo.txid).unwrap().utxos.iter_mut()
-                                        .find(|u| utxo.txid == u.txid && utxo.output_index == u.output_index)
-                                        .unwrap();
-                spent_utxo.unconfirmed_spent = Some(tx.txid());
-            }
+        if tx.expiry_height != proposal.expiry_height {
+            let e = format!("Transaction expires at {} instead of {}. Please use a longer expiry delta",
+                            tx.expiry_height, proposal.expiry_height);
+            error!("{}", e);
+            return Err(e);
         }
 
+        println!("Transaction ID: {}", tx.txid());
+
         // Return the encoded transaction, so the caller can send it.
         let mut raw_tx = vec![];
         tx.write(&mut raw_tx).unwrap();
         Ok(raw_tx.into_boxed_slice())
     }
-}
 
+    /// Remember a raw transaction that we're sending, so it can be broadcast again until it is
+    /// mined or expires. The notes and utxos it spends are marked as unconfirmed spent, so
+    /// they aren't selected again in the meantime.
+    pub fn add_sent_tx(&self, raw_tx: &[u8]) -> Result<TxId, String> {
+        let tx = match Transaction::read(raw_tx) {
+            Ok(tx) => tx,
+            Err(e) => {
+                let e = format!("Couldn't parse the signed transaction: {}", e);
+                error!("{}", e);
+                return Err(e);
+            }
+        };
+        let txid = tx.txid();
 
-
-#[cfg(test)]
-pub mod tests {
-    use std::convert::TryInto;
-    use std::io::{Error};
-    use rand::{RngCore, rngs::OsRng};
-
-    use ff::{Field, PrimeField, PrimeFieldRepr};
-    use pairing::bls12_381::Bls12;
-    use protobuf::{Message, UnknownFields, CachedSize, RepeatedField};
-    use zcash_client_backend::{encoding::encode_payment_address,
-        proto::compact_formats::{
-            CompactBlock, CompactOutput, CompactSpend, CompactTx,
+        let mut txs = self.txs.write().unwrap();
+        let mut marked = 0;
+
+        // Mark sapling notes as unconfirmed spent
+        for spend in tx.shielded_spends.iter() {
+            for nd in txs.values_mut().flat_map(|wtx| wtx.notes.iter_mut()) {
+                if nd.nullifier == spend.nullifier {
+                    nd.unconfirmed_spent = Some(txid);
+                    marked += 1;
+                }
+            }
         }
-    };
-    use zcash_primitives::{
-        block::BlockHash,
-        jubjub::fs::Fs,
-        note_encryption::{Memo, SaplingNoteEncryption},
-        primitives::{Note, PaymentAddress},
-        legacy::{Script, TransparentAddress,},
-        transaction::{
-            TxId, Transaction, TransactionData,
-            components::{TxOut, TxIn, OutPoint, Amount,},
-            components::amount::DEFAULT_FEE,
-        },
-        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
-        JUBJUB,
-    };
 
-    use sha2::{Sha256, Digest};
+        // Mark utxos as unconfirmed spent
+        for vin in tx.vin.iter() {
+            if let Some(wtx) = txs.get_mut(&TxId{0: vin.prevout.hash}) {
+                for utxo in wtx.utxos.iter_mut().filter(|u| u.output_index == vin.prevout.n as u64) {
+                    utxo.unconfirmed_spent = Some(txid);
+                    marked += 1;
+                }
+            }
+        }
 
-    use super::LightWallet;
-    use crate::LightClientConfig;
-    use secp256k1::{Secp256k1, key::PublicKey, key::SecretKey};
-    use crate::SaplingParams;
+        if marked == 0 {
+            warn!("Transaction {} doesn't spend any of this wallet's notes or utxos", txid);
+        }
 
-    fn get_sapling_params() -> Result<(Vec<u8>, Vec<u8>), Error> {
-        // Read Sapling Params
-        let mut sapling_output = vec![];
-        sapling_output.extend_from_slice(SaplingParams::get("sapling-output.params").unwrap().as_ref());
-        println!("Read output {}", sapling_output.len());
+        let mut sent_txs = self.sent_txs.write().unwrap();
+        if !sent_txs.iter().any(|s| s.txid == txid) {
+            sent_txs.push(SentTx { txid, expiry_height: tx.expiry_height, raw: raw_tx.to_vec() });
+        }
 
-        let mut sapling_spend = vec![];
-        sapling_spend.extend_from_slice(SaplingParams::get("sapling-spend.params").unwrap().as_ref());
-        println!("Read output {}", sapling_spend.len());
+        Ok(txid)
+    }
 
-        Ok((sapling_spend, sapling_output))
+    /// The raw bytes of a transaction we sent that hasn't been mined yet
+    pub fn get_sent_tx_raw(&self, txid: &TxId) -> Option<Vec<u8>> {
+        self.sent_txs.read().unwrap().iter().find(|s| s.txid == *txid).map(|s| s.raw.clone())
     }
 
-    struct FakeCompactBlock {
+    /// The payments in a transaction we sent, worked out from its raw bytes the same way
+    /// `scan_full_tx` does once it is mined, and the value of the outputs that come back to us.
+    pub fn sent_tx_outputs(&self, raw_tx: &[u8]) -> (Vec<OutgoingTxMetadata>, u64) {
+        let tx = match Transaction::read(raw_tx) {
+            Ok(tx) => tx,
+            Err(e) => {
+                warn!("Couldn't read a sent transaction: {}", e);
+                return (vec![], 0);
+            }
+        };
+
+        let wallet_taddrs = self.tkeys.read().unwrap().iter()
+                .map(|sk| self.address_from_sk(sk))
+                .collect::<HashSet<String>>();
+
+        let mut outgoing = vec![];
+        let mut to_self = 0;
+
+        for vout in tx.vout.iter() {
+            match self.address_from_pubkeyhash(vout.script_pubkey.address()) {
+                Some(taddr) if wallet_taddrs.contains(&taddr) => to_self += u64::from(vout.value),
+                Some(taddr) => outgoing.push(OutgoingTxMetadata {
+                    address: taddr, value: vout.value.into(), memo: Memo::default(),
+                }),
+                None => {}
+            }
+        }
+
+        let ovks: Vec<_> = self.extfvks.read().unwrap().iter().map(
+            |extfvk| extfvk.fvk.ovk.clone()
+        ).collect();
+
+        for output in tx.shielded_outputs.iter() {
+            let recovered = ovks.iter().find_map(|ovk| try_sapling_output_recovery(ovk,
+                    &output.cv,
+                    &output.cmu,
+                    &output.ephemeral_key.as_prime_order(&JUBJUB).unwrap(),
+                    &output.enc_ciphertext,
+                    &output.out_ciphertext));
+
+            match recovered {
+                Some((note, payment_address, _)) if self.is_wallet_zaddress(&payment_address) => to_self += note.value,
+                Some((note, payment_address, memo)) => outgoing.push(OutgoingTxMetadata {
+                    address: encode_payment_address(self.config.hrp_sapling_address(), &payment_address),
+                    value: note.value,
+                    memo,
+                }),
+                None => {}
+            }
+        }
+
+        (outgoing, to_self)
+    }
+
+    /// Drop the sent transactions that have been mined or have expired, and return the ones
+    /// that are still pending. The inputs of expired transactions can be spent again.
+    pub fn update_sent_txs(&self) -> Vec<SentTx> {
+        let last_height = cmp::max(self.last_scanned_height(), 0) as u32;
+
+        let mut txs = self.txs.write().unwrap();
+        let mut sent_txs = self.sent_txs.write().unwrap();
+
+        // Transactions that are mined show up in the wallet's txs
+        sent_txs.retain(|sent| !txs.contains_key(&sent.txid));
+
+        // An expiry height of 0 means the transaction never expires
+        let (expired, pending): (Vec<SentTx>, Vec<SentTx>) = sent_txs.drain(..)
+            .partition(|sent| sent.expiry_height > 0 && last_height >= sent.expiry_height);
+
+        let mut change_addresses = self.change_addresses.write().unwrap();
+        for sent in expired {
+            warn!("Sent transaction {} expired at height {}", sent.txid, sent.expiry_height);
+            change_addresses.remove(&sent.txid);
+
+            for wtx in txs.values_mut() {
+                for nd in wtx.notes.iter_mut().filter(|nd| nd.unconfirmed_spent == Some(sent.txid)) {
+                    nd.unconfirmed_spent = None;
+                }
+                for utxo in wtx.utxos.iter_mut().filter(|u| u.unconfirmed_spent == Some(sent.txid)) {
+                    utxo.unconfirmed_spent = None;
+                }
+            }
+        }
+
+        *sent_txs = pending;
+        sent_txs.clone()
+    }
+
+    /// The sent transactions that are still pending, like `update_sent_txs` returns, but without
+    /// dropping the mined or expired ones. Use this to show them without changing the wallet.
+    pub fn sent_txs(&self) -> Vec<SentTx> {
+        let last_height = cmp::max(self.last_scanned_height(), 0) as u32;
+        let txs = self.txs.read().unwrap();
+
+        self.sent_txs.read().unwrap().iter()
+            .filter(|sent| !txs.contains_key(&sent.txid))
+            .filter(|sent| sent.expiry_height == 0 || last_height < sent.expiry_height)
+            .cloned()
+            .collect()
+    }
+}
+
+
+
+#[cfg(test)]
+pub mod tests {
+    use std::convert::TryInto;
+    use std::io::{Error};
+    use rand::{RngCore, rngs::OsRng};
+
+    use ff::{Field, PrimeField, PrimeFieldRepr};
+    use pairing::bls12_381::Bls12;
+    use protobuf::{Message, UnknownFields, CachedSize, RepeatedField};
+    use zcash_client_backend::{encoding::encode_payment_address,
+        proto::compact_formats::{
+            CompactBlock, CompactOutput, CompactSpend, CompactTx,
+        }
+    };
+    use zcash_primitives::{
+        block::BlockHash,
+        jubjub::fs::Fs,
+        note_encryption::{Memo, SaplingNoteEncryption},
+        primitives::{Note, PaymentAddress},
+        legacy::{Script, TransparentAddress,},
+        transaction::{
+            TxId, Transaction, TransactionData,
+            components::{TxOut, TxIn, OutPoint, Amount,},
+            components::amount::DEFAULT_FEE,
+        },
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+        JUBJUB,
+    };
+
+    use sha2::{Sha256, Digest};
+
+    use super::{LightWallet, TxProposal, ChangePolicy, TxAnnotation, BalanceBreakdown, ReceivedByAddress,
+                MAX_PROPOSAL_AGE, MIN_EXPIRY_DELTA, MAX_TX_SIZE};
+    use super::address;
+    use crate::LightClientConfig;
+    use crate::grpcconnector::TlsOptions;
+    use secp256k1::{Secp256k1, key::PublicKey, key::SecretKey};
+    use crate::lightclient::read_sapling_params;
+    use lazy_static::lazy_static;
+    use super::prover::InMemTxProver;
+
+    fn get_sapling_params() -> Result<(Vec<u8>, Vec<u8>), Error> {
+        // Read Sapling Params from the default params directory, or the embedded ones
+        let (sapling_spend, sapling_output) = read_sapling_params(&get_test_config())?;
+        println!("Read spend {}, output {}", sapling_spend.len(), sapling_output.len());
+
+        Ok((sapling_spend, sapling_output))
+    }
+
+    lazy_static! {
+        // Building the prover takes a few seconds, so all the tests share one. The tests that build
+        // transactions fail if the sapling params aren't in the params directory, and weren't
+        // embedded with embed_params.
+        static ref PROVER: InMemTxProver = {
+            let (ss, so) = get_sapling_params()
+                .expect("The sapling params are needed to build transactions. Put them in the params directory, or build with embed_params");
+            InMemTxProver::new(&ss, &so)
+        };
+    }
+
+    struct FakeCompactBlock {
         block: CompactBlock,
     }
 
@@ -1669,7 +2766,7 @@ pub mod tests {
         tx.add_t_output(&pk, AMOUNT1);
         let txid1 = tx.get_tx().txid();
 
-        wallet.scan_full_tx(&tx.get_tx(), 100);  // Pretend it is at height 100
+        wallet.scan_full_tx(&tx.get_tx(), 100, 0);  // Pretend it is at height 100
 
         {
             let txs = wallet.txs.read().unwrap();
@@ -1694,7 +2791,7 @@ pub mod tests {
         tx.add_t_input(txid1, 0);
         let txid2 = tx.get_tx().txid();
 
-        wallet.scan_full_tx(&tx.get_tx(), 101);  // Pretent it is at height 101
+        wallet.scan_full_tx(&tx.get_tx(), 101, 0);  // Pretent it is at height 101
 
         {
             // Make sure the txid was spent
@@ -1741,7 +2838,7 @@ pub mod tests {
         tx.add_t_output(&non_wallet_pk, 25);
         let txid1 = tx.get_tx().txid();
 
-        wallet.scan_full_tx(&tx.get_tx(), 100);  // Pretend it is at height 100
+        wallet.scan_full_tx(&tx.get_tx(), 100, 0);  // Pretend it is at height 100
 
         {
             let txs = wallet.txs.read().unwrap();
@@ -1766,7 +2863,7 @@ pub mod tests {
         tx.add_t_input(txid1, 1);   // Ours was at position 1 in the input tx
         let txid2 = tx.get_tx().txid();
 
-        wallet.scan_full_tx(&tx.get_tx(), 101);  // Pretent it is at height 101
+        wallet.scan_full_tx(&tx.get_tx(), 101, 0);  // Pretent it is at height 101
 
         {
             // Make sure the txid was spent
@@ -1815,7 +2912,7 @@ pub mod tests {
 
         let mut tx = FakeTransaction::new_with_txid(txid1);
         tx.add_t_output(&pk, TAMOUNT1);
-        wallet.scan_full_tx(&tx.get_tx(), 0);  // Height 0
+        wallet.scan_full_tx(&tx.get_tx(), 0, 0);  // Height 0
 
         const AMOUNT2:u64 = 2;
 
@@ -1828,7 +2925,7 @@ pub mod tests {
 
         let mut tx = FakeTransaction::new_with_txid(txid2);
         tx.add_t_input(txid1, 0);
-        wallet.scan_full_tx(&tx.get_tx(), 1);  // Height 1
+        wallet.scan_full_tx(&tx.get_tx(), 1, 0);  // Height 1
 
         // Now, the original note should be spent and there should be a change
         assert_eq!(wallet.zbalance(None), AMOUNT1 - AMOUNT2 ); // The t addr amount is received + spent, so it cancels out
@@ -1929,14 +3026,15 @@ pub mod tests {
 
     }
 
-    fn get_test_config() -> LightClientConfig {
+    pub(crate) fn get_test_config() -> LightClientConfig {
         LightClientConfig {
             server: "0.0.0.0:0".parse().unwrap(),
             chain_name: "test".to_string(),
             sapling_activation_height: 0,
             consensus_branch_id: "000000".to_string(),
             anchor_offset: 0,
-            no_cert_verification: false,
+            tls_options: TlsOptions::default(),
+            params_dir: None,
         }
     }
 
@@ -1983,10 +3081,9 @@ pub mod tests {
         let fee: u64 = DEFAULT_FEE.try_into().unwrap();
 
         let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
-        let (ss, so) =get_sapling_params().unwrap();
 
         // Create a tx and send to address
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                 vec![(&ext_address, AMOUNT_SENT, Some(outgoing_memo.clone()))]).unwrap();
 
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
@@ -2023,7 +3120,7 @@ pub mod tests {
         }
 
         // Now, full scan the Tx, which should populate the Outgoing Meta data
-        wallet.scan_full_tx(&sent_tx, 2);
+        wallet.scan_full_tx(&sent_tx, 2, 0);
 
         // Check Outgoing Metadata
         {
@@ -2051,10 +3148,9 @@ pub mod tests {
         let fee: u64 = DEFAULT_FEE.try_into().unwrap();
 
         let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
-        let (ss, so) =get_sapling_params().unwrap();
 
         // Create a tx and send to address
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                 vec![(&zaddr2, AMOUNT_SENT, Some(outgoing_memo.clone()))]).unwrap();
 
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
@@ -2063,7 +3159,7 @@ pub mod tests {
         let mut cb3 = FakeCompactBlock::new(2, block_hash);
         cb3.add_tx(&sent_tx);
         wallet.scan_block(&cb3.as_bytes()).unwrap();
-        wallet.scan_full_tx(&sent_tx, 2);
+        wallet.scan_full_tx(&sent_tx, 2, 0);
 
         // Because the builder will randomize notes outputted, we need to find
         // which note number is the change and which is the output note (Because this tx
@@ -2108,7 +3204,7 @@ pub mod tests {
         let amount_all:u64 = (AMOUNT1 - AMOUNT_SENT - fee) + (AMOUNT_SENT) - fee;
         let taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
 
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                             vec![(&taddr, amount_all, None)]).unwrap();
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
         let sent_ext_txid = sent_tx.txid();
@@ -2116,7 +3212,7 @@ pub mod tests {
         let mut cb4 = FakeCompactBlock::new(3, cb3.hash());
         cb4.add_tx(&sent_tx);
         wallet.scan_block(&cb4.as_bytes()).unwrap();
-        wallet.scan_full_tx(&sent_tx, 3);
+        wallet.scan_full_tx(&sent_tx, 3, 0);
 
         {
             // Both notes should be spent now.
@@ -2144,13 +3240,12 @@ pub mod tests {
         let (wallet, txid1, block_hash) = get_test_wallet(AMOUNT1);
 
         let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
-        let (ss, so) = get_sapling_params().unwrap();
 
         let taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
         const AMOUNT_SENT: u64 = 30;
         let fee: u64 = DEFAULT_FEE.try_into().unwrap();
 
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                             vec![(&taddr, AMOUNT_SENT, None)]).unwrap();
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
         let sent_txid = sent_tx.txid();
@@ -2187,7 +3282,7 @@ pub mod tests {
         }
 
         // Now, full scan the Tx, which should populate the Outgoing Meta data
-        wallet.scan_full_tx(&sent_tx, 2);
+        wallet.scan_full_tx(&sent_tx, 2, 0);
 
         // Check Outgoing Metadata for t address
         {
@@ -2215,7 +3310,7 @@ pub mod tests {
         tx.add_t_output(&pk, AMOUNT_T);
         let txid_t = tx.get_tx().txid();
 
-        wallet.scan_full_tx(&tx.get_tx(), 1);  // Pretend it is at height 1
+        wallet.scan_full_tx(&tx.get_tx(), 1, 0);  // Pretend it is at height 1
 
         {
             let txs = wallet.txs.read().unwrap();
@@ -2239,10 +3334,9 @@ pub mod tests {
         let fee: u64 = DEFAULT_FEE.try_into().unwrap();
 
         let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
-        let (ss, so) =get_sapling_params().unwrap();
 
         // Create a tx and send to address. This should consume both the UTXO and the note
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                 vec![(&ext_address, AMOUNT_SENT, Some(outgoing_memo.clone()))]).unwrap();
 
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
@@ -2277,7 +3371,7 @@ pub mod tests {
 
         // Scan the compact block and the full Tx
         wallet.scan_block(&cb3.as_bytes()).unwrap();
-        wallet.scan_full_tx(&sent_tx, 2);
+        wallet.scan_full_tx(&sent_tx, 2, 0);
 
         // Now this new Spent tx should be in, so the note should be marked confirmed spent
         {
@@ -2313,10 +3407,9 @@ pub mod tests {
         let fee: u64 = DEFAULT_FEE.try_into().unwrap();
 
         let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
-        let (ss, so) = get_sapling_params().unwrap();
 
         // Create a tx and send to address
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                 vec![(&my_address, AMOUNT1 - fee, Some(memo.clone()))]).unwrap();
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
         let sent_txid = sent_tx.txid();
@@ -2327,7 +3420,7 @@ pub mod tests {
         wallet.scan_block(&cb3.as_bytes()).unwrap();
 
         // And scan the Full Tx to get the memo
-        wallet.scan_full_tx(&sent_tx, 2);
+        wallet.scan_full_tx(&sent_tx, 2, 0);
 
         {
             let txs = wallet.txs.read().unwrap();
@@ -2352,10 +3445,9 @@ pub mod tests {
         let fee: u64 = DEFAULT_FEE.try_into().unwrap();
 
         let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
-        let (ss, so) = get_sapling_params().unwrap();
 
         // Create a tx and send to address
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                 vec![(&taddr, AMOUNT_SENT, None)]).unwrap();
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
         let sent_txid = sent_tx.txid();
@@ -2366,7 +3458,7 @@ pub mod tests {
         wallet.scan_block(&cb3.as_bytes()).unwrap();
 
         // And scan the Full Tx to get the memo
-        wallet.scan_full_tx(&sent_tx, 2);
+        wallet.scan_full_tx(&sent_tx, 2, 0);
 
         {
             let txs = wallet.txs.read().unwrap();
@@ -2412,10 +3504,9 @@ pub mod tests {
         let fee: u64 = DEFAULT_FEE.try_into().unwrap();
 
         let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
-        let (ss, so) = get_sapling_params().unwrap();
 
         // Create a Tx and send to the second t address
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                 vec![(&taddr2, AMOUNT_SENT1, None)]).unwrap();
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
         let sent_txid1 = sent_tx.txid();
@@ -2424,7 +3515,7 @@ pub mod tests {
         let mut cb3 = FakeCompactBlock::new(2, block_hash);
         cb3.add_tx(&sent_tx);
         wallet.scan_block(&cb3.as_bytes()).unwrap();
-        wallet.scan_full_tx(&sent_tx, 2);
+        wallet.scan_full_tx(&sent_tx, 2, 0);
 
         // Check that the send to the second taddr worked
         {
@@ -2459,7 +3550,7 @@ pub mod tests {
         let taddr3 = wallet.add_taddr();
 
         // Create a Tx and send to the second t address
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                 vec![(&taddr3, AMOUNT_SENT2, None)]).unwrap();
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
         let sent_txid2 = sent_tx.txid();
@@ -2468,7 +3559,7 @@ pub mod tests {
         let mut cb4 = FakeCompactBlock::new(3, cb3.hash());
         cb4.add_tx(&sent_tx);
         wallet.scan_block(&cb4.as_bytes()).unwrap();
-        wallet.scan_full_tx(&sent_tx, 3);
+        wallet.scan_full_tx(&sent_tx, 3, 0);
 
         // Quickly check we have it
         {
@@ -2496,7 +3587,7 @@ pub mod tests {
         let outgoing_memo = "Outgoing Memo".to_string();
 
         // Create a tx and send to address
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                 vec![(&ext_address, AMOUNT_SENT_EXT, Some(outgoing_memo.clone()))]).unwrap();
 
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
@@ -2505,7 +3596,7 @@ pub mod tests {
         let mut cb5 = FakeCompactBlock::new(4, cb4.hash());
         cb5.add_tx(&sent_tx);
         wallet.scan_block(&cb5.as_bytes()).unwrap();
-        wallet.scan_full_tx(&sent_tx, 4);
+        wallet.scan_full_tx(&sent_tx, 4, 0);
 
         {
             let txs = wallet.txs.read().unwrap();
@@ -2547,21 +3638,20 @@ pub mod tests {
         let fee: u64 = DEFAULT_FEE.try_into().unwrap();
 
         let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
-        let (ss, so) = get_sapling_params().unwrap();
 
         let tos = vec![ (zaddr2.as_str(), ZAMOUNT2, Some(outgoing_memo2.clone())),
                         (zaddr3.as_str(), ZAMOUNT3, Some(outgoing_memo3.clone())),
                         (taddr2.as_str(), TAMOUNT2, None),
                         (taddr3.as_str(), TAMOUNT3, None) ];
         
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so, tos).unwrap();
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER, tos).unwrap();
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
         let sent_txid = sent_tx.txid();
 
         let mut cb3 = FakeCompactBlock::new(2, block_hash);
         cb3.add_tx(&sent_tx);
         wallet.scan_block(&cb3.as_bytes()).unwrap();
-        wallet.scan_full_tx(&sent_tx, 2);
+        wallet.scan_full_tx(&sent_tx, 2, 0);
 
         // Make sure all the outputs are there!
         {
@@ -2626,14 +3716,14 @@ pub mod tests {
 
         let tos = vec![ (ext_address.as_str(), EXT_ZADDR_AMOUNT, Some(ext_memo.clone())),
                         (ext_taddr.as_str(), ext_taddr_amount, None)];
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so, tos).unwrap();
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER, tos).unwrap();
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
         let sent_txid2 = sent_tx.txid();
 
         let mut cb4 = FakeCompactBlock::new(3, cb3.hash());
         cb4.add_tx(&sent_tx);
         wallet.scan_block(&cb4.as_bytes()).unwrap();
-        wallet.scan_full_tx(&sent_tx, 3);
+        wallet.scan_full_tx(&sent_tx, 3, 0);
 
         // Make sure all the outputs are there!
         {
@@ -2674,20 +3764,1030 @@ pub mod tests {
         let (wallet, _txid1, _block_hash) = get_test_wallet(AMOUNT1);
 
         let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
-        let (ss, so) = get_sapling_params().unwrap();
         let ext_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());       
 
         // Bad address
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                             vec![(&"badaddress", 10, None)]);
         assert!(raw_tx.err().unwrap().contains("Invalid recipient address"));
 
         // Insufficient funds
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                             vec![(&ext_taddr, AMOUNT1 + 10, None)]);
         assert!(raw_tx.err().unwrap().contains("Insufficient verified funds"));
     }
 
+    #[test]
+    fn test_sent_txs() {
+        const AMOUNT1: u64 = 50000;
+        let (wallet, txid1, block_hash) = get_test_wallet(AMOUNT1);
+
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+
+        let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                vec![(&ext_address, 20, None)]).unwrap();
+        let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+        let sent_txid = sent_tx.txid();
+
+        // The raw tx is kept until it is mined
+        let pending = wallet.update_sent_txs();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].txid, sent_txid);
+        assert_eq!(pending[0].expiry_height, sent_tx.expiry_height);
+        assert_eq!(pending[0].raw, raw_tx.to_vec());
+
+        // Who it pays is known before it's mined
+        let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+        let (outgoing, to_self) = wallet.sent_tx_outputs(&pending[0].raw);
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].address, ext_address);
+        assert_eq!(outgoing[0].value, 20);
+        assert_eq!(to_self, AMOUNT1 - 20 - fee);
+
+        // After a restart, the tx is still there, and the note is still pending
+        let mut serialized_data = vec![];
+        wallet.write(&mut serialized_data).unwrap();
+        let wallet2 = LightWallet::read(&serialized_data[..], &get_test_config()).unwrap();
+        assert_eq!(wallet2.update_sent_txs().len(), 1);
+        assert_eq!(wallet2.txs.read().unwrap()[&txid1].notes[0].unconfirmed_spent, Some(sent_txid));
+
+        // Once it is mined, it is dropped
+        let mut cb3 = FakeCompactBlock::new(2, block_hash);
+        cb3.add_tx(&sent_tx);
+        wallet.scan_block(&cb3.as_bytes()).unwrap();
+        assert_eq!(wallet.update_sent_txs().len(), 0);
+        assert_eq!(wallet.txs.read().unwrap()[&txid1].notes[0].spent, Some(sent_txid));
+
+        // If it is never mined, it is dropped when it expires, and the note can be spent again
+        add_blocks(&wallet2, 2, sent_tx.expiry_height as i32 - 2, block_hash).unwrap();
+        assert_eq!(wallet2.update_sent_txs().len(), 1);
+        assert_eq!(wallet2.verified_zbalance(None), 0);
+
+        add_blocks(&wallet2, sent_tx.expiry_height as i32, 1, 
+                   wallet2.blocks.read().unwrap().last().unwrap().hash).unwrap();
+
+        // Looking at the pending txs doesn't release the note, only the update does
+        assert_eq!(wallet2.sent_txs().len(), 0);
+        assert_eq!(wallet2.txs.read().unwrap()[&txid1].notes[0].unconfirmed_spent, Some(sent_txid));
+        assert_eq!(wallet2.update_sent_txs().len(), 0);
+        assert_eq!(wallet2.txs.read().unwrap()[&txid1].notes[0].unconfirmed_spent, None);
+        assert_eq!(wallet2.verified_zbalance(None), AMOUNT1);
+    }
+
+    #[test]
+    fn test_expiry_delta() {
+        const AMOUNT1: u64 = 50000;
+        let (wallet, txid1, block_hash) = get_test_wallet(AMOUNT1);
+
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+
+        let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+
+        // The expiry delta is saved with the wallet
+        assert_eq!(wallet.get_expiry_delta(), 20);
+        assert!(wallet.set_expiry_delta(MIN_EXPIRY_DELTA - 1).is_err());
+        wallet.set_expiry_delta(40).unwrap();
+
+        let mut serialized_data = vec![];
+        wallet.write(&mut serialized_data).unwrap();
+        let wallet2 = LightWallet::read(&serialized_data[..], &get_test_config()).unwrap();
+        assert_eq!(wallet2.get_expiry_delta(), 40);
+
+        // Move the chain forward, so short expiries are possible
+        let block_hash = add_blocks(&wallet, 2, 30, block_hash).unwrap();
+
+        // Override the expiry for one transaction
+        let mut proposal = wallet.create_proposal(vec![(&ext_address, 20, None)]).unwrap();
+        proposal.consensus_branch_id = branch_id;
+        assert_eq!(proposal.target_height, 32);
+        assert_eq!(proposal.expiry_height, 32 + 40);
+
+        proposal.expiry_height = proposal.target_height + MIN_EXPIRY_DELTA;
+        let raw_tx = wallet.sign_proposal(&*PROVER, &proposal).unwrap();
+        assert_eq!(Transaction::read(&raw_tx[..]).unwrap().expiry_height, 32 + MIN_EXPIRY_DELTA);
+
+        proposal.expiry_height = proposal.target_height + MIN_EXPIRY_DELTA - 1;
+        assert!(wallet.sign_proposal(&*PROVER, &proposal).err().unwrap().contains("Invalid expiry height"));
+
+        // A normal send uses the wallet's expiry delta, which is recorded on the tx
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                vec![(&ext_address, 20, None)]).unwrap();
+        let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+        assert_eq!(sent_tx.expiry_height, 32 + 40);
+        assert_eq!(wallet.update_sent_txs()[0].expiry_height, 32 + 40);
+
+        let mut cb = FakeCompactBlock::new(32, block_hash);
+        cb.add_tx(&sent_tx);
+        wallet.scan_block(&cb.as_bytes()).unwrap();
+        wallet.scan_full_tx(&sent_tx, 32, 0);
+
+        let txs = wallet.txs.read().unwrap();
+        assert_eq!(txs[&txid1].notes[0].spent, Some(sent_tx.txid()));
+        assert_eq!(txs[&sent_tx.txid()].expiry_height, 32 + 40);
+    }
+
+    #[test]
+    fn test_block_time() {
+        let mut rng = OsRng;
+        let secp = Secp256k1::new();
+
+        const TIME: u32 = 1_570_000_000;
+        let (wallet, _, block_hash) = get_test_wallet(50000);
+
+        // The time of the block a Tx was mined in is recorded
+        let extfvk = wallet.extfvks.read().unwrap()[0].clone();
+        let mut cb2 = FakeCompactBlock::new(1, block_hash);
+        cb2.block.set_time(TIME);
+        let (_, txid2) = cb2.add_tx_paying(extfvk, 20000);
+        wallet.scan_block(&cb2.as_bytes()).unwrap();
+
+        assert_eq!(wallet.get_block_time(1), Some(TIME));
+        assert_eq!(wallet.txs.read().unwrap()[&txid2].datetime, TIME as u64);
+
+        // Scanning the full Tx doesn't change it
+        let pk = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[0]);
+        let mut tx = FakeTransaction::new(&mut rng);
+        tx.add_t_output(&pk, 10000);
+        let txid_t = tx.get_tx().txid();
+        wallet.scan_full_tx(&tx.get_tx(), 1, TIME as u64 + 100);
+        assert_eq!(wallet.txs.read().unwrap()[&txid_t].datetime, TIME as u64 + 100);
+        wallet.scan_full_tx(&tx.get_tx(), 1, 0);
+        assert_eq!(wallet.txs.read().unwrap()[&txid_t].datetime, TIME as u64 + 100);
+
+        assert_eq!(LightWallet::datetime_str(TIME as u64), Some("2019-10-02T07:06:40Z".to_string()));
+        assert_eq!(LightWallet::datetime_str(0), None);
+
+        // The times are saved with the wallet
+        let mut serialized_data = vec![];
+        wallet.write(&mut serialized_data).expect("Serialize wallet");
+        let wallet2 = LightWallet::read(&serialized_data[..], &get_test_config()).unwrap();
+        assert_eq!(wallet2.get_block_time(1), Some(TIME));
+        assert_eq!(wallet2.txs.read().unwrap()[&txid2].datetime, TIME as u64);
+        assert_eq!(wallet2.txs.read().unwrap()[&txid_t].datetime, TIME as u64 + 100);
+    }
+
+    #[test]
+    fn test_consolidate() {
+        const AMOUNT1: u64 = 50000;
+        let (wallet, txid1, block_hash) = get_test_wallet(AMOUNT1);
+        let zaddr = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &wallet.extfvks.read().unwrap()[0].default_address().unwrap().1);
+        let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+
+        // Only one note, so there is nothing to do
+        assert!(wallet.create_consolidation_proposal(10, None).unwrap().is_none());
+
+        // Receive two more notes
+        let extfvk = wallet.extfvks.read().unwrap()[0].clone();
+        let mut cb2 = FakeCompactBlock::new(1, block_hash);
+        let (_, txid2) = cb2.add_tx_paying(extfvk.clone(), 20000);
+        let (_, txid3) = cb2.add_tx_paying(extfvk.clone(), 30000);
+        wallet.scan_block(&cb2.as_bytes()).unwrap();
+
+        // The address has to be ours
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+        let res = wallet.create_consolidation_proposal(10, Some(ext_address));
+        assert!(res.err().unwrap().contains("not a z address in this wallet"));
+        assert!(wallet.create_consolidation_proposal(1, None).is_err());
+
+        // The two smallest notes are merged
+        let proposal = wallet.create_consolidation_proposal(2, None).unwrap().unwrap();
+        assert_eq!(proposal.notes.len(), 2);
+        assert!(proposal.notes.iter().all(|n| n.txid == txid2 || n.txid == txid3));
+        assert_eq!(proposal.outputs.len(), 1);
+        assert_eq!(proposal.outputs[0].address, zaddr);
+        assert_eq!(proposal.total_value(), 20000 + 30000 - fee);
+        assert_eq!(proposal.change().unwrap(), 0);
+
+        // All of them
+        let proposal = wallet.create_consolidation_proposal(10, None).unwrap().unwrap();
+        assert_eq!(proposal.notes.len(), 3);
+        assert_eq!(proposal.total_value(), AMOUNT1 + 20000 + 30000 - fee);
+
+        // After sending, the notes are pending, so there is nothing left to merge
+        let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+        let mut proposal = proposal;
+        proposal.consensus_branch_id = branch_id;
+        let raw_tx = wallet.send_proposal(&*PROVER, &proposal).unwrap();
+        let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+        assert_eq!(sent_tx.shielded_spends.len(), 3);
+        assert_eq!(sent_tx.shielded_outputs.len(), 1);
+        assert_eq!(wallet.txs.read().unwrap()[&txid1].notes[0].unconfirmed_spent, Some(sent_tx.txid()));
+
+        assert!(wallet.create_consolidation_proposal(10, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_estimated_tx_size() {
+        let (wallet, _, _) = get_test_wallet(50000);
+
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+        let taddr = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+
+        // One spend, one output and the change
+        let proposal = wallet.create_proposal(vec![(&ext_address, 100, None)]).unwrap();
+        let size = wallet.estimated_tx_size(&proposal);
+        assert_eq!(size, 100 + 384 + 2 * 948);
+
+        // Transparent outputs are a lot smaller
+        let proposal = wallet.create_proposal(vec![(&ext_address, 100, None), (&taddr, 100, None)]).unwrap();
+        assert_eq!(wallet.estimated_tx_size(&proposal), size + 34);
+
+        // Too many shielded outputs won't fit in one transaction
+        let tos = (0..110).map(|_| (ext_address.as_str(), 1, None)).collect::<Vec<_>>();
+        let proposal = wallet.create_proposal(tos).unwrap();
+        assert!(wallet.estimated_tx_size(&proposal) > MAX_TX_SIZE);
+    }
+
+    #[test]
+    fn test_change_policy() {
+        let mut rng = OsRng;
+        let secp = Secp256k1::new();
+
+        const AMOUNT_Z: u64 = 50000;
+        const AMOUNT_T: u64 = 40000;
+        const AMOUNT_SENT: u64 = 20000;
+        let (wallet, _, _) = get_test_wallet(AMOUNT_Z);
+
+        let pk = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[0]);
+        let taddr = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+        let zaddr = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &wallet.extfvks.read().unwrap()[0].default_address().unwrap().1);
+
+        let mut tx = FakeTransaction::new(&mut rng);
+        tx.add_t_output(&pk, AMOUNT_T);
+        wallet.scan_full_tx(&tx.get_tx(), 1, 0);
+
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+
+        // By default, the change goes back to the first note's address
+        assert_eq!(wallet.get_change_policy(), ChangePolicy::SameAsInput);
+        let proposal = wallet.create_proposal(vec![(&ext_address, AMOUNT_SENT, None)]).unwrap();
+        assert_eq!(proposal.change_address, Some(zaddr.clone()));
+
+        // If only utxos are spent, the change still goes to the shielded address
+        assert_eq!(wallet.change_address_for(&[]).unwrap(), zaddr);
+
+        // Fresh diversified addresses are ours, but aren't reused
+        wallet.set_change_policy(ChangePolicy::FreshDiversified).unwrap();
+        let change1 = wallet.create_proposal(vec![(&ext_address, AMOUNT_SENT, None)]).unwrap().change_address.unwrap();
+        let change2 = wallet.create_proposal(vec![(&ext_address, AMOUNT_SENT, None)]).unwrap().change_address.unwrap();
+        assert_ne!(change1, zaddr);
+        assert_ne!(change1, change2);
+        for change in vec![change1, change2] {
+            match address::RecipientAddress::from_str(&change,
+                        wallet.config.hrp_sapling_address(),
+                        wallet.config.base58_pubkey_address(),
+                        wallet.config.base58_script_address()) {
+                Some(address::RecipientAddress::Shielded(pa)) => assert!(wallet.is_wallet_zaddress(&pa)),
+                _ => panic!("Change address should be a z address"),
+            }
+        }
+
+        // A fixed change address has to be in the wallet
+        let res = wallet.set_change_policy(ChangePolicy::Fixed(ext_address.clone()));
+        assert!(res.err().unwrap().contains("not in this wallet"));
+        assert_eq!(wallet.get_change_policy(), ChangePolicy::FreshDiversified);
+
+        // Send the change to our t address
+        wallet.set_change_policy(ChangePolicy::Fixed(taddr.clone())).unwrap();
+
+        let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+
+        let mut proposal = wallet.create_proposal(vec![(&ext_address, AMOUNT_SENT, None)]).unwrap();
+        proposal.consensus_branch_id = branch_id;
+        assert_eq!(proposal.change_address, Some(taddr.clone()));
+
+        let change = proposal.change().unwrap();
+        let raw_tx = wallet.send_proposal(&*PROVER, &proposal).unwrap();
+        let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+        let sent_txid = sent_tx.txid();
+
+        // The change is the only t output, and there is no sapling change
+        assert_eq!(sent_tx.shielded_outputs.len(), 1);
+        assert_eq!(sent_tx.vout.len(), 1);
+        assert_eq!(sent_tx.vout[0].value, Amount::from_u64(change).unwrap());
+
+        // When it's mined, the t output is flagged as change, and isn't outgoing
+        wallet.scan_full_tx(&sent_tx, 2, 0);
+        {
+            let txs = wallet.txs.read().unwrap();
+            assert_eq!(txs[&sent_txid].utxos.len(), 1);
+            assert_eq!(txs[&sent_txid].utxos[0].value, change);
+            assert_eq!(txs[&sent_txid].utxos[0].is_change, true);
+
+            assert_eq!(txs[&sent_txid].outgoing_metadata.len(), 1);
+            assert_eq!(txs[&sent_txid].outgoing_metadata[0].address, ext_address);
+        }
+
+        // The policy is saved with the wallet
+        let mut serialized_data = vec![];
+        wallet.write(&mut serialized_data).expect("Serialize wallet");
+        let wallet2 = LightWallet::read(&serialized_data[..], &get_test_config()).unwrap();
+        assert_eq!(wallet2.get_change_policy(), ChangePolicy::Fixed(taddr.clone()));
+        assert_eq!(wallet2.txs.read().unwrap()[&sent_txid].utxos[0].is_change, true);
+
+        // The change doesn't depend on the current policy, so a rescan flags the same outputs
+        wallet2.set_change_policy(ChangePolicy::SameAsInput).unwrap();
+        wallet2.scan_full_tx(&sent_tx, 2, 0);
+        assert_eq!(wallet2.txs.read().unwrap()[&sent_txid].utxos[0].is_change, true);
+    }
+
+    #[test]
+    fn test_fixed_taddr_change_from_notes() {
+        const AMOUNT_Z: u64 = 50000;
+        const AMOUNT_SENT: u64 = 20000;
+        let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+        let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+
+        // The wallet only has a note, so the Tx doesn't spend from the change address
+        let (wallet, _, block_hash) = get_test_wallet(AMOUNT_Z);
+        let taddr = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+
+        wallet.set_change_policy(ChangePolicy::Fixed(taddr.clone())).unwrap();
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                vec![(&ext_address, AMOUNT_SENT, None)]).unwrap();
+        let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+        let sent_txid = sent_tx.txid();
+
+        let mut cb3 = FakeCompactBlock::new(2, block_hash);
+        cb3.add_tx(&sent_tx);
+        wallet.scan_block(&cb3.as_bytes()).unwrap();
+        wallet.scan_full_tx(&sent_tx, 2, 0);
+
+        {
+            let txs = wallet.txs.read().unwrap();
+            assert_eq!(txs[&sent_txid].utxos.len(), 1);
+            assert_eq!(txs[&sent_txid].utxos[0].address, taddr);
+            assert_eq!(txs[&sent_txid].utxos[0].is_change, true);
+            assert_eq!(txs[&sent_txid].total_change(), AMOUNT_Z - AMOUNT_SENT - fee);
+
+            assert_eq!(txs[&sent_txid].outgoing_metadata.len(), 1);
+            assert_eq!(txs[&sent_txid].outgoing_metadata[0].address, ext_address);
+        }
+
+        // The change address is saved with the wallet, so a rescan after a policy change still flags it
+        let mut serialized_data = vec![];
+        wallet.write(&mut serialized_data).expect("Serialize wallet");
+        let wallet2 = LightWallet::read(&serialized_data[..], &get_test_config()).unwrap();
+        wallet2.set_change_policy(ChangePolicy::SameAsInput).unwrap();
+        wallet2.scan_full_tx(&sent_tx, 2, 0);
+        assert_eq!(wallet2.txs.read().unwrap()[&sent_txid].utxos[0].is_change, true);
+    }
+
+    #[test]
+    fn test_tx_accounting() {
+        let mut rng = OsRng;
+        let secp = Secp256k1::new();
+
+        const AMOUNT_Z: u64 = 50000;
+        const AMOUNT_T: u64 = 40000;
+        const AMOUNT_SENT: u64 = 20000;
+        let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+        let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(get_test_config().hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+
+        // z -> z, with sapling change
+        {
+            let (wallet, _, block_hash) = get_test_wallet(AMOUNT_Z);
+            let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                    vec![(&ext_address, AMOUNT_SENT, None)]).unwrap();
+            let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+            let sent_txid = sent_tx.txid();
+
+            let mut cb3 = FakeCompactBlock::new(2, block_hash);
+            cb3.add_tx(&sent_tx);
+            wallet.scan_block(&cb3.as_bytes()).unwrap();
+
+            // Without the full Tx, we don't know where the rest went
+            assert_eq!(wallet.txs.read().unwrap()[&sent_txid].fee(), None);
+            assert_eq!(wallet.txs.read().unwrap()[&sent_txid].net_value(), -((AMOUNT_SENT + fee) as i64));
+
+            wallet.scan_full_tx(&sent_tx, 2, 0);
+            let txs = wallet.txs.read().unwrap();
+            assert_eq!(txs[&sent_txid].total_change(), AMOUNT_Z - AMOUNT_SENT - fee);
+            assert_eq!(txs[&sent_txid].fee(), Some(fee));
+            assert_eq!(txs[&sent_txid].net_value(), -((AMOUNT_SENT + fee) as i64));
+        }
+
+        // z -> one of our own t addresses. Only the fee leaves the wallet.
+        {
+            let (wallet, _, block_hash) = get_test_wallet(AMOUNT_Z);
+            let taddr = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+
+            let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                    vec![(&taddr, AMOUNT_SENT, None)]).unwrap();
+            let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+            let sent_txid = sent_tx.txid();
+
+            let mut cb3 = FakeCompactBlock::new(2, block_hash);
+            cb3.add_tx(&sent_tx);
+            wallet.scan_block(&cb3.as_bytes()).unwrap();
+            wallet.scan_full_tx(&sent_tx, 2, 0);
+
+            let txs = wallet.txs.read().unwrap();
+            assert_eq!(txs[&sent_txid].utxos[0].is_change, false);
+            assert_eq!(txs[&sent_txid].total_change(), AMOUNT_Z - AMOUNT_SENT - fee);
+            assert_eq!(txs[&sent_txid].fee(), Some(fee));
+            assert_eq!(txs[&sent_txid].net_value(), -(fee as i64));
+        }
+
+        // t + z -> z, with the change going to a t address, and then a Tx that someone else
+        // also paid into, which returns more to our change address than we spent
+        {
+            let (wallet, _, block_hash) = get_test_wallet(AMOUNT_Z);
+            let pk = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[0]);
+            let taddr = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+
+            let mut tx = FakeTransaction::new(&mut rng);
+            tx.add_t_output(&pk, AMOUNT_T);
+            wallet.scan_full_tx(&tx.get_tx(), 1, 0);
+
+            wallet.set_change_policy(ChangePolicy::Fixed(taddr.clone())).unwrap();
+            let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                    vec![(&ext_address, AMOUNT_SENT, None)]).unwrap();
+            let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+            let sent_txid = sent_tx.txid();
+
+            let mut cb3 = FakeCompactBlock::new(2, block_hash);
+            cb3.add_tx(&sent_tx);
+            wallet.scan_block(&cb3.as_bytes()).unwrap();
+            wallet.scan_full_tx(&sent_tx, 2, 0);
+
+            let change = AMOUNT_Z + AMOUNT_T - AMOUNT_SENT - fee;
+            let change_index = {
+                let txs = wallet.txs.read().unwrap();
+                assert_eq!(txs[&sent_txid].total_value_spent(), AMOUNT_Z + AMOUNT_T);
+                assert_eq!(txs[&sent_txid].utxos[0].is_change, true);
+                assert_eq!(txs[&sent_txid].total_change(), change);
+                assert_eq!(txs[&sent_txid].fee(), Some(fee));
+                assert_eq!(txs[&sent_txid].net_value(), -((AMOUNT_SENT + fee) as i64));
+
+                txs[&sent_txid].utxos[0].output_index
+            };
+
+            let mut tx = FakeTransaction::new(&mut rng);
+            tx.add_t_input(sent_txid, change_index as u32);
+            tx.add_t_output(&pk, change + AMOUNT_T);
+            let mixed_txid = tx.get_tx().txid();
+            wallet.scan_full_tx(&tx.get_tx(), 3, 0);
+
+            let txs = wallet.txs.read().unwrap();
+            assert_eq!(txs[&mixed_txid].total_value_spent(), change);
+            assert_eq!(txs[&mixed_txid].total_change(), change + AMOUNT_T);
+            assert_eq!(txs[&mixed_txid].fee(), None);
+            assert_eq!(txs[&mixed_txid].net_value(), AMOUNT_T as i64);
+        }
+    }
+
+    #[test]
+    fn test_address_book() {
+        let wallet = LightWallet::new(None, &get_test_config(), 0).unwrap();
+
+        let taddr = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+
+        wallet.set_label(&taddr, "Savings").unwrap();
+        wallet.set_label(&ext_address, " Alice ").unwrap();
+        assert_eq!(wallet.get_label(&ext_address), Some("Alice".to_string()));
+        assert!(wallet.is_wallet_address(&taddr));
+        assert!(!wallet.is_wallet_address(&ext_address));
+
+        // Labels are unique, and can't be empty or an address
+        assert!(wallet.set_label(&ext_address, "Savings").err().unwrap().contains("already used"));
+        assert!(wallet.set_label(&ext_address, "  ").is_err());
+        assert!(wallet.set_label(&ext_address, &taddr).is_err());
+        assert!(wallet.set_label("notanaddress", "Bob").err().unwrap().contains("Invalid address"));
+
+        // Relabelling replaces the old label
+        wallet.set_label(&ext_address, "Alice B").unwrap();
+        assert_eq!(wallet.resolve_address("Alice B"), ext_address);
+        assert_eq!(wallet.resolve_address("Alice"), "Alice");
+        assert_eq!(wallet.get_address_book(), vec![(ext_address.clone(), "Alice B".to_string()),
+                                                   (taddr.clone(), "Savings".to_string())]);
+
+        // The address book is saved with the wallet
+        let mut serialized_data = vec![];
+        wallet.write(&mut serialized_data).expect("Serialize wallet");
+        let wallet2 = LightWallet::read(&serialized_data[..], &get_test_config()).unwrap();
+        assert_eq!(wallet2.get_address_book(), wallet.get_address_book());
+
+        // Labels can be removed by the label or the address
+        assert_eq!(wallet.remove_label("Savings"), Ok(taddr.clone()));
+        assert_eq!(wallet.remove_label(&ext_address), Ok(ext_address.clone()));
+        assert!(wallet.remove_label("Savings").is_err());
+        assert!(wallet.get_address_book().is_empty());
+    }
+
+    #[test]
+    fn test_annotations() {
+        const AMOUNT: u64 = 50000;
+        let (wallet, txid1, _) = get_test_wallet(AMOUNT);
+
+        let txid_str = format!("{}", txid1);
+        assert_eq!(LightWallet::parse_txid(&txid_str), Ok(txid1));
+        assert!(LightWallet::parse_txid("abcd").is_err());
+
+        wallet.set_annotation(&txid1, "Invoice 117").unwrap();
+        wallet.add_tag(&txid1, "salary").unwrap();
+        wallet.add_tag(&txid1, "salary").unwrap();
+        wallet.add_tag(&txid1, " 2019 ").unwrap();
+        assert!(wallet.add_tag(&txid1, "a,b").is_err());
+        assert_eq!(wallet.get_annotation(&txid1), Some(TxAnnotation {
+            text: Some("Invoice 117".to_string()),
+            tags: vec!["salary".to_string(), "2019".to_string()],
+        }));
+
+        // Only our own transactions can be annotated
+        let other = TxId{0: [7u8; 32]};
+        assert!(wallet.set_annotation(&other, "Not ours").err().unwrap().contains("not in this wallet"));
+
+        // Annotations are saved with the wallet, and kept when the txs are cleared for a rescan
+        let mut serialized_data = vec![];
+        wallet.write(&mut serialized_data).expect("Serialize wallet");
+        let wallet2 = LightWallet::read(&serialized_data[..], &get_test_config()).unwrap();
+        assert_eq!(wallet2.get_annotation(&txid1), wallet.get_annotation(&txid1));
+
+        wallet.clear_blocks();
+        assert_eq!(wallet.get_annotation(&txid1).unwrap().tags.len(), 2);
+
+        // Removing everything removes the annotation
+        wallet2.set_annotation(&txid1, "").unwrap();
+        wallet2.remove_tag(&txid1, "salary").unwrap();
+        assert!(wallet2.remove_tag(&txid1, "salary").is_err());
+        wallet2.remove_tag(&txid1, "2019").unwrap();
+        assert_eq!(wallet2.get_annotation(&txid1), None);
+    }
+
+    #[test]
+    fn test_balance_breakdown() {
+        let mut rng = OsRng;
+        let secp = Secp256k1::new();
+
+        const AMOUNT_OLD: u64 = 50000;
+        const AMOUNT_NEW: u64 = 30000;
+        const AMOUNT_T: u64 = 20000;
+
+        let mut config = get_test_config();
+        config.anchor_offset = 4;
+        let wallet = LightWallet::new(None, &config, 0).unwrap();
+
+        let extfvk = wallet.extfvks.read().unwrap()[0].clone();
+        let zaddr = encode_payment_address(config.hrp_sapling_address(), &extfvk.default_address().unwrap().1);
+        let pk = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[0]);
+        let taddr = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+
+        // Nothing has been scanned yet
+        assert_eq!(wallet.balance_breakdown(None), BalanceBreakdown::default());
+
+        // Scan up to block 9, with notes in blocks 2 and 8. The anchor is at block 10 - 4 = 6.
+        let mut block_hash = BlockHash([0; 32]);
+        let mut txid_old = None;
+        let mut txid_new = None;
+        for height in 0..10 {
+            let mut cb = FakeCompactBlock::new(height, block_hash);
+            if height == 2 {
+                txid_old = Some(cb.add_tx_paying(extfvk.clone(), AMOUNT_OLD).1);
+            }
+            if height == 8 {
+                txid_new = Some(cb.add_tx_paying(extfvk.clone(), AMOUNT_NEW).1);
+            }
+            wallet.scan_block(&cb.as_bytes()).unwrap();
+            block_hash = cb.hash();
+        }
+
+        let mut tx = FakeTransaction::new(&mut rng);
+        tx.add_t_output(&pk, AMOUNT_T);
+        wallet.scan_full_tx(&tx.get_tx(), 3, 0);
+
+        let mut tx = FakeTransaction::new(&mut rng);
+        tx.add_t_output(&pk, AMOUNT_T + 1);
+        let txid_t_new = tx.get_tx().txid();
+        wallet.scan_full_tx(&tx.get_tx(), 9, 0);
+
+        let breakdown = wallet.balance_breakdown(None);
+        assert_eq!(breakdown.spendable, AMOUNT_OLD);
+        assert_eq!(breakdown.spendable, wallet.verified_zbalance(None));
+        assert_eq!(breakdown.unconfirmed, AMOUNT_NEW);
+        assert_eq!(breakdown.blocks_until_spendable, 2);
+        assert_eq!(breakdown.confirmed_transparent, AMOUNT_T);
+        assert_eq!(breakdown.unconfirmed_transparent, AMOUNT_T + 1);
+
+        // Change and funds locked in unconfirmed sends are kept apart
+        {
+            let mut txs = wallet.txs.write().unwrap();
+            txs.get_mut(&txid_new.unwrap()).unwrap().notes[0].is_change = true;
+            txs.get_mut(&txid_old.unwrap()).unwrap().notes[0].unconfirmed_spent = Some(txid_t_new);
+            txs.get_mut(&txid_t_new).unwrap().utxos[0].unconfirmed_spent = Some(txid_t_new);
+        }
+
+        let breakdown = wallet.balance_breakdown(Some(zaddr.clone()));
+        assert_eq!(breakdown.spendable, 0);
+        assert_eq!(breakdown.unconfirmed, 0);
+        assert_eq!(breakdown.unconfirmed_change, AMOUNT_NEW);
+        assert_eq!(breakdown.pending_spent, AMOUNT_OLD);
+        assert_eq!(breakdown.confirmed_transparent, 0);
+        assert_eq!(breakdown.spendable + breakdown.unconfirmed + breakdown.unconfirmed_change + breakdown.pending_spent,
+                   wallet.zbalance(Some(zaddr)));
+
+        let breakdown = wallet.balance_breakdown(Some(taddr.clone()));
+        assert_eq!(breakdown.unconfirmed_change, 0);
+        assert_eq!(breakdown.confirmed_transparent, AMOUNT_T);
+        assert_eq!(breakdown.unconfirmed_transparent, 0);
+        assert_eq!(breakdown.pending_spent_transparent, AMOUNT_T + 1);
+        assert_eq!(breakdown.confirmed_transparent + breakdown.pending_spent_transparent, wallet.tbalance(Some(taddr)));
+    }
+
+    #[test]
+    fn test_minconf() {
+        let mut rng = OsRng;
+        let secp = Secp256k1::new();
+
+        const AMOUNT_Z: u64 = 50000;
+        const AMOUNT_T: u64 = 40000;
+        let (wallet, txid1, block_hash) = get_test_wallet(AMOUNT_Z);
+
+        // The note is in block 0, and block 1 has been scanned since
+        assert_eq!(wallet.confirmations(wallet.txs.read().unwrap()[&txid1].block), 2);
+        assert_eq!(wallet.confirmations(2), 0);
+
+        let pk = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[0]);
+        let taddr = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+        let mut tx = FakeTransaction::new(&mut rng);
+        tx.add_t_output(&pk, AMOUNT_T);
+        wallet.scan_full_tx(&tx.get_tx(), 1, 0);
+
+        assert_eq!(wallet.zbalance_minconf(None, 2), AMOUNT_Z);
+        assert_eq!(wallet.zbalance_minconf(None, 3), 0);
+        assert_eq!(wallet.tbalance_minconf(Some(taddr.clone()), 1), AMOUNT_T);
+        assert_eq!(wallet.tbalance_minconf(Some(taddr.clone()), 2), 0);
+
+        // Another block confirms both of them once more
+        let cb3 = FakeCompactBlock::new(2, block_hash);
+        wallet.scan_block(&cb3.as_bytes()).unwrap();
+        assert_eq!(wallet.zbalance_minconf(None, 3), AMOUNT_Z);
+        assert_eq!(wallet.tbalance_minconf(Some(taddr), 2), AMOUNT_T);
+        assert_eq!(wallet.zbalance_minconf(None, 0), wallet.zbalance(None));
+    }
+
+    #[test]
+    fn test_reply_to() {
+        let address = "ztestsapling1x65nq4dgp0qfywgxcwk9n0fvm4fysmapgr2q00p85ju252h6l7mmxu2jg9cqqhtvzd69jwhgv8d";
+
+        let memo = LightWallet::memo_with_reply_to("Hi!\nHow are you?", address);
+        assert_eq!(LightWallet::parse_reply_to(&memo), ("Hi!\nHow are you?".to_string(), Some(address.to_string())));
+
+        // Trailing whitespace, as some wallets pad the memo
+        assert_eq!(LightWallet::parse_reply_to(&format!("Hi\nReply-To:\n{}\n ", address)),
+                    ("Hi".to_string(), Some(address.to_string())));
+
+        // No reply-to
+        assert_eq!(LightWallet::parse_reply_to("Hi\nthere"), ("Hi\nthere".to_string(), None));
+        assert_eq!(LightWallet::parse_reply_to("Reply-To:\n"), ("Reply-To:\n".to_string(), None));
+        assert_eq!(LightWallet::parse_reply_to(address), (address.to_string(), None));
+    }
+
+    #[test]
+    fn test_received_by_address() {
+        let mut rng = OsRng;
+        let secp = Secp256k1::new();
+
+        const AMOUNT_Z: u64 = 50000;
+        const AMOUNT_T: u64 = 40000;
+        const AMOUNT_SENT: u64 = 20000;
+        let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+        let (wallet, _, block_hash) = get_test_wallet(AMOUNT_Z);
+
+        let zaddr = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &wallet.extfvks.read().unwrap()[0].default_address().unwrap().1);
+        let pk = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[0]);
+        let taddr = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+
+        let mut tx = FakeTransaction::new(&mut rng);
+        tx.add_t_output(&pk, AMOUNT_T);
+        wallet.scan_full_tx(&tx.get_tx(), 1, 0);
+
+        assert_eq!(wallet.received_by_address(0), vec![
+            ReceivedByAddress{ address: zaddr.clone(), received: AMOUNT_Z, count: 1, unspent: AMOUNT_Z },
+            ReceivedByAddress{ address: taddr.clone(), received: AMOUNT_T, count: 1, unspent: AMOUNT_T },
+        ]);
+
+        // Spend everything, with the change going to a new diversified address
+        wallet.set_change_policy(ChangePolicy::FreshDiversified).unwrap();
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+        let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                vec![(&ext_address, AMOUNT_SENT, None)]).unwrap();
+        let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+
+        let mut cb3 = FakeCompactBlock::new(2, block_hash);
+        cb3.add_tx(&sent_tx);
+        wallet.scan_block(&cb3.as_bytes()).unwrap();
+        wallet.scan_full_tx(&sent_tx, 2, 0);
+
+        // The change isn't counted as received, but it is unspent
+        let received = wallet.received_by_address(0);
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[0], ReceivedByAddress{ address: zaddr.clone(), received: AMOUNT_Z, count: 1, unspent: 0 });
+        assert_eq!(received[1], ReceivedByAddress{ address: taddr.clone(), received: AMOUNT_T, count: 1, unspent: 0 });
+        assert_ne!(received[2].address, zaddr);
+        assert_eq!(received[2].received, 0);
+        assert_eq!(received[2].unspent, AMOUNT_Z + AMOUNT_T - AMOUNT_SENT - fee);
+
+        // With minconf, the change doesn't have enough confirmations yet
+        assert_eq!(wallet.received_by_address(2).len(), 2);
+    }
+
+    #[test]
+    fn test_send_all() {
+        let mut rng = OsRng;
+        let secp = Secp256k1::new();
+
+        const AMOUNT_Z: u64 = 50000;
+        const AMOUNT_T: u64 = 40000;
+        let (wallet, txid1, _) = get_test_wallet(AMOUNT_Z);
+
+        let pk = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[0]);
+        let taddr = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+        let zaddr = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &wallet.extfvks.read().unwrap()[0].default_address().unwrap().1);
+
+        let mut tx = FakeTransaction::new(&mut rng);
+        tx.add_t_output(&pk, AMOUNT_T);
+        let txid_t = tx.get_tx().txid();
+        wallet.scan_full_tx(&tx.get_tx(), 1, 0);
+
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+        let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+
+        // Everything is spent, with no change
+        let proposal = wallet.create_sweep_proposal(&ext_address, Some("All".to_string()), None).unwrap();
+        assert_eq!(proposal.notes.len(), 1);
+        assert_eq!(proposal.utxos.len(), 1);
+        assert_eq!(proposal.total_value(), AMOUNT_Z + AMOUNT_T - fee);
+        assert_eq!(proposal.change().unwrap(), 0);
+
+        // Only from one address
+        let proposal = wallet.create_sweep_proposal(&ext_address, None, Some(taddr.clone())).unwrap();
+        assert_eq!(proposal.notes.len(), 0);
+        assert_eq!(proposal.utxos.len(), 1);
+        assert_eq!(proposal.total_value(), AMOUNT_T - fee);
+
+        let proposal = wallet.create_sweep_proposal(&ext_address, None, Some(zaddr.clone())).unwrap();
+        assert_eq!(proposal.notes.len(), 1);
+        assert_eq!(proposal.utxos.len(), 0);
+        assert_eq!(proposal.total_value(), AMOUNT_Z - fee);
+
+        // Bad memos are still caught
+        let res = wallet.create_sweep_proposal(&taddr, Some("Memo".to_string()), None);
+        assert!(res.err().unwrap().contains("Can't send a memo"));
+
+        // Build it, and make sure there is no change output
+        let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+
+        let mut proposal = wallet.create_sweep_proposal(&ext_address, None, None).unwrap();
+        proposal.consensus_branch_id = branch_id;
+        let raw_tx = wallet.send_proposal(&*PROVER, &proposal).unwrap();
+        let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+
+        assert_eq!(sent_tx.shielded_spends.len(), 1);
+        assert_eq!(sent_tx.vin.len(), 1);
+        assert_eq!(sent_tx.shielded_outputs.len(), 1);
+        assert_eq!(sent_tx.vout.len(), 0);
+
+        {
+            let txs = wallet.txs.read().unwrap();
+            assert_eq!(txs[&txid1].notes[0].unconfirmed_spent, Some(sent_tx.txid()));
+            assert_eq!(txs[&txid_t].utxos[0].unconfirmed_spent, Some(sent_tx.txid()));
+        }
+
+        // Nothing is left to send
+        let res = wallet.create_sweep_proposal(&ext_address, None, None);
+        assert!(res.err().unwrap().contains("Insufficient verified funds"));
+    }
+
+    #[test]
+    fn test_parse_memos() {
+        // None of these need a prover
+        assert!(LightWallet::parse_memo(&"a".repeat(513)).err().unwrap().contains("Memo is too long"));
+        assert!(LightWallet::parse_memo(&format!("0xff{}", "00".repeat(512))).err().unwrap().contains("Memo is too long"));
+        assert!(LightWallet::parse_memo("0xzz").err().unwrap().contains("Invalid hex memo"));
+        assert!(LightWallet::parse_memo("0x").err().unwrap().contains("Hex memo is empty"));
+        assert!(LightWallet::parse_memo("0x80ff").err().unwrap().contains("valid UTF-8"));
+
+        // Hex memos are shown as text if they are text, and as hex if they are arbitrary data
+        let memo = LightWallet::parse_memo("0x48656c6c6f").unwrap();
+        assert_eq!(LightWallet::memo_str(&Some(memo.clone())), Some("Hello".to_string()));
+        assert_eq!(LightWallet::memo_hex(&Some(memo)), None);
+
+        let memo = LightWallet::parse_memo("0xff0102").unwrap();
+        assert_eq!(LightWallet::memo_str(&Some(memo.clone())), None);
+        assert_eq!(LightWallet::memo_hex(&Some(memo)), Some("ff0102".to_string()));
+
+        // Empty memos are neither
+        assert!(LightWallet::is_empty_memo(&Memo::default()));
+        assert_eq!(LightWallet::memo_str(&Some(Memo::default())), None);
+        assert_eq!(LightWallet::memo_hex(&Some(Memo::default())), None);
+    }
+
+    #[test]
+    fn test_bad_memos() {
+        const AMOUNT1: u64 = 50000;
+        let (wallet, txid1, block_hash) = get_test_wallet(AMOUNT1);
+
+        let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+        let ext_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+
+        // Memo too long
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                            vec![(&ext_address, 10, Some("a".repeat(513)))]);
+        assert!(raw_tx.err().unwrap().contains("Memo is too long"));
+
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                            vec![(&ext_address, 10, Some(format!("0xff{}", "00".repeat(512))))]);
+        assert!(raw_tx.err().unwrap().contains("Memo is too long"));
+
+        // Memo to a taddr
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                            vec![(&ext_taddr, 10, Some("Memo".to_string()))]);
+        assert!(raw_tx.err().unwrap().contains("Can't send a memo"));
+
+        // Bad hex memos
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                            vec![(&ext_address, 10, Some("0xzz".to_string()))]);
+        assert!(raw_tx.err().unwrap().contains("Invalid hex memo"));
+
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                            vec![(&ext_address, 10, Some("0x80ff".to_string()))]);
+        assert!(raw_tx.err().unwrap().contains("valid UTF-8"));
+
+        // None of these should have spent the note
+        assert_eq!(wallet.txs.read().unwrap()[&txid1].notes[0].unconfirmed_spent, None);
+
+        // A binary memo can be sent
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
+                                            vec![(&ext_address, 10, Some("0xff0102".to_string()))]).unwrap();
+        let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+
+        let mut cb3 = FakeCompactBlock::new(2, block_hash);
+        cb3.add_tx(&sent_tx);
+        wallet.scan_block(&cb3.as_bytes()).unwrap();
+        wallet.scan_full_tx(&sent_tx, 2, 0);
+
+        let txs = wallet.txs.read().unwrap();
+        let outgoing = &txs[&sent_tx.txid()].outgoing_metadata[0];
+        assert_eq!(LightWallet::memo_hex(&Some(outgoing.memo.clone())), Some("ff0102".to_string()));
+    }
+
+    #[test]
+    fn test_send_proposal() {
+        const AMOUNT1: u64 = 50000;
+        let (wallet, txid1, block_hash) = get_test_wallet(AMOUNT1);
+
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+
+        const AMOUNT_SENT: u64 = 20;
+        let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+
+        let proposal = wallet.create_proposal(
+                            vec![(&ext_address, AMOUNT_SENT, Some("Outgoing Memo".to_string()))]).unwrap();
+
+        assert_eq!(proposal.target_height, 2);
+        assert_eq!(proposal.notes.len(), 1);
+        assert_eq!(proposal.notes[0].txid, txid1);
+        assert_eq!(proposal.utxos.len(), 0);
+        assert_eq!(proposal.outputs.len(), 1);
+        assert_eq!(proposal.outputs[0].address, ext_address);
+        assert_eq!(proposal.total_value(), AMOUNT_SENT);
+        assert_eq!(proposal.selected_value(), AMOUNT1);
+        assert_eq!(proposal.fee, fee);
+        assert_eq!(proposal.change().unwrap(), AMOUNT1 - AMOUNT_SENT - fee);
+
+        // Creating a proposal doesn't spend anything
+        {
+            let txs = wallet.txs.read().unwrap();
+            assert_eq!(txs[&txid1].notes[0].spent, None);
+            assert_eq!(txs[&txid1].notes[0].unconfirmed_spent, None);
+        }
+        assert_eq!(wallet.verified_zbalance(None), AMOUNT1);
+
+        // Proposals fail the same way sends do
+        let res = wallet.create_proposal(vec![(&"badaddress", 10, None)]);
+        assert!(res.err().unwrap().contains("Invalid recipient address"));
+
+        let res = wallet.create_proposal(vec![(&ext_address, AMOUNT1, None)]);
+        assert!(res.err().unwrap().contains("Insufficient verified funds"));
+
+        // If the note is spent after the proposal was made, the proposal can't be sent anymore
+        {
+            let mut txs = wallet.txs.write().unwrap();
+            txs.get_mut(&txid1).unwrap().notes[0].unconfirmed_spent = Some(txid1);
+        }
+        let res = wallet.send_proposal(&*PROVER, &proposal);
+        assert!(res.err().unwrap().contains("already been spent"));
+        {
+            let mut txs = wallet.txs.write().unwrap();
+            txs.get_mut(&txid1).unwrap().notes[0].unconfirmed_spent = None;
+        }
+
+        // Neither can a proposal that is too old
+        add_blocks(&wallet, 2, MAX_PROPOSAL_AGE as i32 + 1, block_hash).unwrap();
+        let res = wallet.send_proposal(&*PROVER, &proposal);
+        assert!(res.err().unwrap().contains("too old"));
+    }
+
+    #[test]
+    fn test_offline_signing() {
+        const AMOUNT1: u64 = 50000;
+        let (wallet, txid1, _) = get_test_wallet(AMOUNT1);
+
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                            &fvk.default_address().unwrap().1);
+
+        const AMOUNT_SENT: u64 = 20;
+        let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+
+        // The "offline" wallet has the same keys, but doesn't need any of the blocks
+        let mut serialized_wallet = vec![];
+        wallet.write(&mut serialized_wallet).unwrap();
+        let offline_wallet = LightWallet::read(&serialized_wallet[..], &get_test_config()).unwrap();
+        offline_wallet.clear_blocks();
+
+        let mut proposal = wallet.create_proposal(
+                            vec![(&ext_address, AMOUNT_SENT, Some("Outgoing Memo".to_string()))]).unwrap();
+        proposal.consensus_branch_id = branch_id;
+
+        // Round trip the proposal, as if it was sent to the offline machine
+        let mut serialized_proposal = vec![];
+        proposal.write(&mut serialized_proposal).unwrap();
+        let read_proposal = TxProposal::read(&serialized_proposal[..]).unwrap();
+
+        assert_eq!(read_proposal.target_height, proposal.target_height);
+        assert_eq!(read_proposal.consensus_branch_id, branch_id);
+        assert_eq!(read_proposal.notes.len(), 1);
+        assert_eq!(read_proposal.notes[0].txid, txid1);
+        assert_eq!(read_proposal.notes[0].nullifier, proposal.notes[0].nullifier);
+        assert_eq!(read_proposal.notes[0].note.value, AMOUNT1);
+        assert_eq!(read_proposal.anchor(), proposal.anchor());
+        assert_eq!(read_proposal.outputs[0].address, ext_address);
+        assert_eq!(LightWallet::memo_str(&read_proposal.outputs[0].memo), Some("Outgoing Memo".to_string()));
+        assert_eq!(read_proposal.fee, proposal.fee);
+
+        // Sign on the offline wallet
+        let raw_tx = offline_wallet.sign_proposal(&*PROVER, &read_proposal).unwrap();
+        let sent_txid = Transaction::read(&raw_tx[..]).unwrap().txid();
+
+        // Signing doesn't touch the online wallet until the signed tx is sent
+        assert_eq!(wallet.txs.read().unwrap()[&txid1].notes[0].unconfirmed_spent, None);
+
+        assert_eq!(wallet.add_sent_tx(&raw_tx).unwrap(), sent_txid);
+        assert_eq!(wallet.txs.read().unwrap()[&txid1].notes[0].unconfirmed_spent, Some(sent_txid));
+        assert_eq!(wallet.verified_zbalance(None), 0);
+
+        // A wallet without the spending key can't sign
+        let other_wallet = LightWallet::new(None, &get_test_config(), 0).unwrap();
+        let res = other_wallet.sign_proposal(&*PROVER, &read_proposal);
+        assert!(res.err().unwrap().contains("Couldn't find the spending key"));
+
+        // A proposal that sends more than it selects is rejected, instead of underflowing the change
+        let mut bad_proposal = TxProposal::read(&serialized_proposal[..]).unwrap();
+        bad_proposal.outputs[0].value = AMOUNT1;
+        assert!(bad_proposal.change().is_err());
+        let res = offline_wallet.sign_proposal(&*PROVER, &bad_proposal);
+        assert!(res.err().unwrap().contains("don't cover"));
+    }
+
     #[test]
     #[should_panic]
     fn test_bad_params() {
@@ -2696,7 +4796,8 @@ pub mod tests {
 
         let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
         // Bad params
-        let _ = wallet.send_to_address(branch_id, &[], &[],
+        let prover = InMemTxProver::new(&[], &[]);
+        let _ = wallet.send_to_address(branch_id, &prover,
                                 vec![(&ext_taddr, 10, None)]);
     }
 
@@ -2798,12 +4899,11 @@ pub mod tests {
         let taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
 
         let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
-        let (ss, so) = get_sapling_params().unwrap();
 
         // Create a tx and send to address
         const AMOUNT_SENT: u64 = 30000;
         let fee: u64 = DEFAULT_FEE.try_into().unwrap();
-        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+        let raw_tx = wallet.send_to_address(branch_id, &*PROVER,
                                 vec![(&taddr, AMOUNT_SENT, None)]).unwrap();
 
         let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
@@ -2811,7 +4911,7 @@ pub mod tests {
         let mut cb3 = FakeCompactBlock::new(7, blk6_hash);
         cb3.add_tx(&sent_tx);
         wallet.scan_block(&cb3.as_bytes()).unwrap();
-        wallet.scan_full_tx(&sent_tx, 7);
+        wallet.scan_full_tx(&sent_tx, 7, 0);
 
         // Make sure the Tx is in.
         {
@@ -2859,7 +4959,8 @@ pub mod tests {
             sapling_activation_height: 0,
             consensus_branch_id: "000000".to_string(),
             anchor_offset: 1,
-            no_cert_verification: false,
+            tls_options: TlsOptions::default(),
+            params_dir: None,
         };
 
         let seed_phrase = Some("chimney better bulb horror rebuild whisper improve intact letter giraffe brave rib appear bulk aim burst snap salt hill sad merge tennis phrase raise".to_string());
@@ -6,7 +6,7 @@ use ff::{PrimeField, PrimeFieldRepr};
 
 use zcash_primitives::{
     block::BlockHash,
-    merkle_tree::{CommitmentTree, IncrementalWitness},
+    merkle_tree::{CommitmentTree, IncrementalWitness, Hashable},
     sapling::Node,
     serialize::{Vector, Optional},
     transaction::{
@@ -22,13 +22,15 @@ use zcash_primitives::{
         fs::{Fs, FsRepr},
     }
 };
-use zcash_primitives::zip32::ExtendedSpendingKey;
+
+use super::utils;
 
 
 pub struct BlockData {
     pub height: i32,
     pub hash: BlockHash,
     pub tree: CommitmentTree<Node>,
+    pub time: u32,      // Block timestamp, 0 if it isn't known
 }
 
 impl BlockData {
@@ -40,16 +42,22 @@ impl BlockData {
 
         let tree = CommitmentTree::<Node>::read(&mut reader)?;
 
+        // The end tag is 12 if the block time comes after it
         let endtag = reader.read_u64::<LittleEndian>()?;
-        if endtag != 11 {
-            println!("End tag for blockdata {}", endtag);
-        }
-
+        let time = match endtag {
+            11 => 0,
+            12 => reader.read_u32::<LittleEndian>()?,
+            _  => {
+                println!("End tag for blockdata {}", endtag);
+                0
+            }
+        };
 
         Ok(BlockData{
             height,
             hash: BlockHash{ 0: hash_bytes },
-            tree
+            tree,
+            time,
         })
     }
 
@@ -57,7 +65,8 @@ impl BlockData {
         writer.write_i32::<LittleEndian>(self.height)?;
         writer.write_all(&self.hash.0)?;
         self.tree.write(&mut writer)?;
-        writer.write_u64::<LittleEndian>(11)
+        writer.write_u64::<LittleEndian>(12)?;
+        writer.write_u32::<LittleEndian>(self.time)
     }
 }
 
@@ -248,26 +257,40 @@ pub struct Utxo {
 
     pub spent: Option<TxId>,             // If this utxo was confirmed spent
     pub unconfirmed_spent: Option<TxId>, // If this utxo was spent in a send, but has not yet been confirmed.
+
+    pub is_change: bool,                 // If this utxo is change from a tx that spent our funds
 }
 
 impl Utxo {
     pub fn serialized_version() -> u64 {
-        return 1;
+        return 2;
     }
 
     pub fn to_outpoint(&self) -> OutPoint {
         OutPoint { hash: self.txid.0, n: self.output_index as u32 }
     }
 
+    // Utxos are also read from transaction proposals, so bad data is an error instead of a panic
     pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
         let version = reader.read_u64::<LittleEndian>()?;
-        assert_eq!(version, Utxo::serialized_version());
+        if version > Utxo::serialized_version() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        format!("Unsupported utxo version {}", version)));
+        }
 
         let address_len = reader.read_i32::<LittleEndian>()?;
+        if address_len < 0 || address_len as u64 > MAX_ADDRESS_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        format!("Invalid utxo address length {}", address_len)));
+        }
         let mut address_bytes = vec![0; address_len as usize];
         reader.read_exact(&mut address_bytes)?;
-        let address = String::from_utf8(address_bytes).unwrap();
-        assert_eq!(address.chars().take(1).collect::<Vec<char>>()[0], 't');
+        let address = String::from_utf8(address_bytes)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        if !address.starts_with('t') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        format!("Utxo address {} isn't a transparent address", address)));
+        }
 
         let mut txid_bytes = [0; 32];
         reader.read_exact(&mut txid_bytes)?;
@@ -277,7 +300,7 @@ impl Utxo {
         let value = reader.read_u64::<LittleEndian>()?;
         let height = reader.read_i32::<LittleEndian>()?;
 
-        let script = Vector::read(&mut reader, |r| {
+        let script = utils::read_vector_max(&mut reader, MAX_SCRIPT_LEN, |r| {
             let mut byte = [0; 1];
             r.read_exact(&mut byte)?;
             Ok(byte[0])
@@ -291,6 +314,12 @@ impl Utxo {
 
         // Note that we don't write the unconfirmed spent field, because if the wallet is restarted, we'll reset any unconfirmed stuff.
 
+        let is_change = if version >= 2 {
+            reader.read_u8()? > 0
+        } else {
+            false
+        };
+
         Ok(Utxo {
             address,
             txid,
@@ -300,6 +329,7 @@ impl Utxo {
             height,
             spent,
             unconfirmed_spent: None::<TxId>,
+            is_change,
         })
     }
 
@@ -321,7 +351,7 @@ impl Utxo {
 
         // Note that we don't write the unconfirmed spent field, because if the wallet is restarted, we'll reset any unconfirmed stuff.
 
-        Ok(())
+        writer.write_u8(if self.is_change {1} else {0})
     }
 }
 
@@ -387,16 +417,25 @@ pub struct WalletTx {
     pub outgoing_metadata: Vec<OutgoingTxMetadata>,
 
     pub full_tx_scanned: bool,
+
+    // The height after which this Tx can't be mined anymore. 0 if it doesn't expire, or
+    // if the full Tx hasn't been scanned yet.
+    pub expiry_height: u32,
+
+    // Unix timestamp of the block this Tx was mined in. 0 if it isn't known, which is the
+    // case for wallets from before it was recorded until they are rescanned.
+    pub datetime: u64,
 }
 
 impl WalletTx {
     pub fn serialized_version() -> u64 {
-        return 3;
+        return 5;
     }
 
-    pub fn new(height: i32, txid: &TxId) -> Self {
+    pub fn new(height: i32, datetime: u64, txid: &TxId) -> Self {
         WalletTx {
             block: height,
+            datetime,
             txid: txid.clone(),
             notes: vec![],
             utxos: vec![],
@@ -404,9 +443,47 @@ impl WalletTx {
             total_transparent_value_spent: 0,
             outgoing_metadata: vec![],
             full_tx_scanned: false,
+            expiry_height: 0,
         }
     }
 
+    // Value of all of our notes and utxos that were spent in this Tx, shielded or transparent
+    pub fn total_value_spent(&self) -> u64 {
+        self.total_shielded_value_spent + self.total_transparent_value_spent
+    }
+
+    // Value of all the notes and utxos we received in this Tx, including change
+    pub fn total_value_received(&self) -> u64 {
+        self.notes.iter().map(|nd| nd.note.value).sum::<u64>()
+            + self.utxos.iter().map(|u| u.value).sum::<u64>()
+    }
+
+    // Value of the notes and utxos that came back to us as change, shielded or transparent
+    pub fn total_change(&self) -> u64 {
+        self.notes.iter().filter(|nd| nd.is_change).map(|nd| nd.note.value).sum::<u64>()
+            + self.utxos.iter().filter(|u| u.is_change).map(|u| u.value).sum::<u64>()
+    }
+
+    /// How much this Tx changed the wallet's balance by. This is what all the entries for this
+    /// Tx in the transaction list add up to.
+    pub fn net_value(&self) -> i64 {
+        self.total_value_received() as i64 - self.total_value_spent() as i64
+    }
+
+    /// The fee we paid for this Tx. We only know it if we paid for the Tx and it was fully scanned,
+    /// since it is what's left after all the outputs. If someone else also put money into this Tx,
+    /// the outputs can add up to more than what we spent, and the fee can't be worked out.
+    pub fn fee(&self) -> Option<u64> {
+        if self.total_value_spent() == 0 || !self.full_tx_scanned {
+            return None;
+        }
+
+        let outputs = self.total_value_received()
+            + self.outgoing_metadata.iter().map(|om| om.value).sum::<u64>();
+
+        self.total_value_spent().checked_sub(outputs)
+    }
+
     pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
         let version = reader.read_u64::<LittleEndian>()?;
         assert!(version <= WalletTx::serialized_version());
@@ -428,16 +505,30 @@ impl WalletTx {
         let outgoing_metadata = Vector::read(&mut reader, |r| OutgoingTxMetadata::read(r))?;
 
         let full_tx_scanned = reader.read_u8()? > 0;
+
+        let expiry_height = if version >= 4 {
+            reader.read_u32::<LittleEndian>()?
+        } else {
+            0
+        };
+
+        let datetime = if version >= 5 {
+            reader.read_u64::<LittleEndian>()?
+        } else {
+            0
+        };
             
         Ok(WalletTx{
             block,
+            datetime,
             txid,
             notes,
             utxos,
             total_shielded_value_spent,
             total_transparent_value_spent,
             outgoing_metadata,
-            full_tx_scanned
+            full_tx_scanned,
+            expiry_height,
         })
     }
 
@@ -459,21 +550,69 @@ impl WalletTx {
 
         writer.write_u8(if self.full_tx_scanned {1} else {0})?;
 
+        writer.write_u32::<LittleEndian>(self.expiry_height)?;
+
+        writer.write_u64::<LittleEndian>(self.datetime)?;
+
         Ok(())
     }
 }
 
+/// A raw transaction sent by this wallet. It is kept until it is mined or has expired,
+/// so it can be broadcast again if the server loses it.
+#[derive(Clone)]
+pub struct SentTx {
+    pub txid: TxId,
+    pub expiry_height: u32,
+    pub raw: Vec<u8>,
+}
+
+impl SentTx {
+    pub fn serialized_version() -> u64 {
+        return 1;
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let version = reader.read_u64::<LittleEndian>()?;
+        assert_eq!(version, SentTx::serialized_version());
+
+        let mut txid_bytes = [0u8; 32];
+        reader.read_exact(&mut txid_bytes)?;
+        let txid = TxId{0: txid_bytes};
+
+        let expiry_height = reader.read_u32::<LittleEndian>()?;
+        let raw = Vector::read(&mut reader, |r| r.read_u8())?;
+
+        Ok(SentTx {
+            txid,
+            expiry_height,
+            raw,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(SentTx::serialized_version())?;
+
+        writer.write_all(&self.txid.0)?;
+        writer.write_u32::<LittleEndian>(self.expiry_height)?;
+        Vector::write(&mut writer, &self.raw, |w, b| w.write_u8(*b))
+    }
+}
+
 pub struct SpendableNote {
     pub txid: TxId,
     pub nullifier: [u8; 32],
     pub diversifier: Diversifier,
     pub note: Note<Bls12>,
     pub witness: IncrementalWitness<Node>,
-    pub extsk: ExtendedSpendingKey,
+
+    // The spending key is looked up from this when the note is actually spent, which lets
+    // the proposal be created on a machine that doesn't have the spending keys.
+    pub extfvk: ExtendedFullViewingKey,
 }
 
 impl SpendableNote {
-    pub fn from(txid: TxId, nd: &SaplingNoteData, anchor_offset: usize, extsk: &ExtendedSpendingKey) -> Option<Self> {
+    pub fn from(txid: TxId, nd: &SaplingNoteData, anchor_offset: usize) -> Option<Self> {
         // Include only notes that haven't been spent, or haven't been included in an unconfirmed spend yet.
         if nd.spent.is_none() && nd.unconfirmed_spent.is_none() {
             let witness = nd.witnesses.get(nd.witnesses.len() - anchor_offset - 1);
@@ -484,10 +623,367 @@ impl SpendableNote {
                 diversifier: nd.diversifier,
                 note: nd.note.clone(),
                 witness: w.clone(),
-                extsk: extsk.clone(),
+                extfvk: nd.extfvk.clone(),
             })
         } else {
             None
         }
     }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut txid_bytes = [0u8; 32];
+        reader.read_exact(&mut txid_bytes)?;
+        let txid = TxId{0: txid_bytes};
+
+        let mut nullifier = [0u8; 32];
+        reader.read_exact(&mut nullifier)?;
+
+        let extfvk = ExtendedFullViewingKey::read(&mut reader)?;
+
+        let mut diversifier_bytes = [0u8; 11];
+        reader.read_exact(&mut diversifier_bytes)?;
+        let diversifier = Diversifier{0: diversifier_bytes};
+
+        // Same as in SaplingNoteData, the note is recreated from the value, r and the payment address
+        let (value, r) = read_note(&mut reader)?;
+        let note = extfvk.fvk.vk.into_payment_address(diversifier, &JUBJUB)
+                    .and_then(|pa| pa.create_note(value, r, &JUBJUB))
+                    .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Couldn't create the note for the address"))?;
+
+        let witness = IncrementalWitness::<Node>::read(&mut reader)?;
+
+        Ok(SpendableNote {
+            txid,
+            nullifier,
+            diversifier,
+            note,
+            witness,
+            extfvk,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.txid.0)?;
+        writer.write_all(&self.nullifier)?;
+
+        self.extfvk.write(&mut writer)?;
+
+        writer.write_all(&self.diversifier.0)?;
+
+        writer.write_u64::<LittleEndian>(self.note.value)?;
+        let mut rcm = [0; 32];
+        self.note.r.into_repr().write_le(&mut rcm[..])?;
+        writer.write_all(&rcm)?;
+
+        self.witness.write(&mut writer)
+    }
+}
+
+pub struct ProposedOutput {
+    pub address: String,
+    pub value  : u64,
+    pub memo   : Option<Memo>,
+}
+
+impl ProposedOutput {
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let address = utils::read_string_max(&mut reader, MAX_ADDRESS_LEN)?;
+        let value = reader.read_u64::<LittleEndian>()?;
+
+        let memo = Optional::read(&mut reader, |r| {
+            let mut memo_bytes = [0u8; 512];
+            r.read_exact(&mut memo_bytes)?;
+            match Memo::from_bytes(&memo_bytes) {
+                Some(m) => Ok(m),
+                None    => Err(io::Error::new(io::ErrorKind::InvalidInput, "Couldn't create the memo"))
+            }
+        })?;
+
+        Ok(ProposedOutput {
+            address,
+            value,
+            memo,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        utils::write_string(&mut writer, &self.address)?;
+        writer.write_u64::<LittleEndian>(self.value)?;
+        Optional::write(&mut writer, &self.memo, |w, m| w.write_all(m.as_bytes()))
+    }
+}
+
+// Limits for reading transaction proposals and the utxos in them. They're far more than fit
+// in a transaction, and only stop a corrupt file from making us allocate a lot of memory.
+const MAX_PROPOSAL_ITEMS: usize = 10_000;
+const MAX_ADDRESS_LEN: u64 = 1_000;
+const MAX_SCRIPT_LEN: usize = 10_000;
+
+/// A transaction that has had its inputs selected and its fee and change calculated, but
+/// has not been built or proven yet. Creating a proposal doesn't mark any notes or utxos
+/// as spent, so it can be discarded freely.
+pub struct TxProposal {
+    pub target_height: u32,
+    pub expiry_height: u32,
+    pub consensus_branch_id: u32,
+    pub notes: Vec<SpendableNote>,
+    pub utxos: Vec<Utxo>,
+    pub outputs: Vec<ProposedOutput>,
+    pub fee: u64,
+    pub change_address: Option<String>,  // Where the change goes. None means the builder's default
+}
+
+impl TxProposal {
+    pub fn serialized_version() -> u64 {
+        return 1;
+    }
+
+    // Total value sent to the outputs, not including the fee
+    pub fn total_value(&self) -> u64 {
+        self.outputs.iter().map(|o| o.value).sum::<u64>()
+    }
+
+    // Total value of all the notes and utxos selected as inputs
+    pub fn selected_value(&self) -> u64 {
+        self.notes.iter().map(|n| n.note.value).sum::<u64>()
+            + self.utxos.iter().map(|u| u.value).sum::<u64>()
+    }
+
+    // What's left of the selected value after the outputs and the fee. Proposals can be read from
+    // a file, so this is an error if the selected value doesn't cover them, instead of a panic.
+    pub fn change(&self) -> Result<u64, String> {
+        let selected = self.notes.iter().map(|n| n.note.value)
+            .chain(self.utxos.iter().map(|u| u.value))
+            .try_fold(0u64, |total, v| total.checked_add(v));
+        let spent = self.outputs.iter().map(|o| o.value)
+            .chain(std::iter::once(self.fee))
+            .try_fold(0u64, |total, v| total.checked_add(v));
+
+        match (selected, spent) {
+            (Some(selected), Some(spent)) if selected >= spent => Ok(selected - spent),
+            _ => Err(format!("The selected funds don't cover the outputs and the fee of the transaction proposal")),
+        }
+    }
+
+    // The root of the commitment tree that all the selected notes' witnesses point to.
+    // None if no notes were selected.
+    pub fn anchor(&self) -> Option<Vec<u8>> {
+        self.notes.first().map(|n| {
+            let mut anchor = vec![];
+            n.witness.root().write(&mut anchor).unwrap();
+            anchor
+        })
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let version = reader.read_u64::<LittleEndian>()?;
+        if version != TxProposal::serialized_version() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        format!("Unsupported transaction proposal version {}", version)));
+        }
+
+        let target_height = reader.read_u32::<LittleEndian>()?;
+        let expiry_height = reader.read_u32::<LittleEndian>()?;
+        let consensus_branch_id = reader.read_u32::<LittleEndian>()?;
+
+        // Proposals come from another machine, so the lengths are checked before reading
+        let anchor = Optional::read(&mut reader, |r| utils::read_vector_max(r, 32, |r| r.read_u8()))?;
+        let notes = utils::read_vector_max(&mut reader, MAX_PROPOSAL_ITEMS, |r| SpendableNote::read(r))?;
+        let utxos = utils::read_vector_max(&mut reader, MAX_PROPOSAL_ITEMS, |r| Utxo::read(r))?;
+        let outputs = utils::read_vector_max(&mut reader, MAX_PROPOSAL_ITEMS, |r| ProposedOutput::read(r))?;
+        let fee = reader.read_u64::<LittleEndian>()?;
+
+        let change_address = Optional::read(&mut reader, |r| utils::read_string_max(r, MAX_ADDRESS_LEN))?;
+
+        let proposal = TxProposal {
+            target_height,
+            expiry_height,
+            consensus_branch_id,
+            notes,
+            utxos,
+            outputs,
+            fee,
+            change_address,
+        };
+
+        // All the witnesses have to be for the same anchor, or the transaction will be invalid
+        if proposal.notes.iter().any(|n| {
+            let mut root = vec![];
+            n.witness.root().write(&mut root).unwrap();
+            Some(root) != anchor
+        }) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Note witnesses don't match the proposal's anchor"));
+        }
+
+        Ok(proposal)
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(TxProposal::serialized_version())?;
+
+        writer.write_u32::<LittleEndian>(self.target_height)?;
+        writer.write_u32::<LittleEndian>(self.expiry_height)?;
+        writer.write_u32::<LittleEndian>(self.consensus_branch_id)?;
+
+        Optional::write(&mut writer, &self.anchor(), |w, a| Vector::write(w, a, |w, b| w.write_u8(*b)))?;
+        Vector::write(&mut writer, &self.notes, |w, n| n.write(w))?;
+        Vector::write(&mut writer, &self.utxos, |w, u| u.write(w))?;
+        Vector::write(&mut writer, &self.outputs, |w, o| o.write(w))?;
+        writer.write_u64::<LittleEndian>(self.fee)?;
+        Optional::write(&mut writer, &self.change_address, |w, a| utils::write_string(w, a))
+    }
+}
+
+// The sapling transaction builder's default expiry delta
+pub const DEFAULT_EXPIRY_DELTA: u32 = 20;
+
+/// Where the change from a transaction is sent
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChangePolicy {
+    // The address of the first spent note, or the first account's shielded address if no notes were spent
+    SameAsInput,
+    // A designated wallet address. This is the only way to get transparent change.
+    Fixed(String),
+    // A new diversified address of the spending account for every transaction
+    FreshDiversified,
+}
+
+impl ChangePolicy {
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        match reader.read_u8()? {
+            0 => Ok(ChangePolicy::SameAsInput),
+            1 => Ok(ChangePolicy::Fixed(utils::read_string(&mut reader)?)),
+            2 => Ok(ChangePolicy::FreshDiversified),
+            p => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown change policy {}", p))),
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        match self {
+            ChangePolicy::SameAsInput      => writer.write_u8(0),
+            ChangePolicy::Fixed(address)   => {
+                writer.write_u8(1)?;
+                utils::write_string(&mut writer, address)
+            },
+            ChangePolicy::FreshDiversified => writer.write_u8(2),
+        }
+    }
+}
+
+/// Settings that the user can change, saved with the wallet
+pub struct WalletOptions {
+    // Number of blocks after the target height that sent transactions expire
+    pub expiry_delta: u32,
+    pub change_policy: ChangePolicy,
+}
+
+impl WalletOptions {
+    pub fn serialized_version() -> u64 {
+        return 2;
+    }
+
+    pub fn new() -> Self {
+        WalletOptions {
+            expiry_delta: DEFAULT_EXPIRY_DELTA,
+            change_policy: ChangePolicy::SameAsInput,
+        }
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let version = reader.read_u64::<LittleEndian>()?;
+        assert!(version <= WalletOptions::serialized_version());
+
+        let expiry_delta = reader.read_u32::<LittleEndian>()?;
+
+        let change_policy = if version >= 2 {
+            ChangePolicy::read(&mut reader)?
+        } else {
+            ChangePolicy::SameAsInput
+        };
+
+        Ok(WalletOptions {
+            expiry_delta,
+            change_policy,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(WalletOptions::serialized_version())?;
+
+        writer.write_u32::<LittleEndian>(self.expiry_delta)?;
+        self.change_policy.write(&mut writer)
+    }
+}
+
+/// Notes the user added to a transaction, such as an invoice number and categories. These aren't
+/// part of the transaction, so they're only kept in the wallet.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TxAnnotation {
+    pub text: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl TxAnnotation {
+    pub fn serialized_version() -> u64 {
+        return 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_none() && self.tags.is_empty()
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let version = reader.read_u64::<LittleEndian>()?;
+        assert!(version <= TxAnnotation::serialized_version());
+
+        let text = Optional::read(&mut reader, |r| utils::read_string(r))?;
+        let tags = Vector::read(&mut reader, |r| utils::read_string(r))?;
+
+        Ok(TxAnnotation {
+            text,
+            tags,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(TxAnnotation::serialized_version())?;
+
+        Optional::write(&mut writer, &self.text, |w, t| utils::write_string(w, t))?;
+        Vector::write(&mut writer, &self.tags, |w, t| utils::write_string(w, t))
+    }
+}
+
+/// The unspent funds of the wallet or of one address, split by whether they can be spent right now
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BalanceBreakdown {
+    // Sapling notes that are confirmed enough to be spent
+    pub spendable: u64,
+
+    // Received notes, and change notes, that need more confirmations before they can be spent
+    pub unconfirmed: u64,
+    pub unconfirmed_change: u64,
+
+    // Number of blocks until all the unconfirmed notes can be spent
+    pub blocks_until_spendable: u32,
+
+    // Notes and utxos spent by sent transactions that haven't been mined yet
+    pub pending_spent: u64,
+    pub pending_spent_transparent: u64,
+
+    // Utxos with at least as many confirmations as the notes need to be spent, and the newer ones
+    pub confirmed_transparent: u64,
+    pub unconfirmed_transparent: u64,
+}
+
+/// What one address received, like zcashd's `z_listreceivedbyaddress`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReceivedByAddress {
+    pub address: String,
+
+    // Total value and number of the notes and utxos received, not counting change
+    pub received: u64,
+    pub count: usize,
+
+    // Value of the notes and utxos at this address that are still unspent, including change
+    pub unspent: u64,
 }
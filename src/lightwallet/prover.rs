@@ -121,3 +121,55 @@ impl TxProver for InMemTxProver {
         ctx.binding_sig(value_balance, sighash, &JUBJUB)
     }
 }
+
+/// Building an [`InMemTxProver`] reads and prepares the params, which is slow, so the
+/// builder can also use a borrowed one that is shared across transactions.
+impl<'a> TxProver for &'a InMemTxProver {
+    type SaplingProvingContext = SaplingProvingContext;
+
+    fn new_sapling_proving_context(&self) -> Self::SaplingProvingContext {
+        <InMemTxProver as TxProver>::new_sapling_proving_context(*self)
+    }
+
+    fn spend_proof(
+        &self,
+        ctx: &mut Self::SaplingProvingContext,
+        proof_generation_key: ProofGenerationKey<Bls12>,
+        diversifier: Diversifier,
+        rcm: Fs,
+        ar: Fs,
+        value: u64,
+        anchor: Fr,
+        witness: CommitmentTreeWitness<Node>,
+    ) -> Result<
+        (
+            [u8; GROTH_PROOF_SIZE],
+            edwards::Point<Bls12, Unknown>,
+            PublicKey<Bls12>,
+        ),
+        (),
+    > {
+        <InMemTxProver as TxProver>::spend_proof(*self, ctx, proof_generation_key, diversifier,
+                                                 rcm, ar, value, anchor, witness)
+    }
+
+    fn output_proof(
+        &self,
+        ctx: &mut Self::SaplingProvingContext,
+        esk: Fs,
+        payment_address: PaymentAddress<Bls12>,
+        rcm: Fs,
+        value: u64,
+    ) -> ([u8; GROTH_PROOF_SIZE], edwards::Point<Bls12, Unknown>) {
+        <InMemTxProver as TxProver>::output_proof(*self, ctx, esk, payment_address, rcm, value)
+    }
+
+    fn binding_sig(
+        &self,
+        ctx: &mut Self::SaplingProvingContext,
+        value_balance: Amount,
+        sighash: &[u8; 32],
+    ) -> Result<Signature, ()> {
+        <InMemTxProver as TxProver>::binding_sig(*self, ctx, value_balance, sighash)
+    }
+}
@@ -1,9 +1,21 @@
 use std::io::{self, Read, Write};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use zcash_primitives::serialize::CompactSize;
 
-pub fn read_string<R: Read>(mut reader: R) -> io::Result<String> {
+pub fn read_string<R: Read>(reader: R) -> io::Result<String> {
+    read_string_max(reader, u64::max_value())
+}
+
+// Like read_string, but fails if the string is longer than `max_len` bytes, before allocating it.
+// Use this for files that we didn't write ourselves, like transaction proposals.
+pub fn read_string_max<R: Read>(mut reader: R, max_len: u64) -> io::Result<String> {
     // Strings are written as <littleendian> len + bytes
     let str_len = reader.read_u64::<LittleEndian>()?;
+    if str_len > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("String is too long ({} bytes, the maximum is {})", str_len, max_len)));
+    }
+
     let mut str_bytes = vec![0; str_len as usize];
     reader.read_exact(&mut str_bytes)?;
 
@@ -18,4 +30,19 @@ pub fn write_string<W: Write>(mut writer: W, s: &String) -> io::Result<()> {
     // Strings are written as len + utf8
     writer.write_u64::<LittleEndian>(s.as_bytes().len() as u64)?;
     writer.write_all(s.as_bytes())
-}
\ No newline at end of file
+}
+
+// Read a vector written with Vector::write, but fail if it has more than `max_len` elements,
+// so a bad length in a file we didn't write can't make us read or allocate that many.
+pub fn read_vector_max<R: Read, E, F>(mut reader: R, max_len: usize, func: F) -> io::Result<Vec<E>>
+where
+    F: Fn(&mut R) -> io::Result<E>,
+{
+    let count = CompactSize::read(&mut reader)?;
+    if count > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Too many elements ({}, the maximum is {})", count, max_len)));
+    }
+
+    (0..count).map(|_| func(&mut reader)).collect()
+}
@@ -0,0 +1,311 @@
+//! Parsing and creating ZIP-321 payment request URIs, eg.
+//! `zcash:ztestsapling1...?amount=1.5&memo=SGVsbG8`
+
+use std::collections::BTreeMap;
+
+use crate::LightClientConfig;
+use super::address::RecipientAddress;
+
+const COIN: u64 = 100_000_000;
+const MAX_MONEY: u64 = 21_000_000 * COIN;
+
+/// A single payment in a payment request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payment {
+    pub address: String,
+    pub amount : Option<u64>,       // In zatoshis
+    pub memo   : Option<Vec<u8>>,   // The raw memo bytes
+    pub label  : Option<String>,
+    pub message: Option<String>,
+}
+
+impl Payment {
+    pub fn new(address: String) -> Self {
+        Payment {
+            address,
+            amount: None,
+            memo: None,
+            label: None,
+            message: None,
+        }
+    }
+}
+
+/// Parse a `zcash:` URI into the list of payments it requests, ordered by their payment index.
+pub fn parse_uri(uri: &str, config: &LightClientConfig) -> Result<Vec<Payment>, String> {
+    if !uri.get(..6).map_or(false, |s| s.eq_ignore_ascii_case("zcash:")) {
+        return Err(format!("Not a zcash: URI"));
+    }
+
+    let (path, query) = match uri[6..].find('?') {
+        Some(i) => (&uri[6..6+i], Some(&uri[6+i+1..])),
+        None    => (&uri[6..], None),
+    };
+
+    // Collect all the params for each payment index. The address in the path is payment 0
+    let mut params: BTreeMap<u32, BTreeMap<String, String>> = BTreeMap::new();
+    if !path.is_empty() {
+        params.entry(0).or_default().insert("address".to_string(), path.to_string());
+    }
+
+    for param in query.iter().flat_map(|q| q.split('&')) {
+        let (key, value) = match param.find('=') {
+            Some(i) => (&param[..i], &param[i+1..]),
+            None    => return Err(format!("Invalid parameter '{}'", param)),
+        };
+
+        let (name, index) = match key.find('.') {
+            Some(i) => (&key[..i], parse_index(&key[i+1..])?),
+            None    => (key, 0),
+        };
+
+        match name {
+            "address" | "amount" | "memo" | "label" | "message" => {},
+            // Required params that we don't understand mean we can't pay this request
+            _ if name.starts_with("req-") => return Err(format!("Unsupported required parameter '{}'", name)),
+            _ => continue,
+        }
+
+        if params.entry(index).or_default().insert(name.to_string(), value.to_string()).is_some() {
+            return Err(format!("Duplicate parameter '{}'", key));
+        }
+    }
+
+    if params.is_empty() {
+        return Err(format!("URI doesn't have any payments"));
+    }
+
+    params.into_iter().map(|(index, p)| {
+        let address = match p.get("address") {
+            Some(a) => a.clone(),
+            None    => return Err(format!("Payment {} doesn't have an address", index)),
+        };
+
+        let recipient = match RecipientAddress::from_str(&address,
+                            config.hrp_sapling_address(),
+                            config.base58_pubkey_address(),
+                            config.base58_script_address()) {
+            Some(r) => r,
+            None    => return Err(format!("Invalid address in payment {}: {}", index, address)),
+        };
+
+        let mut payment = Payment::new(address);
+
+        payment.amount = p.get("amount").map(|a| parse_amount(a)).transpose()?;
+
+        payment.memo = match p.get("memo") {
+            Some(m) => {
+                if let RecipientAddress::Transparent(_) = recipient {
+                    return Err(format!("Payment {} has a memo for a transparent address", index));
+                }
+
+                let bytes = base64::decode_config(m, base64::URL_SAFE_NO_PAD)
+                                .map_err(|e| format!("Invalid memo in payment {}: {}", index, e))?;
+                if bytes.len() > 512 {
+                    return Err(format!("Memo in payment {} is too long ({} bytes)", index, bytes.len()));
+                }
+
+                Some(bytes)
+            },
+            None => None
+        };
+
+        payment.label   = p.get("label").map(|l| percent_decode(l)).transpose()?;
+        payment.message = p.get("message").map(|m| percent_decode(m)).transpose()?;
+
+        Ok(payment)
+    }).collect()
+}
+
+/// Create a `zcash:` URI for the payments. A single payment has its address in the path,
+/// the rest use indexed parameters.
+pub fn create_uri(payments: &[Payment]) -> String {
+    let mut uri = "zcash:".to_string();
+    let mut params = vec![];
+
+    for (i, payment) in payments.iter().enumerate() {
+        let suffix = if i == 0 { "".to_string() } else { format!(".{}", i) };
+
+        if payments.len() == 1 {
+            uri.push_str(&payment.address);
+        } else {
+            params.push(format!("address{}={}", suffix, payment.address));
+        }
+
+        if let Some(amount) = payment.amount {
+            params.push(format!("amount{}={}", suffix, format_amount(amount)));
+        }
+        if let Some(memo) = &payment.memo {
+            params.push(format!("memo{}={}", suffix, base64::encode_config(memo, base64::URL_SAFE_NO_PAD)));
+        }
+        if let Some(label) = &payment.label {
+            params.push(format!("label{}={}", suffix, percent_encode(label)));
+        }
+        if let Some(message) = &payment.message {
+            params.push(format!("message{}={}", suffix, percent_encode(message)));
+        }
+    }
+
+    if !params.is_empty() {
+        uri.push('?');
+        uri.push_str(&params.join("&"));
+    }
+
+    uri
+}
+
+// Payment indexes are 1 to 9999, without leading zeros
+fn parse_index(s: &str) -> Result<u32, String> {
+    if s.is_empty() || s.len() > 4 || s.starts_with('0') || !s.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Invalid payment index '{}'", s));
+    }
+
+    Ok(s.parse::<u32>().unwrap())
+}
+
+/// Parse a ZEC amount with up to 8 decimals, like "1.5", into zatoshis
+pub fn parse_amount(s: &str) -> Result<u64, String> {
+    let err = || format!("Invalid amount '{}'", s);
+
+    let (whole, frac) = match s.find('.') {
+        Some(i) => (&s[..i], &s[i+1..]),
+        None    => (s, ""),
+    };
+
+    if whole.is_empty() || whole.len() > 8 || frac.len() > 8
+        || !whole.chars().chain(frac.chars()).all(|c| c.is_ascii_digit())
+        || (s.contains('.') && frac.is_empty()) {
+        return Err(err());
+    }
+
+    let zats = whole.parse::<u64>().map_err(|_| err())? * COIN
+                + format!("{:0<8}", frac).parse::<u64>().map_err(|_| err())?;
+    if zats > MAX_MONEY {
+        return Err(format!("Amount '{}' is more than the maximum", s));
+    }
+
+    Ok(zats)
+}
+
+/// Format zatoshis as a ZEC amount, without trailing zeros
+pub fn format_amount(zats: u64) -> String {
+    let frac = format!("{:08}", zats % COIN);
+    let frac = frac.trim_end_matches('0');
+
+    if frac.is_empty() {
+        format!("{}", zats / COIN)
+    } else {
+        format!("{}.{}", zats / COIN, frac)
+    }
+}
+
+fn percent_decode(s: &str) -> Result<String, String> {
+    let bytes = s.as_bytes();
+    let mut decoded = vec![];
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            // from_str_radix would also accept a sign, like "%+1"
+            let byte = s.get(i+1..i+3)
+                        .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+                        .and_then(|h| u8::from_str_radix(h, 16).ok())
+                        .ok_or(format!("Invalid percent encoding in '{}'", s))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| format!("'{}' isn't valid UTF-8", s))
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::key::SecretKey;
+    use zcash_client_backend::encoding::encode_payment_address;
+    use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+
+    use super::{Payment, parse_uri, create_uri, percent_decode};
+    use crate::lightwallet::LightWallet;
+    use crate::lightwallet::tests::get_test_config;
+
+    #[test]
+    fn test_zip321() {
+        let config = get_test_config();
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let zaddr = encode_payment_address(config.hrp_sapling_address(), &fvk.default_address().unwrap().1);
+        let wallet = LightWallet::new(None, &config, 0).unwrap();
+        let taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
+
+        // Single payment with the address in the path
+        let payments = parse_uri(
+            &format!("zcash:{}?amount=1.0001&memo=SGVsbG8&label=Shop%20One&message=Order%2042", zaddr),
+            &config).unwrap();
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].address, zaddr);
+        assert_eq!(payments[0].amount, Some(100_010_000));
+        assert_eq!(payments[0].memo, Some(b"Hello".to_vec()));
+        assert_eq!(payments[0].label, Some("Shop One".to_string()));
+        assert_eq!(payments[0].message, Some("Order 42".to_string()));
+
+        // Multiple payments, with unknown parameters ignored
+        let payments = parse_uri(
+            &format!("zcash:?address={}&amount=0.5&address.1={}&amount.1=0.00000001&other=1", taddr, zaddr),
+            &config).unwrap();
+        assert_eq!(payments.len(), 2);
+        assert_eq!(payments[0].address, taddr);
+        assert_eq!(payments[0].amount, Some(50_000_000));
+        assert_eq!(payments[1].address, zaddr);
+        assert_eq!(payments[1].amount, Some(1));
+
+        // Bad URIs
+        let bad = vec![
+            format!("bitcoin:{}", zaddr),
+            format!("zcash:{}?amount=1.123456789", zaddr),
+            format!("zcash:{}?amount=-1", zaddr),
+            format!("zcash:{}?amount=1&amount=2", zaddr),
+            format!("zcash:{}?address={}", zaddr, zaddr),
+            format!("zcash:{}?amount.01=1", zaddr),
+            format!("zcash:{}?req-unknown=1", zaddr),
+            format!("zcash:{}?memo=SGVsbG8", taddr),
+            format!("zcash:?amount=1"),
+            format!("zcash:badaddress?amount=1"),
+            format!("zcash:{}?message=%+1", zaddr),
+            format!("zcashé:{}", zaddr),
+            format!("zcas"),
+        ];
+        for uri in bad {
+            assert!(parse_uri(&uri, &config).is_err(), "{} should fail", uri);
+        }
+
+        // Percent encodings need exactly two hex digits
+        assert_eq!(percent_decode("a%20b%2Fc").unwrap(), "a b/c");
+        for bad in &["%+1", "%-1", "% 1", "%1", "%zz"] {
+            assert!(percent_decode(bad).is_err(), "{} should fail", bad);
+        }
+
+        // Round trip
+        let mut payment = Payment::new(zaddr.clone());
+        payment.amount = Some(123_450_000);
+        payment.memo = Some(vec![0xff, 0x01]);
+        payment.message = Some("Thanks & bye".to_string());
+
+        let uri = create_uri(&[payment.clone()]);
+        assert_eq!(uri, format!("zcash:{}?amount=1.2345&memo=_wE&message=Thanks%20%26%20bye", zaddr));
+        assert_eq!(parse_uri(&uri, &config).unwrap(), vec![payment.clone()]);
+
+        let payment2 = Payment::new(taddr.clone());
+        let uri = create_uri(&[payment.clone(), payment2.clone()]);
+        assert_eq!(parse_uri(&uri, &config).unwrap(), vec![payment, payment2]);
+    }
+}
@@ -1,3 +1,4 @@
+#[cfg(feature = "embed_params")]
 #[macro_use]
 extern crate rust_embed;
 
@@ -11,6 +12,7 @@ use std::sync::{Arc};
 use std::time::Duration;
 
 use lightclient::{LightClient, LightClientConfig};
+use grpcconnector::TlsOptions;
 
 use log::{info, LevelFilter};
 use log4rs::append::rolling_file::RollingFileAppender;
@@ -32,6 +34,7 @@ pub mod grpc_client {
     include!(concat!(env!("OUT_DIR"), "/cash.z.wallet.sdk.rpc.rs"));
 }
 
+#[cfg(feature = "embed_params")]
 #[derive(RustEmbed)]
 #[folder = "zcash-params/"]
 pub struct SaplingParams;
@@ -82,13 +85,27 @@ pub fn main() {
                     .arg(Arg::with_name("server")
                         .long("server")
                         .value_name("server")
-                        .help("Lightwalletd server to connect to.")
+                        .help("Lightwalletd server to connect to. https:// servers use TLS, and http:// servers, like a local lightwalletd, use plain TCP. Servers without a scheme use TLS. http:// servers that aren't on this machine need --allow-plaintext.")
                         .takes_value(true)
                         .default_value(lightclient::DEFAULT_SERVER))
                     .arg(Arg::with_name("dangerous")
                         .long("dangerous")
-                        .help("Disable server TLS certificate verification. Use this if you're running a local lightwalletd with a self-signed certificate. WARNING: This is dangerous, don't use it with a server that is not your own.")
+                        .help("Disable server TLS certificate verification. A local lightwalletd without TLS doesn't need it, use an http:// server URL instead, and a server with a self-signed certificate can be trusted with --tls-ca or --tls-pin. WARNING: This is dangerous, don't use it with a server that is not your own.")
                         .takes_value(false))
+                    .arg(Arg::with_name("allow-plaintext")
+                        .long("allow-plaintext")
+                        .help("Allow connecting to an http:// server that isn't on this machine. The connection isn't encrypted, so anyone on the network can see your addresses and transactions.")
+                        .takes_value(false))
+                    .arg(Arg::with_name("tls-ca")
+                        .long("tls-ca")
+                        .value_name("pem_file")
+                        .help("PEM file with CA certificates to trust for the server's TLS certificate, in addition to the built-in roots. Use this for a server with a private CA or a self-signed certificate.")
+                        .takes_value(true))
+                    .arg(Arg::with_name("tls-pin")
+                        .long("tls-pin")
+                        .value_name("sha256")
+                        .help("Trust only a server whose TLS certificate, or its public key, has this hex SHA-256 hash. The certificate may be self-signed.")
+                        .takes_value(true))
                     .arg(Arg::with_name("recover")
                         .long("recover")
                         .help("Attempt to recover the seed from the wallet")
@@ -98,6 +115,22 @@ pub fn main() {
                         .long("nosync")
                         .short("n")
                         .takes_value(false))
+                    .arg(Arg::with_name("offline")
+                        .long("offline")
+                        .help("Don't connect to the server at all. Use this on an air-gapped machine that signs transaction proposals with 'signproposal'. Implies --nosync.")
+                        .takes_value(false))
+                    .arg(Arg::with_name("chain")
+                        .long("chain")
+                        .value_name("chain")
+                        .help("The chain to use with --offline, since it can't be fetched from the server.")
+                        .possible_values(&["main", "test", "regtest"])
+                        .default_value("main")
+                        .takes_value(true))
+                    .arg(Arg::with_name("params-dir")
+                        .long("params-dir")
+                        .value_name("params_dir")
+                        .help("Directory with the sapling-spend.params and sapling-output.params files. Defaults to the zcashd params directory, ~/.zcash-params")
+                        .takes_value(true))
                     .arg(Arg::with_name("COMMAND")
                         .help("Command to execute. If a command is not specified, zecwallet-cli will start in interactive mode.")
                         .required(false)
@@ -127,15 +160,71 @@ pub fn main() {
         return;
     }
 
-    let dangerous = matches.is_present("dangerous");
+    let offline = matches.is_present("offline");
 
-    // Do a getinfo first, before opening the wallet
-    let info = match grpcconnector::get_info(server.clone(), dangerous) {
-        Ok(ld) => ld,
-        Err(e) => {
-            eprintln!("Error:\n{}\nCouldn't get server info, quitting!", e);
+    // Plain TCP is meant for a lightwalletd on this machine. Anywhere else, the wallet's addresses and
+    // transactions would cross the network in the clear.
+    if server.scheme_str() == Some("http") && !offline && !is_loopback_host(server.host().unwrap()) {
+        if !matches.is_present("allow-plaintext") {
+            eprintln!("{} doesn't use TLS, and isn't on this machine. Use an https:// server URL, or pass --allow-plaintext to connect without encryption anyway.", server);
+            return;
+        }
+        eprintln!("WARNING: The connection to {} isn't encrypted", server);
+    }
+
+    // Don't let options that look like they make the connection more secure be silently ignored
+    if let Some(option) = ["tls-ca", "tls-pin"].iter().find(|o| matches.is_present(**o)) {
+        if server.scheme_str() == Some("http") {
+            eprintln!("--{} can't be used with an http:// server, which doesn't use TLS. Use an https:// server URL.", option);
+            return;
+        }
+        if matches.is_present("dangerous") {
+            eprintln!("--{} can't be used with --dangerous, which turns off all certificate checks.", option);
+            return;
+        }
+    }
+
+    let mut tls_options = TlsOptions {
+        no_cert: matches.is_present("dangerous"),
+        ..Default::default()
+    };
+    if let Some(path) = matches.value_of("tls-ca") {
+        if let Err(e) = tls_options.add_ca_file(path) {
+            eprintln!("Error:\n{}\nCouldn't read --tls-ca, quitting!", e);
+            return;
+        }
+    }
+    if let Some(pin) = matches.value_of("tls-pin") {
+        if let Err(e) = tls_options.set_pin(pin) {
+            eprintln!("Error:\n{}\nCouldn't read --tls-pin, quitting!", e);
             return;
         }
+    }
+
+    // Do a getinfo first, before opening the wallet. An offline wallet only needs to know which chain
+    // it's on, because the consensus branch id and target height come from the transaction proposals.
+    let info = if offline {
+        let chain_name = matches.value_of("chain").unwrap().to_string();
+        let sapling_activation_height = match chain_name.as_str() {
+            "main" => 419200,
+            "test" => 280000,
+            _      => 0,
+        };
+
+        grpc_client::LightdInfo {
+            chain_name,
+            sapling_activation_height,
+            consensus_branch_id: "000000".to_string(),
+            ..Default::default()
+        }
+    } else {
+        match grpcconnector::get_info(server.clone(), &tls_options) {
+            Ok(ld) => ld,
+            Err(e) => {
+                eprintln!("Error:\n{}\nCouldn't get server info, quitting!", e);
+                return;
+            }
+        }
     };
 
     // Create a Light Client Config
@@ -145,7 +234,8 @@ pub fn main() {
         sapling_activation_height   : info.sapling_activation_height,
         consensus_branch_id         : info.consensus_branch_id,
         anchor_offset               : ANCHOR_OFFSET,
-        no_cert_verification        : dangerous,
+        tls_options,
+        params_dir                  : matches.value_of("params-dir").map(|s| s.to_string()),
     };
 
     // Configure logging first.
@@ -169,24 +259,33 @@ pub fn main() {
     };
 
     // At startup, run a sync. 
-    let sync_output = if matches.is_present("nosync") {
+    let sync_output = if matches.is_present("nosync") || offline {
          None
     } else {
         Some(lightclient.do_sync(true))
     };
 
     if command.is_none() {
+        // Get the prover ready while the user types in the first command
+        lightclient.warm_up_prover();
+
         // If running in interactive mode, output of the sync command
         if sync_output.is_some() {
             println!("{}", sync_output.unwrap());
         }
-        start_interactive(lightclient, &config);
+        start_interactive(lightclient, &config, offline);
     } else {
         let cmd_response = commands::do_user_command(&command.unwrap(), &params, lightclient.as_ref());
         println!("{}", cmd_response);
     }
 }
 
+/// If `host` is this machine, like "localhost", "127.0.0.1" or "[::1]"
+fn is_loopback_host(host: &str) -> bool {
+    host == "localhost"
+        || host.trim_start_matches('[').trim_end_matches(']').parse::<std::net::IpAddr>().map_or(false, |ip| ip.is_loopback())
+}
+
 fn attempt_recover_seed() {
     use std::fs::File;
     use std::io::prelude::*;
@@ -201,7 +300,8 @@ fn attempt_recover_seed() {
         sapling_activation_height: 0,
         consensus_branch_id: "000000".to_string(),
         anchor_offset: 0,
-        no_cert_verification: false,
+        tls_options: TlsOptions::default(),
+        params_dir: None,
     };
 
     let mut reader = BufReader::new(File::open(config.get_wallet_path()).unwrap());
@@ -217,8 +317,12 @@ fn attempt_recover_seed() {
     println!("Recovered seed phrase:\n{}", phrase);
 }
 
-fn start_interactive(lightclient: Arc<LightClient>, config: &LightClientConfig) {
-    println!("Lightclient connecting to {}", config.server);
+fn start_interactive(lightclient: Arc<LightClient>, config: &LightClientConfig, offline: bool) {
+    if offline {
+        println!("Lightclient running offline");
+    } else {
+        println!("Lightclient connecting to {}", config.server);
+    }
 
     let (command_tx, command_rx) = std::sync::mpsc::channel::<(String, Vec<String>)>();
     let (resp_tx, resp_rx) = std::sync::mpsc::channel::<String>();
@@ -238,6 +342,7 @@ fn start_interactive(lightclient: Arc<LightClient>, config: &LightClientConfig)
                         break;
                     }
                 },
+                Err(_) if offline => {},
                 Err(_) => {
                     // Timeout. Do a sync to keep the wallet up-to-date. False to whether to print updates on the console
                     info!("Timeout, doing a sync");